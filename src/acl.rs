@@ -0,0 +1,130 @@
+use std::{io, path::Path};
+
+use clap::ValueEnum;
+
+use crate::{DedupArgs, HashDigest, HASH_LEN};
+
+/// How files with access control lists are handled, since merged files share a single ACL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AclPolicy {
+  /// Merge files regardless of their ACLs.
+  Ignore,
+  /// Only merge files with the same ACL.
+  RequireEqual,
+  /// Skip files that have an ACL beyond their mode (or on Windows, beyond inherited entries).
+  Skip,
+}
+
+impl AclPolicy {
+  pub fn get() -> Self {
+    DedupArgs::get().acl_policy
+  }
+}
+
+/// The digest of files without an ACL of their own.
+pub const TRIVIAL: HashDigest = [0; HASH_LEN];
+
+/// The POSIX access ACL of a file, which Linux only stores when it's not equivalent to the mode.
+#[cfg(target_os = "linux")]
+fn read(path: &Path) -> io::Result<Option<Vec<u8>>> {
+  match xattr::get(path, "system.posix_acl_access") {
+    Err(e) if e.raw_os_error() == Some(libc::EOPNOTSUPP) => Ok(None),
+    result => result,
+  }
+}
+
+/// The explicit entries of the DACL of a file, if it has any or doesn't inherit entries.
+#[cfg(windows)]
+fn read(path: &Path) -> io::Result<Option<Vec<u8>>> {
+  use std::{ffi::c_void, os::windows::ffi::OsStrExt, ptr};
+  const DACL_SECURITY_INFORMATION: u32 = 0x4;
+  const SE_DACL_PRESENT: u16 = 0x0004;
+  const SE_DACL_PROTECTED: u16 = 0x1000;
+  const INHERITED_ACE: u8 = 0x10;
+  #[link(name = "advapi32")]
+  extern "system" {
+    fn GetFileSecurityW(
+      file_name: *const u16,
+      requested_information: u32,
+      security_descriptor: *mut c_void,
+      length: u32,
+      length_needed: *mut u32,
+    ) -> i32;
+  }
+
+  let path = path
+    .as_os_str()
+    .encode_wide()
+    .chain([0])
+    .collect::<Vec<_>>();
+  let mut length = 0;
+  // SAFETY: Only the needed length is written when no buffer is given.
+  unsafe {
+    GetFileSecurityW(
+      path.as_ptr(),
+      DACL_SECURITY_INFORMATION,
+      ptr::null_mut(),
+      0,
+      &mut length,
+    )
+  };
+  let mut descriptor = vec![0u8; length as usize];
+  // SAFETY: The buffer is as long as the call asked for.
+  if unsafe {
+    GetFileSecurityW(
+      path.as_ptr(),
+      DACL_SECURITY_INFORMATION,
+      descriptor.as_mut_ptr().cast(),
+      length,
+      &mut length,
+    )
+  } == 0
+  {
+    return Err(io::Error::last_os_error());
+  }
+  let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid security descriptor");
+  let read_u16 = |offset: usize| -> io::Result<u16> {
+    let bytes = descriptor.get(offset..offset + 2).ok_or_else(invalid)?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+  };
+  // A self-relative `SECURITY_DESCRIPTOR` has the control flags at 2 and the DACL offset at 16.
+  let control = read_u16(2)?;
+  let dacl_offset = u32::from_le_bytes(
+    descriptor
+      .get(16..20)
+      .ok_or_else(invalid)?
+      .try_into()
+      .unwrap(),
+  ) as usize;
+  if control & SE_DACL_PRESENT == 0 || dacl_offset == 0 {
+    return Ok(None);
+  }
+  // An `ACL` has its size at 2 and the number of entries at 4, followed by the entries from 8.
+  let (dacl_size, ace_count) = (read_u16(dacl_offset + 2)?, read_u16(dacl_offset + 4)?);
+  let dacl = descriptor
+    .get(dacl_offset..dacl_offset + dacl_size as usize)
+    .ok_or_else(invalid)?;
+  let mut explicit = control & SE_DACL_PROTECTED != 0;
+  let mut offset = 8;
+  for _ in 0..ace_count {
+    // Every `ACE_HEADER` has its flags at 1 and its size at 2.
+    let header = dacl.get(offset..offset + 4).ok_or_else(invalid)?;
+    explicit |= header[1] & INHERITED_ACE == 0;
+    offset += u16::from_le_bytes([header[2], header[3]]) as usize;
+  }
+  Ok(explicit.then(|| dacl.to_vec()))
+}
+
+/// ACLs are not read on this platform.
+#[cfg(not(any(target_os = "linux", windows)))]
+fn read(_path: &Path) -> io::Result<Option<Vec<u8>>> {
+  Ok(None)
+}
+
+/// A digest of the ACL of a file, or `TRIVIAL` if it has none of its own.
+pub fn digest(path: &Path) -> io::Result<HashDigest> {
+  Ok(match read(path)? {
+    Some(acl) => *blake3::hash(&acl).as_bytes(),
+    None => TRIVIAL,
+  })
+}
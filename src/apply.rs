@@ -0,0 +1,119 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::{
+  input, keep, merge_with_hard_link_with_context, os, output,
+  report::DuplicateGroups,
+  storage::{calculate_file_hash_with_context, verify_identical_with_context, FileStorageData},
+  HashDigest, Stats,
+};
+
+/// Reads the metadata of a listed path.
+async fn read_file(path: &Path) -> Result<FileStorageData> {
+  FileStorageData::new(os::extended_path(path)?)
+    .await
+    .with_context(|| format!("Could not read metadata for {}", path.display()))
+}
+
+/// Merges every path of `group` that is still identical to the first one into it.
+async fn apply_group(
+  group: Vec<PathBuf>,
+  size_only: bool,
+  stats: &mut Stats,
+  groups: &mut DuplicateGroups,
+) -> Result<()> {
+  let mut paths = group.into_iter();
+  let Some(original) = paths.next() else {
+    return Ok(());
+  };
+  let original = read_file(&original).await?;
+  let original_uid = (original.storage_uid, original.file_id);
+  // The original is only hashed once another file has its size.
+  let mut original_hash = None::<Option<HashDigest>>;
+  for redundant in paths {
+    let redundant = read_file(&redundant).await?;
+    stats.files_processed += 1;
+    let skipped = if redundant.storage_uid != original.storage_uid {
+      Some("on another storage device than the original")
+    } else if redundant.file_id == original.file_id {
+      Some("already linked to the original")
+    } else if redundant.size != original.size {
+      Some("size differs from the original")
+    } else if keep::is_protected(&redundant.path) {
+      Some("protected")
+    } else {
+      None
+    };
+    if let Some(reason) = skipped {
+      output::print_skipped(&redundant.path, reason);
+      continue;
+    }
+    if !size_only {
+      let original_hash = match original_hash {
+        Some(hash) => hash,
+        None => {
+          stats.files_hashed += 1;
+          stats.bytes_hashed += original.size;
+          *original_hash.insert(calculate_file_hash_with_context(&original).await?)
+        }
+      };
+      let Some(original_hash) = original_hash else {
+        output::print_skipped(&redundant.path, "the original could not be hashed");
+        continue;
+      };
+      stats.files_hashed += 1;
+      stats.bytes_hashed += redundant.size;
+      if calculate_file_hash_with_context(&redundant).await? != Some(original_hash) {
+        output::print_skipped(&redundant.path, "content differs from the original");
+        continue;
+      }
+    }
+    if !verify_identical_with_context(
+      &original.path,
+      &redundant.path,
+      original.size,
+      original.storage_uid,
+    )
+    .await?
+    {
+      continue;
+    }
+    if !merge_with_hard_link_with_context(&original.path, &redundant.path, redundant.size).await? {
+      continue;
+    }
+    stats.links_created += 1;
+    stats.saved_storage += redundant.size;
+    // The listing has no digest when only the sizes are compared.
+    let digest = original_hash.flatten().unwrap_or_default();
+    groups.add_link(
+      original_uid,
+      &original.path,
+      (original.size, digest),
+      redundant.path.clone(),
+    );
+    groups.add_wasted(original_uid, &original.path, (original.size, digest));
+  }
+  Ok(())
+}
+
+/// Merges the duplicate groups listed in `source`, for `apply-groups`.
+pub async fn apply_groups(
+  source: &Path,
+  size_only: bool,
+  stats: &mut Stats,
+  groups: &mut DuplicateGroups,
+) -> Result<()> {
+  for group in input::read_group_list(source).await? {
+    stats.files_processed += 1;
+    let first = group[0].clone();
+    let result = apply_group(group, size_only, stats, groups)
+      .await
+      .with_context(|| format!("Could not apply the group of {}", first.display()));
+    // The files of a listing may have been moved or removed since it was written.
+    if let Err(e) = result {
+      output::print_error(format!("{e:#}"));
+    }
+  }
+  Ok(())
+}
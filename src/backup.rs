@@ -0,0 +1,94 @@
+use std::{
+  path::{Component, Path, PathBuf},
+  sync::OnceLock,
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use tokio::fs;
+
+use crate::{output, DedupArgs};
+
+/// The directory of this run inside the `--backup-dir`, if any.
+static BACKUP_RUN_DIR: OnceLock<Option<PathBuf>> = OnceLock::new();
+
+/// Removes the runs in `backup_dir` that are older than `retention`. Runs are named by the second
+/// they started, so anything else in the directory is left alone.
+async fn remove_expired(backup_dir: &Path, now: Duration, retention: Duration) -> Result<()> {
+  let mut reader = fs::read_dir(backup_dir).await?;
+  while let Some(entry) = reader.next_entry().await? {
+    let Some(started) = entry
+      .file_name()
+      .to_str()
+      .and_then(|name| name.parse::<u64>().ok())
+    else {
+      continue;
+    };
+    if now.saturating_sub(Duration::from_secs(started)) > retention {
+      output::print_verbose(
+        1,
+        format!("Removing expired backup {}", entry.path().display()),
+      );
+      fs::remove_dir_all(entry.path())
+        .await
+        .with_context(|| format!("Could not remove expired backup {}", entry.path().display()))?;
+    }
+  }
+  Ok(())
+}
+
+/// Creates the `--backup-dir`, if any, and removes backups older than `--backup-retention`.
+pub async fn init() -> Result<()> {
+  let args = DedupArgs::get();
+  let run_dir = match (&args.backup_dir, args.dry_run) {
+    (Some(backup_dir), false) => {
+      fs::create_dir_all(backup_dir)
+        .await
+        .with_context(|| format!("Could not create backup dir {}", backup_dir.display()))?;
+      let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+      if let Some(retention) = args.backup_retention {
+        remove_expired(backup_dir, now, retention.into()).await?;
+      }
+      Some(backup_dir.join(now.as_secs().to_string()))
+    }
+    _ => None,
+  };
+  let _ = BACKUP_RUN_DIR.set(run_dir);
+  Ok(())
+}
+
+/// Preserves the content of `redundant` in the backup dir before it's replaced. The backup is a
+/// hard link when the backup dir is on the same storage, and a copy otherwise.
+pub async fn preserve(redundant: &Path) -> Result<()> {
+  let Some(Some(run_dir)) = BACKUP_RUN_DIR.get() else {
+    return Ok(());
+  };
+  let absolute = if redundant.is_absolute() {
+    redundant.to_owned()
+  } else {
+    std::env::current_dir()?.join(redundant)
+  };
+  let backup = absolute
+    .components()
+    .filter_map(|component| match component {
+      Component::Normal(name) => Some(name),
+      _ => None,
+    })
+    .fold(run_dir.clone(), |backup, name| backup.join(name));
+  let result = async {
+    if let Some(parent) = backup.parent() {
+      fs::create_dir_all(parent).await?;
+    }
+    if fs::hard_link(redundant, &backup).await.is_err() {
+      fs::copy(redundant, &backup).await?;
+    }
+    anyhow::Ok(())
+  };
+  result.await.with_context(|| {
+    format!(
+      "Could not back up {} to {}",
+      redundant.display(),
+      backup.display()
+    )
+  })
+}
@@ -0,0 +1,235 @@
+use std::{
+  collections::{HashMap, HashSet},
+  fs::{self, File},
+  io::{BufWriter, ErrorKind, Write},
+  path::PathBuf,
+  sync::{Mutex, OnceLock},
+  time::UNIX_EPOCH,
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{hash::HashAlgorithm, storage::FileStorageData, DedupArgs, HashDigest, HASH_LEN};
+
+const MAGIC: &[u8; 8] = b"HLDCACHE";
+const VERSION: u32 = 2;
+const RECORD_LEN: usize = 16 + 16 + 8 + 8 + 4 + HASH_LEN;
+
+/// Identifies the content of a file for as long as it's unmodified.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct CacheKey {
+  storage_uid: u128,
+  file_id: u128,
+  size: u64,
+  modified: (u64, u32),
+}
+
+impl CacheKey {
+  fn new(file: &FileStorageData) -> Option<Self> {
+    let modified = file.modified?.duration_since(UNIX_EPOCH).ok()?;
+    // The IDs are already `u128` on some platforms.
+    #[allow(clippy::useless_conversion)]
+    Some(CacheKey {
+      storage_uid: file.storage_uid.into(),
+      file_id: file.file_id.into(),
+      size: file.size,
+      modified: (modified.as_secs(), modified.subsec_nanos()),
+    })
+  }
+
+  fn write(&self, digest: &HashDigest, writer: &mut impl Write) -> std::io::Result<()> {
+    writer.write_all(&self.storage_uid.to_le_bytes())?;
+    writer.write_all(&self.file_id.to_le_bytes())?;
+    writer.write_all(&self.size.to_le_bytes())?;
+    writer.write_all(&self.modified.0.to_le_bytes())?;
+    writer.write_all(&self.modified.1.to_le_bytes())?;
+    writer.write_all(digest)
+  }
+
+  fn read(record: &[u8]) -> (Self, HashDigest) {
+    let (storage_uid, record) = record.split_at(16);
+    let (file_id, record) = record.split_at(16);
+    let (size, record) = record.split_at(8);
+    let (secs, record) = record.split_at(8);
+    let (nanos, digest) = record.split_at(4);
+    let key = CacheKey {
+      storage_uid: u128::from_le_bytes(storage_uid.try_into().unwrap()),
+      file_id: u128::from_le_bytes(file_id.try_into().unwrap()),
+      size: u64::from_le_bytes(size.try_into().unwrap()),
+      modified: (
+        u64::from_le_bytes(secs.try_into().unwrap()),
+        u32::from_le_bytes(nanos.try_into().unwrap()),
+      ),
+    };
+    (key, digest.try_into().unwrap())
+  }
+}
+
+struct HashCache {
+  path: PathBuf,
+  hashes: HashMap<CacheKey, HashDigest>,
+  /// The files whose digests were looked up or added in this run.
+  used: HashSet<CacheKey>,
+  changed: bool,
+}
+
+static HASH_CACHE: OnceLock<Option<Mutex<HashCache>>> = OnceLock::new();
+
+fn get_cache() -> Option<&'static Mutex<HashCache>> {
+  HASH_CACHE.get().and_then(Option::as_ref)
+}
+
+fn read_cache(path: &PathBuf) -> Result<HashMap<CacheKey, HashDigest>> {
+  let content = match fs::read(path) {
+    Ok(content) => content,
+    Err(e) if e.kind() == ErrorKind::NotFound => return Ok(HashMap::new()),
+    Err(e) => Err(e)?,
+  };
+  let Some(records) = content.strip_prefix(MAGIC.as_slice()) else {
+    bail!("Not a hash cache");
+  };
+  let (version, records) = records.split_at(records.len().min(12));
+  if version.len() != 12 {
+    bail!("Truncated hash cache");
+  }
+  // The cache is replaced by one of the current version and algorithm when it's saved.
+  if u32::from_le_bytes(version[..4].try_into().unwrap()) != VERSION
+    || u32::from_le_bytes(version[4..8].try_into().unwrap()) as usize != HASH_LEN
+    || u32::from_le_bytes(version[8..].try_into().unwrap()) != HashAlgorithm::get().id()
+  {
+    return Ok(HashMap::new());
+  }
+  if records.len() % RECORD_LEN != 0 {
+    bail!("Truncated hash cache");
+  }
+  Ok(
+    records
+      .chunks_exact(RECORD_LEN)
+      .map(CacheKey::read)
+      .collect(),
+  )
+}
+
+/// Loads the `--hash-cache`, if any.
+pub fn init() -> Result<()> {
+  let cache = match DedupArgs::get().hash_cache {
+    Some(ref path) => {
+      let hashes = read_cache(path)
+        .with_context(|| format!("Could not read hash cache {}", path.display()))?;
+      Some(Mutex::new(HashCache {
+        path: path.clone(),
+        hashes,
+        used: HashSet::new(),
+        changed: false,
+      }))
+    }
+    None => None,
+  };
+  let _ = HASH_CACHE.set(cache);
+  Ok(())
+}
+
+/// The cached digest of `file`, if its size and modification time are unchanged.
+pub fn get(file: &FileStorageData) -> Option<HashDigest> {
+  let key = CacheKey::new(file)?;
+  let mut cache = get_cache()?.lock().unwrap_or_else(|e| e.into_inner());
+  let digest = cache.hashes.get(&key).copied()?;
+  cache.used.insert(key);
+  Some(digest)
+}
+
+pub fn insert(file: &FileStorageData, digest: HashDigest) {
+  let (Some(cache), Some(key)) = (get_cache(), CacheKey::new(file)) else {
+    return;
+  };
+  let mut cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+  cache.used.insert(key);
+  if cache.hashes.insert(key, digest) != Some(digest) {
+    cache.changed = true;
+  }
+}
+
+/// Drops the digests of the files that weren't looked up in this run, which are gone, modified or
+/// no longer scanned. Only called once a run is complete, since a stopped one may not have got to
+/// them yet.
+pub fn prune() {
+  let Some(cache) = get_cache() else {
+    return;
+  };
+  let mut cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+  let HashCache { hashes, used, .. } = &mut *cache;
+  let len = hashes.len();
+  hashes.retain(|key, _| used.contains(key));
+  if hashes.len() != len {
+    cache.changed = true;
+  }
+}
+
+/// Writes the cache back to disk, if anything was added to it.
+pub fn save() -> Result<()> {
+  let Some(cache) = get_cache() else {
+    return Ok(());
+  };
+  let cache = cache.lock().unwrap_or_else(|e| e.into_inner());
+  if !cache.changed {
+    return Ok(());
+  }
+  let mut temporary_path = cache.path.clone().into_os_string();
+  temporary_path.push(".tmp");
+  let temporary_path = PathBuf::from(temporary_path);
+  let write = || -> Result<()> {
+    let mut writer = BufWriter::new(File::create(&temporary_path)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    writer.write_all(&(HASH_LEN as u32).to_le_bytes())?;
+    writer.write_all(&HashAlgorithm::get().id().to_le_bytes())?;
+    for (key, digest) in &cache.hashes {
+      key.write(digest, &mut writer)?;
+    }
+    writer
+      .into_inner()
+      .map_err(|e| e.into_error())?
+      .sync_all()?;
+    fs::rename(&temporary_path, &cache.path)?;
+    Ok(())
+  };
+  write().with_context(|| format!("Could not write hash cache {}", cache.path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn records_round_trip() {
+    let key = CacheKey {
+      storage_uid: u128::MAX - 1,
+      file_id: 0x0123_4567_89ab_cdef,
+      size: 2_000_000,
+      modified: (1_700_000_000, 999_999_999),
+    };
+    let digest: HashDigest = std::array::from_fn(|i| i as u8 ^ 0x5a);
+    let mut record = vec![];
+    key.write(&digest, &mut record).unwrap();
+    assert_eq!(record.len(), RECORD_LEN);
+    assert_eq!(CacheKey::read(&record), (key, digest));
+  }
+
+  #[test]
+  fn records_are_little_endian() {
+    let key = CacheKey {
+      storage_uid: 1,
+      file_id: 2,
+      size: 3,
+      modified: (4, 5),
+    };
+    let mut record = vec![];
+    key.write(&[0xff; HASH_LEN], &mut record).unwrap();
+    assert_eq!(record[0], 1);
+    assert_eq!(record[16], 2);
+    assert_eq!(record[32], 3);
+    assert_eq!(record[40], 4);
+    assert_eq!(record[48], 5);
+    assert_eq!(&record[52..], &[0xff; HASH_LEN]);
+  }
+}
@@ -0,0 +1,278 @@
+use std::{
+  env,
+  ffi::OsString,
+  path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use clap::{error::ErrorKind, Arg, ArgAction, CommandFactory, ValueHint};
+use serde::Deserialize;
+use toml::{Table, Value};
+
+use crate::DedupArgs;
+
+/// Settings for everything below a path.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+  path: PathBuf,
+  /// Adds the path to `--prefer-path`.
+  #[serde(default)]
+  prefer: bool,
+  /// Adds the path to `--protect-path`.
+  #[serde(default)]
+  protect: bool,
+}
+
+#[cfg(not(windows))]
+fn default_path() -> Option<PathBuf> {
+  let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+    Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+    _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+  };
+  Some(config_dir.join("hardlinkdedup").join("config.toml"))
+}
+
+#[cfg(windows)]
+fn default_path() -> Option<PathBuf> {
+  Some(
+    PathBuf::from(env::var_os("APPDATA")?)
+      .join("hardlinkdedup")
+      .join("config.toml"),
+  )
+}
+
+/// The value of `--config` on the command line, which has to be known before it's parsed.
+fn config_arg(args: &[OsString]) -> Option<PathBuf> {
+  let mut args = args.iter().skip(1);
+  while let Some(arg) = args.next() {
+    if arg == "--" {
+      break;
+    } else if arg == "--config" {
+      return args.next().map(PathBuf::from);
+    } else if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+      return Some(path.into());
+    }
+  }
+  None
+}
+
+fn value_string(key: &str, value: &Value) -> Result<String> {
+  Ok(match value {
+    Value::String(value) => value.clone(),
+    Value::Integer(value) => value.to_string(),
+    Value::Float(value) => value.to_string(),
+    Value::Boolean(value) => value.to_string(),
+    _ => bail!("{key} must be a string or a number"),
+  })
+}
+
+/// Pushes `value` of the setting `key` for `arg` as if it was given on the command line.
+fn push_setting(
+  args: &mut Vec<OsString>,
+  (key, arg): (&str, &Arg),
+  value: &Value,
+  dir: &Path,
+) -> Result<()> {
+  let flag = arg
+    .get_long()
+    .map(|long| OsString::from(format!("--{long}")));
+  match arg.get_action() {
+    ArgAction::SetTrue | ArgAction::SetFalse => {
+      let Value::Boolean(enabled) = value else {
+        bail!("{key} must be a boolean");
+      };
+      // A flag that clears a field may be set by the name of the field, so `skip-caches = false` is
+      // `--scan-caches`.
+      let by_field = matches!(arg.get_action(), ArgAction::SetFalse)
+        && arg.get_long() != Some(key.replace('_', "-").as_str());
+      args.extend(flag.filter(|_| *enabled != by_field));
+    }
+    ArgAction::Count => {
+      let Value::Integer(count) = value else {
+        bail!("{key} must be an integer");
+      };
+      for _ in 0..*count {
+        args.extend(flag.clone());
+      }
+    }
+    _ => {
+      let values = match value {
+        Value::Array(values) => values.iter().collect(),
+        value => vec![value],
+      };
+      let is_path = matches!(
+        arg.get_value_hint(),
+        ValueHint::AnyPath | ValueHint::FilePath | ValueHint::DirPath
+      );
+      for value in values {
+        let value = value_string(key, value)?;
+        let value = if is_path && value != "-" {
+          // Paths are relative to the config file, since scheduled runs start anywhere.
+          dir.join(value).into_os_string()
+        } else {
+          value.into()
+        };
+        match flag {
+          // Joined with the flag, so that values starting with a dash aren't taken for flags.
+          Some(ref flag) => {
+            let mut arg = flag.clone();
+            arg.push("=");
+            arg.push(value);
+            args.push(arg);
+          }
+          None => args.push(value),
+        }
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Translates the config file to the command line arguments that it's equivalent to.
+fn read(path: &Path) -> Result<Vec<OsString>> {
+  let content = std::fs::read_to_string(path)?;
+  let mut table: Table = content.parse()?;
+  let dir = path.parent().unwrap_or(Path::new(""));
+  let command = DedupArgs::command();
+  let mut args = vec![];
+  if let Some(rules) = table.remove("rule") {
+    let rules: Vec<Rule> = rules.try_into().context("Invalid rule")?;
+    for rule in rules {
+      let path = dir.join(rule.path).into_os_string();
+      for (enabled, flag) in [
+        (rule.prefer, "--prefer-path"),
+        (rule.protect, "--protect-path"),
+      ] {
+        if enabled {
+          args.extend([flag.into(), path.clone()]);
+        }
+      }
+    }
+  }
+  for (key, value) in table {
+    let (id, long) = (key.replace('-', "_"), key.replace('_', "-"));
+    // Settings are named like the field or like the flag, which differ for flags that clear it.
+    let Some(arg) = command.get_arguments().find(|arg| {
+      (arg.get_id() == id.as_str() || arg.get_long() == Some(long.as_str())) && id != "config"
+    }) else {
+      bail!("Unknown setting {key}");
+    };
+    push_setting(&mut args, (&key, arg), &value, dir)?;
+  }
+  Ok(args)
+}
+
+/// Returns the command line with the settings of the config file inserted before the arguments, so
+/// that the arguments take precedence. The config file is `--config`, or the default one if it
+/// exists.
+pub fn with_config_args(args: impl IntoIterator<Item = OsString>) -> Vec<OsString> {
+  let mut args = args.into_iter().collect::<Vec<_>>();
+  let path = match config_arg(&args) {
+    Some(path) => path,
+    None => match default_path() {
+      Some(path) if path.is_file() => path,
+      _ => return args,
+    },
+  };
+  match read(&path) {
+    Ok(settings) => {
+      let end = args.len().min(1);
+      args.splice(end..end, settings);
+      args
+    }
+    Err(e) => DedupArgs::command()
+      .error(
+        ErrorKind::InvalidValue,
+        format!("Could not read config {}: {e:#}", path.display()),
+      )
+      .exit(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Translates `content` as a config file in `dir`, which is created for it.
+  fn translate(dir: &str, content: &str) -> Result<Vec<OsString>> {
+    let dir = env::temp_dir().join(format!("hard-link-dedup-{dir}-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let path = dir.join("config.toml");
+    std::fs::write(&path, content)?;
+    let args = read(&path);
+    std::fs::remove_dir_all(&dir)?;
+    args
+  }
+
+  /// The settings are in the order of their keys, after the rules.
+  #[test]
+  fn settings_become_arguments() {
+    let args = translate(
+      "settings",
+      "dry-run = true\nquiet = false\nverbose = 2\nmin_file_size = 64\nexclude = '-x'\n",
+    )
+    .unwrap();
+    assert_eq!(
+      args,
+      [
+        "--dry-run",
+        "--exclude=-x",
+        "--min-file-size=64",
+        "--verbose",
+        "--verbose"
+      ]
+    );
+  }
+
+  #[test]
+  fn flags_that_clear_a_field_are_named_like_either() {
+    for (setting, args) in [
+      ("scan-caches = true", &["--scan-caches"][..]),
+      ("scan_caches = false", &[]),
+      ("skip-caches = false", &["--scan-caches"]),
+      ("skip_caches = true", &[]),
+    ] {
+      assert_eq!(translate("clear", setting).unwrap(), args, "{setting}");
+    }
+    let args = translate("parse", "scan-caches = true\n").unwrap();
+    let mut command_line = vec![OsString::from("hard-link-dedup")];
+    command_line.extend(args);
+    command_line.push(".".into());
+    assert!(!<DedupArgs as clap::Parser>::parse_from(command_line).skip_caches);
+  }
+
+  #[test]
+  fn paths_are_relative_to_the_config() {
+    let dir = env::temp_dir().join(format!("hard-link-dedup-paths-{}", std::process::id()));
+    let args = translate(
+      "paths",
+      "prefer-path = ['a', 'b']\nhashes-from = '-'\n[[rule]]\npath = 'c'\nprotect = true\n",
+    )
+    .unwrap();
+    let arg = |flag: &str, path: &str| {
+      let mut arg = OsString::from(flag);
+      arg.push(dir.join(path));
+      arg
+    };
+    assert_eq!(
+      args,
+      [
+        "--protect-path".into(),
+        dir.join("c").into_os_string(),
+        "--hashes-from=-".into(),
+        arg("--prefer-path=", "a"),
+        arg("--prefer-path=", "b"),
+      ]
+    );
+  }
+
+  #[test]
+  fn unknown_and_mistyped_settings_are_rejected() {
+    assert!(translate("unknown", "no-such-setting = 1\n").is_err());
+    assert!(translate("config", "config = 'other.toml'\n").is_err());
+    assert!(translate("boolean", "dry-run = 'yes'\n").is_err());
+    assert!(translate("count", "verbose = true\n").is_err());
+    assert!(translate("rule", "[[rule]]\npath = 'a'\nskip = true\n").is_err());
+  }
+}
@@ -0,0 +1,180 @@
+use std::{
+  path::{Path, PathBuf},
+  sync::OnceLock,
+};
+
+use anyhow::{bail, Context, Result};
+use tokio::{
+  io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+  sync::{mpsc, oneshot, watch, Mutex},
+};
+
+use crate::{output, DedupArgs};
+
+/// A command that the run loop answers, since it owns the state of the run.
+pub enum Request {
+  Status(oneshot::Sender<String>),
+  Rescan(PathBuf, oneshot::Sender<Result<()>>),
+}
+
+struct Control {
+  paused: watch::Sender<bool>,
+  requests: Mutex<mpsc::Receiver<Request>>,
+}
+
+static CONTROL: OnceLock<Option<Control>> = OnceLock::new();
+
+fn get_control() -> Option<&'static Control> {
+  CONTROL.get().and_then(Option::as_ref)
+}
+
+/// Starts listening on the `--control-socket`, if any.
+pub fn init() -> Result<()> {
+  let control = match DedupArgs::get().control_socket {
+    Some(ref path) => {
+      let (sender, requests) = mpsc::channel(16);
+      listen(path, sender)
+        .with_context(|| format!("Could not listen on control socket {}", path.display()))?;
+      Some(Control {
+        paused: watch::channel(false).0,
+        requests: Mutex::new(requests),
+      })
+    }
+    None => None,
+  };
+  let _ = CONTROL.set(control);
+  Ok(())
+}
+
+/// Whether the run keeps waiting for commands after it's done.
+pub fn is_enabled() -> bool {
+  get_control().is_some()
+}
+
+pub fn is_paused() -> bool {
+  get_control().is_some_and(|control| *control.paused.borrow())
+}
+
+/// Waits until the run isn't paused by the `pause` command.
+pub async fn wait_if_paused() {
+  let Some(control) = get_control() else {
+    return;
+  };
+  let mut paused = control.paused.subscribe();
+  // The sender lives in a static, so it's never dropped.
+  let _ = paused.wait_for(|paused| !paused).await;
+}
+
+/// Waits for the next command for the run loop. Never completes without a control socket.
+pub async fn next_request() -> Request {
+  if let Some(control) = get_control() {
+    if let Some(request) = control.requests.lock().await.recv().await {
+      return request;
+    }
+  }
+  std::future::pending().await
+}
+
+async fn execute(line: &str, requests: &mpsc::Sender<Request>) -> Result<String> {
+  let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+  match (command, argument) {
+    ("status", "") => {
+      let (reply, status) = oneshot::channel();
+      requests.send(Request::Status(reply)).await?;
+      Ok(status.await?)
+    }
+    ("pause" | "resume", "") => {
+      let Some(control) = get_control() else {
+        unreachable!("Commands are only received with a control socket")
+      };
+      control.paused.send_replace(command == "pause");
+      Ok("ok".into())
+    }
+    ("rescan", path) if !path.is_empty() => {
+      let (reply, rescanned) = oneshot::channel();
+      requests
+        .send(Request::Rescan(PathBuf::from(path), reply))
+        .await?;
+      rescanned.await??;
+      Ok("ok".into())
+    }
+    _ => bail!("Unknown command {line:?}, expected status, pause, resume or rescan PATH"),
+  }
+}
+
+/// Answers the commands of a client, one line each.
+async fn serve(stream: impl AsyncRead + AsyncWrite, requests: mpsc::Sender<Request>) -> Result<()> {
+  let (reader, mut writer) = tokio::io::split(stream);
+  let mut lines = BufReader::new(reader).lines();
+  while let Some(line) = lines.next_line().await? {
+    let reply = match execute(line.trim(), &requests).await {
+      Ok(reply) => reply,
+      Err(e) => format!("error: {e:#}"),
+    };
+    writer.write_all(reply.as_bytes()).await?;
+    writer.write_all(b"\n").await?;
+  }
+  Ok(())
+}
+
+fn spawn_serve(
+  stream: impl AsyncRead + AsyncWrite + Send + 'static,
+  requests: mpsc::Sender<Request>,
+) {
+  tokio::spawn(async move {
+    if let Err(e) = serve(stream, requests).await {
+      output::print_error(format_args!("Control connection failed: {e}"));
+    }
+  });
+}
+
+#[cfg(unix)]
+fn listen(path: &Path, requests: mpsc::Sender<Request>) -> Result<()> {
+  use tokio::net::UnixListener;
+  // A socket left behind by a previous run would fail the bind.
+  if std::fs::symlink_metadata(path).is_ok_and(|metadata| {
+    use std::os::unix::fs::FileTypeExt;
+    metadata.file_type().is_socket()
+  }) {
+    std::fs::remove_file(path)?;
+  }
+  let listener = UnixListener::bind(path)?;
+  tokio::spawn(async move {
+    loop {
+      match listener.accept().await {
+        Ok((stream, _)) => spawn_serve(stream, requests.clone()),
+        Err(e) => output::print_error(format_args!("Could not accept control connection: {e}")),
+      }
+    }
+  });
+  Ok(())
+}
+
+/// Listens on a named pipe, e.g. `\\.\pipe\hardlinkdedup`.
+#[cfg(windows)]
+fn listen(path: &Path, requests: mpsc::Sender<Request>) -> Result<()> {
+  use tokio::net::windows::named_pipe::ServerOptions;
+  let path = path.to_owned();
+  let mut server = ServerOptions::new()
+    .first_pipe_instance(true)
+    .create(&path)?;
+  tokio::spawn(async move {
+    loop {
+      if let Err(e) = server.connect().await {
+        output::print_error(format_args!("Could not accept control connection: {e}"));
+        continue;
+      }
+      // A new instance has to exist before the connected one is handed off, or clients would find
+      // no pipe in between.
+      let next = match ServerOptions::new().create(&path) {
+        Ok(next) => next,
+        Err(e) => {
+          output::print_error(format_args!("Could not create control pipe: {e}"));
+          return;
+        }
+      };
+      spawn_serve(std::mem::replace(&mut server, next), requests.clone());
+    }
+  });
+  Ok(())
+}
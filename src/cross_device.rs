@@ -0,0 +1,108 @@
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use anyhow::Result;
+use tokio::task::JoinSet;
+
+use crate::{
+  os::StorageUid,
+  storage::{calculate_file_hash_with_context, FileStorageData},
+  Filesize, HashDigest, Stats,
+};
+
+/// Files with the same content on different storage devices, which can't be linked to each other.
+#[derive(Debug)]
+pub struct CrossDeviceGroup {
+  pub size: Filesize,
+  pub hash: HashDigest,
+  /// One path on every storage device with the content.
+  pub paths: Vec<(StorageUid, Arc<Path>)>,
+}
+
+impl CrossDeviceGroup {
+  /// The storage taken up by the copies beyond the first.
+  pub fn wasted(&self) -> Filesize {
+    self.size * (self.paths.len() as Filesize - 1)
+  }
+}
+
+/// A file left over after a run, which may have copies on other storage devices.
+pub enum Found {
+  /// The original of its content on its storage.
+  Hashed(StorageUid, Arc<Path>, (Filesize, HashDigest)),
+  /// A file that was never hashed, since nothing else on its storage could be a duplicate of it.
+  Unhashed(FileStorageData),
+}
+
+impl Found {
+  fn size(&self) -> Filesize {
+    match self {
+      Found::Hashed(_, _, (size, _)) => *size,
+      Found::Unhashed(file) => file.size,
+    }
+  }
+
+  fn storage_uid(&self) -> StorageUid {
+    match self {
+      Found::Hashed(storage_uid, ..) => *storage_uid,
+      Found::Unhashed(file) => file.storage_uid,
+    }
+  }
+}
+
+/// Groups the files by content across storage devices, for `--cross-device`. Only the files whose
+/// size is also found on another device are hashed. The groups wasting the most come first.
+pub async fn find(found: Vec<Found>, stats: &mut Stats) -> Result<Vec<CrossDeviceGroup>> {
+  let mut by_size = HashMap::<Filesize, Vec<Found>>::new();
+  for file in found {
+    by_size.entry(file.size()).or_default().push(file);
+  }
+  let mut hashed = vec![];
+  let mut worker = JoinSet::new();
+  let on_several_devices = |files: &Vec<Found>| {
+    files
+      .iter()
+      .any(|file| file.storage_uid() != files[0].storage_uid())
+  };
+  for file in by_size.into_values().filter(on_several_devices).flatten() {
+    match file {
+      Found::Hashed(storage_uid, path, key) => hashed.push((storage_uid, path, key)),
+      Found::Unhashed(file) => {
+        worker.spawn(async move {
+          let digest = calculate_file_hash_with_context(&file).await?;
+          anyhow::Ok((file, digest))
+        });
+      }
+    }
+  }
+  while let Some(result) = worker.join_next().await {
+    let (file, digest) = result??;
+    stats.files_hashed += 1;
+    stats.bytes_hashed += file.size;
+    if let Some(digest) = digest {
+      hashed.push((file.storage_uid, file.path, (file.size, digest)));
+    }
+  }
+
+  let mut by_content = HashMap::<(Filesize, HashDigest), Vec<(StorageUid, Arc<Path>)>>::new();
+  for (storage_uid, path, key) in hashed {
+    let paths = by_content.entry(key).or_default();
+    // Files on the same storage that weren't merged, e.g. for differing metadata, are left out.
+    if paths.iter().all(|(other, _)| *other != storage_uid) {
+      paths.push((storage_uid, path));
+    }
+  }
+  let mut groups = by_content
+    .into_iter()
+    .filter(|(_, paths)| paths.len() > 1)
+    .map(|((size, hash), mut paths)| {
+      paths.sort_by(|(_, a), (_, b)| a.cmp(b));
+      CrossDeviceGroup { size, hash, paths }
+    })
+    .collect::<Vec<_>>();
+  groups.sort_by(|a, b| {
+    b.wasted()
+      .cmp(&a.wasted())
+      .then_with(|| a.paths[0].1.cmp(&b.paths[0].1))
+  });
+  Ok(groups)
+}
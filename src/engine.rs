@@ -0,0 +1,300 @@
+use std::{
+  future::Future,
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{CommandFactory, FromArgMatches};
+use regex::Regex;
+use tokio::{
+  sync::Mutex,
+  task::{JoinError, JoinHandle},
+};
+
+use crate::{
+  backup, cache, control, filter, interactive, journal, keep, log_file, manifest, observer, output,
+  priority, progress, storage, undo, DedupArgs, Filesize, HashAlgorithm, Keep, Observer,
+  OutputFormat, Report, Strategy, ARGS,
+};
+
+fn canonical_path(path: &PathBuf) -> Result<PathBuf> {
+  std::fs::canonicalize(path).with_context(|| format!("Could not find {}", path.display()))
+}
+
+/// The canonical paths of `paths`, without those below another one of them, so that no file is
+/// scanned twice.
+fn canonical_roots(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+  let mut roots: Vec<PathBuf> = vec![];
+  for path in paths {
+    let path = canonical_path(path)?;
+    if !roots.iter().any(|root| path.starts_with(root)) {
+      roots.retain(|root| !root.starts_with(&path));
+      roots.push(path);
+    }
+  }
+  Ok(roots)
+}
+
+/// Configures a `DedupEngine`. Everything that isn't set has the default of the command line.
+pub struct DedupEngineBuilder {
+  args: DedupArgs,
+  observer: Option<Box<dyn Observer>>,
+}
+
+impl Default for DedupEngineBuilder {
+  fn default() -> Self {
+    let matches = DedupArgs::command()
+      // Only the missing paths would fail, which are added to the builder later.
+      .ignore_errors(true)
+      .get_matches_from(["hard-link-dedup"]);
+    DedupArgs::from_arg_matches(&matches)
+      .expect("The defaults are valid arguments")
+      .into()
+  }
+}
+
+impl From<DedupArgs> for DedupEngineBuilder {
+  /// Starts from every option of the command line, e.g. from `DedupArgs::parse_with_config`.
+  fn from(args: DedupArgs) -> Self {
+    Self {
+      args,
+      observer: None,
+    }
+  }
+}
+
+impl DedupEngineBuilder {
+  /// Adds a path where files will be deduplicated.
+  pub fn path(mut self, path: impl Into<PathBuf>) -> Self {
+    self.args.path.push(path.into());
+    self
+  }
+
+  /// Only deduplicate files whose name fully matches `pattern`. May be called multiple times, to
+  /// include files matching any of the patterns.
+  pub fn pattern(mut self, pattern: Regex) -> Self {
+    self.args.pattern.push(pattern);
+    self
+  }
+
+  /// Link duplicates in the paths to their copies in `dir`, which is never modified. May be called
+  /// multiple times.
+  pub fn reference(mut self, dir: impl Into<PathBuf>) -> Self {
+    self.args.reference.push(dir.into());
+    self
+  }
+
+  /// Skip files and directories whose name fully matches `pattern`.
+  pub fn exclude(mut self, pattern: Regex) -> Self {
+    self.args.exclude = Some(pattern);
+    self
+  }
+
+  /// Only deduplicate files with this extension, like `--ext`. May be called multiple times.
+  pub fn extension(mut self, extension: impl Into<String>) -> Self {
+    self.args.ext.push(extension.into());
+    self
+  }
+
+  /// Only deduplicate files matching `glob`, like `--glob`.
+  pub fn glob(mut self, glob: &str) -> Result<Self> {
+    self.args.glob.push(filter::parse_glob(glob)?);
+    Ok(self)
+  }
+
+  /// Skip files matching `glob`, like `--exclude-glob`.
+  pub fn exclude_glob(mut self, glob: &str) -> Result<Self> {
+    self.args.exclude_glob.push(filter::parse_glob(glob)?);
+    Ok(self)
+  }
+
+  /// Don't descend into directories matching `glob`, like `--exclude-dir`.
+  pub fn exclude_dir(mut self, glob: &str) -> Result<Self> {
+    self.args.exclude_dir.push(filter::parse_glob(glob)?);
+    Ok(self)
+  }
+
+  /// The smallest file to deduplicate, in KiB.
+  pub fn min_file_size(mut self, kib: Filesize) -> Self {
+    self.args.min_file_size = kib;
+    self
+  }
+
+  /// The largest file to deduplicate, in KiB.
+  pub fn max_file_size(mut self, kib: Filesize) -> Self {
+    self.args.max_file_size = Some(kib);
+    self
+  }
+
+  /// Ignore files modified more recently than `age`.
+  pub fn older_than(mut self, age: std::time::Duration) -> Self {
+    self.args.older_than = Some(age.into());
+    self
+  }
+
+  pub fn strategy(mut self, strategy: Strategy) -> Self {
+    self.args.strategy = strategy;
+    self
+  }
+
+  pub fn keep(mut self, keep: Keep) -> Self {
+    self.args.keep = keep;
+    self
+  }
+
+  pub fn hash(mut self, hash: HashAlgorithm) -> Self {
+    self.args.hash = hash;
+    self
+  }
+
+  /// List the duplicate groups after the run, without merging anything.
+  pub fn report(mut self, report: bool) -> Self {
+    self.args.report = report;
+    self
+  }
+
+  /// Only report what would be merged.
+  pub fn dry_run(mut self, dry_run: bool) -> Self {
+    self.args.dry_run = dry_run;
+    self
+  }
+
+  /// Don't print anything but errors.
+  pub fn quiet(mut self, quiet: bool) -> Self {
+    self.args.quiet = quiet;
+    self
+  }
+
+  /// Receives the events of the run, e.g. to render progress.
+  pub fn observer(mut self, observer: impl Observer + 'static) -> Self {
+    self.observer = Some(Box::new(observer));
+    self
+  }
+
+  /// Configures the engine. The configuration is global to the process, so this fails if an engine
+  /// has already been built.
+  pub fn build(self) -> Result<DedupEngine> {
+    let (mut args, observer) = (self.args, self.observer);
+    // Nothing is merged for the report, just like for a dry run.
+    args.dry_run |= args.report;
+    if args.command.is_none() && args.path.is_empty() && args.files_from.is_none() && !args.resume {
+      bail!("No paths to deduplicate");
+    }
+    if args.summary_json.as_deref() == Some(Path::new("-")) && args.format != OutputFormat::Text {
+      bail!("--summary-json can't be printed on stdout together with --format");
+    }
+    args.path = canonical_roots(&args.path)?;
+    args.reference = canonical_roots(&args.reference)?;
+    // Reference directories are scanned like the paths, but only ever provide originals. A path
+    // below a reference is scanned on its own, since its files are still deduplicated.
+    for reference in &args.reference {
+      if !args.path.iter().any(|path| reference.starts_with(path)) {
+        args.path.push(reference.clone());
+      }
+    }
+    ARGS
+      .set(args)
+      .map_err(|_| anyhow!("A DedupEngine has already been built in this process"))?;
+    observer::init(observer);
+    log_file::init()?;
+    priority::init()?;
+    interactive::init()?;
+    #[cfg(feature = "tui")]
+    crate::tui::init()?;
+    Ok(DedupEngine(()))
+  }
+}
+
+/// Finds duplicate files and merges them according to the configuration it was built with.
+#[derive(Debug)]
+pub struct DedupEngine(());
+
+impl DedupEngine {
+  pub fn builder() -> DedupEngineBuilder {
+    DedupEngineBuilder::default()
+  }
+
+  pub fn args(&self) -> &'static DedupArgs {
+    DedupArgs::get()
+  }
+
+  /// Reverses the merges recorded in `undo_log`.
+  pub async fn undo(&self, undo_log: &Path) -> Result<()> {
+    undo::undo(undo_log).await
+  }
+
+  /// Deduplicates every configured path.
+  pub async fn run(&self) -> Result<Report> {
+    let (report, result) = self.run_until(std::future::pending()).await;
+    result.map(|()| report)
+  }
+
+  /// Deduplicates every configured path, until `stop` completes. The report covers what was done
+  /// before the run stopped or failed.
+  pub async fn run_until(&self, stop: impl Future<Output = ()>) -> (Report, Result<()>) {
+    let report: Arc<Mutex<Report>> = Default::default();
+    let result = self.run_with_report(report.clone(), stop).await;
+    progress::finish();
+    // Digests calculated before a failure are still worth keeping.
+    let saved = cache::save();
+    let result = result.and(saved);
+    let report = std::mem::take(&mut *report.lock().await);
+    (report, result)
+  }
+
+  /// Aborts the run once the merges in progress are done.
+  async fn stop(handle: JoinHandle<Result<bool>>) -> Result<Result<bool>, JoinError> {
+    let _merging = crate::stop_merging().await;
+    handle.abort();
+    handle.await
+  }
+
+  async fn run_with_report(
+    &self,
+    report: Arc<Mutex<Report>>,
+    stop: impl Future<Output = ()>,
+  ) -> Result<()> {
+    storage::init_aliases().await?;
+    cache::init()?;
+    journal::init()?;
+    manifest::init()?;
+    keep::init()?;
+    undo::init()?;
+    backup::init().await?;
+    control::init()?;
+    let mut handle = tokio::task::spawn(crate::run(report));
+    let max_runtime = async {
+      match DedupArgs::get().max_runtime {
+        Some(max_runtime) => {
+          tokio::time::sleep(*max_runtime).await;
+          output::print_info(format!("Stopping after running for {max_runtime}"))?;
+        }
+        None => std::future::pending().await,
+      }
+      anyhow::Ok(())
+    };
+    let joined = tokio::select! {
+      joined = &mut handle => joined,
+      () = stop => Self::stop(handle).await,
+      () = interactive::quit_requested() => Self::stop(handle).await,
+      stopped = max_runtime => {
+        stopped?;
+        Self::stop(handle).await
+      }
+    };
+    match joined {
+      // Merges that a run stopped short of stay in the journal for `--resume`, and the digests of
+      // files it didn't get to stay in the cache.
+      Ok(Ok(true)) => {
+        journal::finish()?;
+        manifest::finish()?;
+        cache::prune();
+        Ok(())
+      }
+      Ok(result) => result.map(drop),
+      Err(e) if e.is_cancelled() => Ok(()),
+      Err(e) => Err(e.into()),
+    }
+  }
+}
@@ -0,0 +1,59 @@
+use std::sync::OnceLock;
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// File handles kept out of the limiter, for stdio, the log file, the journal and the runtime.
+const RESERVED: usize = 32;
+
+/// Raises the soft limit of open files to the hard limit, and returns the limit.
+#[cfg(unix)]
+fn open_file_limit() -> usize {
+  let mut limit = libc::rlimit {
+    rlim_cur: 0,
+    rlim_max: 0,
+  };
+  // SAFETY: `limit` is a valid `rlimit` for the duration of both calls.
+  unsafe {
+    if libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) != 0 {
+      return 256;
+    }
+    let raised = libc::rlimit {
+      rlim_cur: limit.rlim_max.min(1 << 20),
+      rlim_max: limit.rlim_max,
+    };
+    if libc::setrlimit(libc::RLIMIT_NOFILE, &raised) == 0 {
+      limit = raised;
+    }
+  }
+  limit.rlim_cur.try_into().unwrap_or(usize::MAX)
+}
+
+/// Windows has no practical limit on handles, but they are still bounded to keep the memory used by
+/// the kernel reasonable.
+#[cfg(windows)]
+fn open_file_limit() -> usize {
+  8192
+}
+
+static FD_SEMAPHORE: OnceLock<Semaphore> = OnceLock::new();
+
+fn get_fd_lock() -> &'static Semaphore {
+  FD_SEMAPHORE.get_or_init(|| Semaphore::new(open_file_limit().saturating_sub(RESERVED).max(1)))
+}
+
+/// Waits until a file or directory handle may be opened. The permit must be held for as long as
+/// the handle is open, and no other permit may be acquired while holding it.
+pub async fn acquire() -> SemaphorePermit<'static> {
+  get_fd_lock()
+    .acquire()
+    .await
+    .expect("The semaphore is never closed")
+}
+
+/// Waits until `count` handles may be opened at once.
+pub async fn acquire_many(count: u32) -> SemaphorePermit<'static> {
+  get_fd_lock()
+    .acquire_many(count)
+    .await
+    .expect("The semaphore is never closed")
+}
@@ -0,0 +1,284 @@
+use std::{
+  path::Path,
+  sync::{Arc, OnceLock},
+};
+
+use anyhow::{Context, Result};
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use ignore::{
+  gitignore::{Gitignore, GitignoreBuilder},
+  Match,
+};
+use regex::{Regex, RegexBuilder};
+use tokio::fs;
+
+use crate::{storage::FileStorageData, DedupArgs};
+
+pub fn parse_glob(glob: &str) -> Result<Glob, globset::Error> {
+  GlobBuilder::new(glob).literal_separator(true).build()
+}
+
+/// The user ID of the user called `name`, for `--owner`.
+#[cfg(unix)]
+pub fn parse_user(name: &str) -> Result<u32> {
+  use std::{ffi::CString, io};
+  let c_name = CString::new(name).context("User names can't contain NUL")?;
+  let mut buffer = vec![0 as libc::c_char; 1024];
+  loop {
+    // SAFETY: `passwd` is plain data that `getpwnam_r` fills in.
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    // SAFETY: The strings of `passwd` point into `buffer`, which outlives them.
+    let error = unsafe {
+      libc::getpwnam_r(
+        c_name.as_ptr(),
+        &mut passwd,
+        buffer.as_mut_ptr(),
+        buffer.len(),
+        &mut result,
+      )
+    };
+    match error {
+      0 if result.is_null() => anyhow::bail!("No such user {name}"),
+      0 => return Ok(passwd.pw_uid),
+      libc::ERANGE => buffer.resize(buffer.len() * 2, 0),
+      error => {
+        return Err(io::Error::from_raw_os_error(error))
+          .with_context(|| format!("Could not look up user {name}"))
+      }
+    }
+  }
+}
+
+fn build_glob_set(globs: &[Glob], ignore_case: bool) -> Option<GlobSet> {
+  if globs.is_empty() {
+    return None;
+  }
+  let mut builder = GlobSetBuilder::new();
+  for glob in globs {
+    builder.add(
+      GlobBuilder::new(glob.glob())
+        .literal_separator(true)
+        .case_insensitive(ignore_case)
+        .build()
+        .expect("Globs are validated by the argument parser"),
+    );
+  }
+  Some(
+    builder
+      .build()
+      .expect("Globs are validated by the argument parser"),
+  )
+}
+
+fn build_regex(pattern: &Regex, ignore_case: bool) -> Regex {
+  RegexBuilder::new(pattern.as_str())
+    .case_insensitive(ignore_case)
+    .build()
+    .expect("Patterns are validated by the argument parser")
+}
+
+/// The name filters, built with `--ignore-case`.
+struct Patterns {
+  include: Vec<Regex>,
+  exclude: Option<Regex>,
+  include_glob: Option<GlobSet>,
+  exclude_glob: Option<GlobSet>,
+  exclude_dir: Option<GlobSet>,
+}
+
+static PATTERNS: OnceLock<Patterns> = OnceLock::new();
+
+fn get_patterns() -> &'static Patterns {
+  PATTERNS.get_or_init(|| {
+    let args = DedupArgs::get();
+    let ignore_case = args.ignore_case;
+    Patterns {
+      include: args
+        .pattern
+        .iter()
+        .map(|pattern| build_regex(pattern, ignore_case))
+        .collect(),
+      exclude: args
+        .exclude
+        .as_ref()
+        .map(|pattern| build_regex(pattern, ignore_case)),
+      include_glob: build_glob_set(&args.glob, ignore_case),
+      exclude_glob: build_glob_set(&args.exclude_glob, ignore_case),
+      exclude_dir: build_glob_set(&args.exclude_dir, ignore_case),
+    }
+  })
+}
+
+fn full_match(pattern: &Regex, haystack: &str) -> bool {
+  pattern
+    .find(haystack)
+    .is_some_and(|found| found.start() == 0 && found.end() == haystack.len())
+}
+
+/// Whether a file or directory should be skipped entirely.
+pub fn is_excluded(name: &str) -> bool {
+  let patterns = get_patterns();
+  if let Some(ref exclude) = patterns.exclude {
+    if full_match(exclude, name) {
+      return true;
+    }
+  }
+  if let Some(ref exclude) = patterns.exclude_glob {
+    if exclude.is_match(name) {
+      return true;
+    }
+  }
+  false
+}
+
+/// Whether a directory should be pruned from the scan.
+pub fn is_excluded_dir(name: &str) -> bool {
+  get_patterns()
+    .exclude_dir
+    .as_ref()
+    .is_some_and(|exclude_dir| exclude_dir.is_match(name))
+}
+
+/// Whether `name` has one of the `--ext` extensions, if any.
+pub fn has_extension(name: impl AsRef<Path>) -> bool {
+  let extensions = &DedupArgs::get().ext;
+  if extensions.is_empty() {
+    return true;
+  }
+  let Some(extension) = name.as_ref().extension() else {
+    return false;
+  };
+  let extension = extension.to_string_lossy();
+  extensions.iter().any(|wanted| {
+    wanted
+      .trim_start_matches('.')
+      .eq_ignore_ascii_case(&extension)
+  })
+}
+
+/// Whether a file matches the include filters.
+pub fn is_included(name: &str) -> bool {
+  let patterns = get_patterns();
+  if !has_extension(name) {
+    return false;
+  }
+  if !patterns.include.is_empty()
+    && !patterns
+      .include
+      .iter()
+      .any(|pattern| full_match(pattern, name))
+  {
+    return false;
+  }
+  if let Some(ref include) = patterns.include_glob {
+    if !include.is_match(name) {
+      return false;
+    }
+  }
+  true
+}
+
+/// Whether `file` is owned by the `--owner` or `--uid` user, if any.
+#[cfg(unix)]
+pub fn is_owned(file: &FileStorageData) -> bool {
+  let args = DedupArgs::get();
+  args
+    .owner
+    .or(args.uid)
+    .map_or(true, |uid| file.owner == uid)
+}
+
+#[cfg(windows)]
+pub fn is_owned(_file: &FileStorageData) -> bool {
+  true
+}
+
+/// The ignore files found from a scanned root down to the directory currently being scanned.
+#[derive(Debug)]
+pub struct IgnoreStack {
+  matcher: Gitignore,
+  parent: Option<Arc<IgnoreStack>>,
+}
+
+impl IgnoreStack {
+  /// Pushes the ignore files of `dir` (if any) on top of `parent`.
+  pub async fn read_dir(
+    dir: &Path,
+    parent: Option<Arc<IgnoreStack>>,
+  ) -> Result<Option<Arc<IgnoreStack>>> {
+    let args = DedupArgs::get();
+    let mut builder = GitignoreBuilder::new(dir);
+    let mut found_any = false;
+    let file_names: &[&str] = if args.gitignore {
+      &[".gitignore", ".dedupignore"]
+    } else {
+      &[".dedupignore"]
+    };
+    for file_name in file_names {
+      let ignore_file = dir.join(file_name);
+      if fs::try_exists(&ignore_file).await? {
+        if let Some(e) = builder.add(&ignore_file) {
+          Err(e).with_context(|| format!("Could not parse {}", ignore_file.display()))?;
+        }
+        found_any = true;
+      }
+    }
+    if !found_any {
+      return Ok(parent);
+    }
+    Ok(Some(Arc::new(IgnoreStack {
+      matcher: builder.build()?,
+      parent,
+    })))
+  }
+
+  pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+    let mut current = Some(self);
+    while let Some(ignores) = current {
+      match ignores.matcher.matched(path, is_dir) {
+        Match::Ignore(_) => return true,
+        Match::Whitelist(_) => return false,
+        Match::None => current = ignores.parent.as_deref(),
+      }
+    }
+    false
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn patterns_match_the_whole_name() {
+    let pattern = Regex::new(r"\.?cache").unwrap();
+    assert!(full_match(&pattern, "cache"));
+    assert!(full_match(&pattern, ".cache"));
+    assert!(!full_match(&pattern, "cache.bin"));
+    assert!(!full_match(&pattern, "my-cache"));
+  }
+
+  #[test]
+  fn globs_do_not_match_across_separators() {
+    let glob = parse_glob("*.bin").unwrap().compile_matcher();
+    assert!(glob.is_match("file.bin"));
+    assert!(!glob.is_match("dir/file.bin"));
+    let glob = parse_glob("**/*.bin").unwrap().compile_matcher();
+    assert!(glob.is_match("dir/sub/file.bin"));
+    assert!(parse_glob("[").is_err());
+  }
+
+  #[test]
+  fn ignore_case_applies_to_patterns_and_globs() {
+    let pattern = Regex::new("cache").unwrap();
+    assert!(!full_match(&build_regex(&pattern, false), "Cache"));
+    assert!(full_match(&build_regex(&pattern, true), "Cache"));
+    let globs = [parse_glob("*.bin").unwrap()];
+    assert!(build_glob_set(&[], true).is_none());
+    assert!(!build_glob_set(&globs, false).unwrap().is_match("FILE.BIN"));
+    let globs = build_glob_set(&globs, true).unwrap();
+    assert!(globs.is_match("FILE.BIN"));
+    assert!(!globs.is_match("DIR/FILE.BIN"));
+  }
+}
@@ -0,0 +1,103 @@
+use std::{fs::File, io, path::Path};
+
+use clap::ValueEnum;
+use memmap2::Mmap;
+use sha2::{Digest, Sha256};
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::{DedupArgs, HashDigest, HASH_LEN};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum HashAlgorithm {
+  /// BLAKE3, which is fast and cryptographically secure.
+  Blake3,
+  /// XXH3 with a 128 bit digest, which is faster but not cryptographically secure.
+  #[value(name = "xxh3-128")]
+  Xxh3_128,
+  /// SHA-256, which is slower but matches the digests of `sha256sum`.
+  Sha256,
+}
+
+impl HashAlgorithm {
+  pub fn get() -> Self {
+    DedupArgs::get().hash
+  }
+
+  /// The number of bytes of a `HashDigest` that are used by this algorithm. The rest are zero.
+  pub fn digest_len(self) -> usize {
+    match self {
+      HashAlgorithm::Blake3 => blake3::OUT_LEN,
+      HashAlgorithm::Xxh3_128 => 16,
+      HashAlgorithm::Sha256 => 32,
+    }
+  }
+
+  /// A stable identifier of the algorithm, stored in the hash cache.
+  pub fn id(self) -> u32 {
+    match self {
+      HashAlgorithm::Blake3 => 0,
+      HashAlgorithm::Xxh3_128 => 1,
+      HashAlgorithm::Sha256 => 2,
+    }
+  }
+
+  pub fn hasher(self) -> Hasher {
+    match self {
+      HashAlgorithm::Blake3 => Hasher::Blake3(Box::default()),
+      HashAlgorithm::Xxh3_128 => Hasher::Xxh3_128(Box::default()),
+      HashAlgorithm::Sha256 => Hasher::Sha256(Box::default()),
+    }
+  }
+}
+
+/// Hashes a file with BLAKE3 using every core, by memory mapping it. Returns the digest and the
+/// number of bytes hashed. This blocks, and the file must not be truncated while it's hashed.
+pub fn blake3_parallel(path: &Path) -> io::Result<(HashDigest, u64)> {
+  let mut hasher = blake3::Hasher::new();
+  hasher.update_mmap_rayon(path)?;
+  let mut digest = [0; HASH_LEN];
+  digest.copy_from_slice(hasher.finalize().as_bytes());
+  Ok((digest, hasher.count()))
+}
+
+/// Hashes a file by memory mapping it. Returns the digest and the number of bytes hashed, or `None`
+/// if the file can't be mapped. This blocks, and the file must not be truncated while it's hashed.
+pub fn hash_mapped(path: &Path, algorithm: HashAlgorithm) -> io::Result<Option<(HashDigest, u64)>> {
+  let file = File::open(path)?;
+  // SAFETY: The mapping is only read, and only for as long as the file is hashed.
+  let Ok(map) = (unsafe { Mmap::map(&file) }) else {
+    return Ok(None);
+  };
+  let mut hasher = algorithm.hasher();
+  hasher.update(&map);
+  Ok(Some((hasher.finalize(), map.len() as u64)))
+}
+
+pub enum Hasher {
+  Blake3(Box<blake3::Hasher>),
+  Xxh3_128(Box<Xxh3>),
+  Sha256(Box<Sha256>),
+}
+
+impl Hasher {
+  pub fn update(&mut self, data: &[u8]) {
+    match self {
+      Hasher::Blake3(hasher) => {
+        hasher.update(data);
+      }
+      Hasher::Xxh3_128(hasher) => hasher.update(data),
+      Hasher::Sha256(hasher) => hasher.update(data),
+    }
+  }
+
+  pub fn finalize(self) -> HashDigest {
+    let mut digest = [0; HASH_LEN];
+    match self {
+      Hasher::Blake3(hasher) => digest.copy_from_slice(hasher.finalize().as_bytes()),
+      // Big endian, like the canonical representation printed by `xxhsum`.
+      Hasher::Xxh3_128(hasher) => digest[..16].copy_from_slice(&hasher.digest128().to_be_bytes()),
+      Hasher::Sha256(hasher) => digest.copy_from_slice(&hasher.finalize()),
+    }
+    digest
+  }
+}
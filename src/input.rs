@@ -0,0 +1,131 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use tokio::{
+  fs,
+  io::{self, AsyncBufReadExt, AsyncRead, BufReader},
+};
+
+#[cfg(unix)]
+pub fn bytes_to_path(bytes: Vec<u8>) -> Result<PathBuf> {
+  use std::{ffi::OsString, os::unix::ffi::OsStringExt};
+  Ok(OsString::from_vec(bytes).into())
+}
+
+#[cfg(windows)]
+pub fn bytes_to_path(bytes: Vec<u8>) -> Result<PathBuf> {
+  Ok(String::from_utf8(bytes)?.into())
+}
+
+async fn read_paths(reader: impl AsyncRead + Unpin, separator: u8) -> Result<Vec<PathBuf>> {
+  let mut reader = BufReader::new(reader);
+  let mut paths = vec![];
+  loop {
+    let mut line = vec![];
+    if reader.read_until(separator, &mut line).await? == 0 {
+      break;
+    }
+    if line.last() == Some(&separator) {
+      line.pop();
+    }
+    if separator == b'\n' && line.last() == Some(&b'\r') {
+      line.pop();
+    }
+    if !line.is_empty() {
+      paths.push(bytes_to_path(line)?);
+    }
+  }
+  Ok(paths)
+}
+
+/// Reads groups of paths written by `fdupes` or `jdupes`: one path per line, with blank lines
+/// between the groups.
+async fn read_groups(reader: impl AsyncRead + Unpin) -> Result<Vec<Vec<PathBuf>>> {
+  let mut reader = BufReader::new(reader);
+  let mut groups = vec![vec![]];
+  loop {
+    let mut line = vec![];
+    if reader.read_until(b'\n', &mut line).await? == 0 {
+      break;
+    }
+    if line.last() == Some(&b'\n') {
+      line.pop();
+    }
+    if line.last() == Some(&b'\r') {
+      line.pop();
+    }
+    if !line.is_empty() {
+      groups
+        .last_mut()
+        .expect("Never empty")
+        .push(bytes_to_path(line)?);
+    } else if groups.last().is_some_and(|group| !group.is_empty()) {
+      groups.push(vec![]);
+    }
+  }
+  groups.retain(|group| group.len() > 1);
+  Ok(groups)
+}
+
+/// Reads duplicate groups in the format of `fdupes` from `source`, or from stdin if `source` is
+/// `-`. Groups of a single path are dropped.
+pub async fn read_group_list(source: &Path) -> Result<Vec<Vec<PathBuf>>> {
+  if source == Path::new("-") {
+    read_groups(io::stdin())
+      .await
+      .context("Could not read duplicate groups from stdin")
+  } else {
+    let file = fs::File::open(source)
+      .await
+      .with_context(|| format!("Could not open duplicate groups {}", source.display()))?;
+    read_groups(file)
+      .await
+      .with_context(|| format!("Could not read duplicate groups {}", source.display()))
+  }
+}
+
+/// Reads a list of paths separated by `separator` from `source`, or from stdin if `source` is `-`.
+pub async fn read_path_list(source: &Path, separator: u8) -> Result<Vec<PathBuf>> {
+  if source == Path::new("-") {
+    read_paths(io::stdin(), separator)
+      .await
+      .context("Could not read path list from stdin")
+  } else {
+    let file = fs::File::open(source)
+      .await
+      .with_context(|| format!("Could not open path list {}", source.display()))?;
+    read_paths(file, separator)
+      .await
+      .with_context(|| format!("Could not read path list {}", source.display()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn paths(paths: &[&str]) -> Vec<PathBuf> {
+    paths.iter().map(PathBuf::from).collect()
+  }
+
+  #[tokio::test]
+  async fn groups_are_separated_by_blank_lines() {
+    let groups = read_groups(&b"a\nb\n\nc\r\nd\r\ne\r\n"[..]).await.unwrap();
+    assert_eq!(groups, [paths(&["a", "b"]), paths(&["c", "d", "e"])]);
+  }
+
+  #[tokio::test]
+  async fn single_paths_and_extra_blank_lines_are_dropped() {
+    let groups = read_groups(&b"\n\na\n\n\n\nb\n\nc\nd"[..]).await.unwrap();
+    assert_eq!(groups, [paths(&["c", "d"])]);
+    assert!(read_groups(&b""[..]).await.unwrap().is_empty());
+  }
+
+  #[tokio::test]
+  async fn paths_are_split_by_the_separator() {
+    let list = read_paths(&b"a b\0c\nd\0\0"[..], b'\0').await.unwrap();
+    assert_eq!(list, paths(&["a b", "c\nd"]));
+    let list = read_paths(&b"a\r\nb\n\nc"[..], b'\n').await.unwrap();
+    assert_eq!(list, paths(&["a", "b", "c"]));
+  }
+}
@@ -0,0 +1,101 @@
+use std::{
+  io::{IsTerminal, Write},
+  path::{Path, PathBuf},
+  sync::{
+    atomic::{AtomicBool, Ordering},
+    OnceLock,
+  },
+};
+
+use anyhow::{bail, Result};
+use tokio::sync::Notify;
+
+use crate::{output, progress, DedupArgs, Filesize};
+
+/// Set by answering `all`, after which merges are no longer asked for.
+static MERGE_ALL: AtomicBool = AtomicBool::new(false);
+/// Set by answering `quit`, after which no more merges are made.
+static QUIT: AtomicBool = AtomicBool::new(false);
+static QUIT_NOTIFY: OnceLock<Notify> = OnceLock::new();
+
+enum Answer {
+  Yes,
+  No,
+  All,
+  Quit,
+}
+
+fn is_enabled() -> bool {
+  let args = DedupArgs::get();
+  args.interactive && !args.dry_run
+}
+
+/// Fails if `--interactive` has nobody to ask.
+pub fn init() -> Result<()> {
+  if is_enabled() && !std::io::stdin().is_terminal() {
+    bail!("--interactive requires stdin to be a terminal");
+  }
+  Ok(())
+}
+
+fn prompt(original: &Path, redundant: &Path, size: Filesize) -> Result<Answer> {
+  progress::suspend(|| loop {
+    eprint!(
+      "Merge {} into {} ({})? [y/N/all/quit] ",
+      redundant.display(),
+      original.display(),
+      output::format_size(size)
+    );
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer)? == 0 {
+      // Nobody is left to ask.
+      eprintln!();
+      return Ok(Answer::Quit);
+    }
+    match answer.trim() {
+      "y" | "Y" | "yes" => return Ok(Answer::Yes),
+      "" | "n" | "N" | "no" => return Ok(Answer::No),
+      "a" | "all" => return Ok(Answer::All),
+      "q" | "quit" => return Ok(Answer::Quit),
+      _ => eprintln!("Expected y, n, all or quit"),
+    }
+  })
+}
+
+/// Asks the user whether `redundant` may be replaced by `original`, with `--interactive`.
+pub async fn confirm_merge(original: &Path, redundant: &Path, size: Filesize) -> Result<bool> {
+  if QUIT.load(Ordering::Relaxed) {
+    return Ok(false);
+  }
+  if !is_enabled() || MERGE_ALL.load(Ordering::Relaxed) {
+    return Ok(true);
+  }
+  let (original, redundant) = (PathBuf::from(original), PathBuf::from(redundant));
+  let shown = redundant.clone();
+  let answer = tokio::task::spawn_blocking(move || prompt(&original, &redundant, size)).await??;
+  match answer {
+    Answer::Yes => Ok(true),
+    Answer::All => {
+      MERGE_ALL.store(true, Ordering::Relaxed);
+      Ok(true)
+    }
+    Answer::No => {
+      output::print_skipped(&shown, "declined");
+      Ok(false)
+    }
+    Answer::Quit => {
+      QUIT.store(true, Ordering::Relaxed);
+      QUIT_NOTIFY.get_or_init(Notify::new).notify_one();
+      Ok(false)
+    }
+  }
+}
+
+/// Completes when the user answers `quit`. Never completes without `--interactive`.
+pub async fn quit_requested() {
+  if !is_enabled() {
+    return std::future::pending().await;
+  }
+  QUIT_NOTIFY.get_or_init(Notify::new).notified().await
+}
@@ -0,0 +1,450 @@
+use std::{
+  collections::{HashMap, HashSet},
+  fs::{File, OpenOptions},
+  io::{ErrorKind, Write},
+  path::{Path, PathBuf},
+  sync::{Mutex, OnceLock},
+};
+
+use anyhow::{bail, Context, Result};
+use tokio::fs;
+
+use crate::{
+  cache,
+  input::bytes_to_path,
+  manifest::parse_hex,
+  merge_with_hard_link_with_context,
+  output::{self, path_bytes},
+  report::hex,
+  storage::{self, calculate_file_hash, verify_identical_with_context, FileStorageData},
+  temporary_path, temporary_suffix, DedupArgs, Filesize, HashDigest, Stats,
+};
+
+// Every record is four NUL terminated fields: the kind, the original path, the redundant path and
+// the temporary path, or for a plan the size and hex digest separated by a colon. A newline is
+// prepended to the kind to keep the journal somewhat readable.
+const PLAN: &[u8] = b"\nplan";
+const BEGIN: &[u8] = b"\nbegin";
+const DONE: &[u8] = b"\ndone";
+
+static JOURNAL: OnceLock<Option<Mutex<File>>> = OnceLock::new();
+
+fn get_journal() -> Option<&'static Mutex<File>> {
+  JOURNAL.get().and_then(Option::as_ref)
+}
+
+/// Opens the `--journal`, if any.
+pub fn init() -> Result<()> {
+  let args = DedupArgs::get();
+  let journal = match args.journal {
+    Some(ref path) => Some(Mutex::new(
+      OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Could not open journal {}", path.display()))?,
+    )),
+    None => None,
+  };
+  let _ = JOURNAL.set(journal);
+  Ok(())
+}
+
+fn write(records: &[[&[u8]; 4]]) -> Result<()> {
+  let Some(journal) = get_journal() else {
+    return Ok(());
+  };
+  let mut bytes = vec![];
+  for record in records {
+    for field in record {
+      bytes.extend_from_slice(field);
+      bytes.push(b'\0');
+    }
+  }
+  let mut journal = journal.lock().unwrap_or_else(|e| e.into_inner());
+  journal.write_all(&bytes)?;
+  journal.sync_data()?;
+  Ok(())
+}
+
+fn write_merge(kind: &[u8], original: &Path, redundant: &Path, temporary: &Path) -> Result<()> {
+  let (original, redundant, temporary) = (
+    path_bytes(original),
+    path_bytes(redundant),
+    path_bytes(temporary),
+  );
+  write(&[[kind, &original, &redundant, &temporary]])
+}
+
+/// Records that every path in `redundant` is about to be merged into `original`, so that
+/// `--resume` can finish the merges without scanning and hashing again.
+pub fn plan<'a>(
+  original: &Path,
+  redundant: impl IntoIterator<Item = &'a Path>,
+  (size, digest): (Filesize, HashDigest),
+) -> Result<()> {
+  if DedupArgs::get().dry_run {
+    return Ok(());
+  }
+  let original = path_bytes(original);
+  let content = format!("{size}:{}", hex(&digest));
+  let redundant = redundant
+    .into_iter()
+    .map(|path| path_bytes(path).into_owned())
+    .collect::<Vec<_>>();
+  let records = redundant
+    .iter()
+    .map(|redundant| [PLAN, &original, redundant, content.as_bytes()])
+    .collect::<Vec<_>>();
+  write(&records).context("Could not write to journal")
+}
+
+/// Records that a merge is about to start. Must be written before the temporary file is created.
+pub fn begin(original: &Path, redundant: &Path, temporary: &Path) -> Result<()> {
+  write_merge(BEGIN, original, redundant, temporary).context("Could not write to journal")
+}
+
+/// Records that a merge is complete, and that its temporary file is gone.
+pub fn done(original: &Path, redundant: &Path, temporary: &Path) -> Result<()> {
+  write_merge(DONE, original, redundant, temporary).context("Could not write to journal")
+}
+
+/// Empties the journal after a run that finished without leaving any merge half done. A dry run
+/// leaves it as it was, since it finished none of the merges in it.
+pub fn finish() -> Result<()> {
+  let Some(journal) = get_journal() else {
+    return Ok(());
+  };
+  if DedupArgs::get().dry_run {
+    return Ok(());
+  }
+  let journal = journal.lock().unwrap_or_else(|e| e.into_inner());
+  journal.set_len(0).context("Could not truncate journal")
+}
+
+type UnfinishedMerge = (PathBuf, PathBuf, PathBuf);
+/// A merge that was planned but never started, with the size and digest of the files.
+type PlannedMerge = (PathBuf, PathBuf, Filesize, Option<HashDigest>);
+
+fn parse_plan(content: &[u8]) -> Result<(Filesize, Option<HashDigest>)> {
+  let Some((size, digest)) = std::str::from_utf8(content)
+    .ok()
+    .and_then(|content| content.split_once(':'))
+  else {
+    bail!("Invalid journal record");
+  };
+  let size = size.parse().context("Invalid journal record")?;
+  // The digest is unknown if it was calculated with another `--hash`.
+  Ok((size, parse_hex(digest.as_bytes())))
+}
+
+/// The merges that were started but not finished, and those that were planned but not started.
+fn read_outstanding(path: &Path) -> Result<(Vec<UnfinishedMerge>, Vec<PlannedMerge>)> {
+  let content = match std::fs::read(path) {
+    Ok(content) => content,
+    Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Default::default()),
+    Err(e) => Err(e)?,
+  };
+  let Some(content) = content.strip_suffix(b"\0") else {
+    if content.is_empty() {
+      return Ok(Default::default());
+    }
+    bail!("Truncated journal");
+  };
+  let fields = content.split(|byte| *byte == b'\0').collect::<Vec<_>>();
+  if fields.len() % 4 != 0 {
+    bail!("Truncated journal");
+  }
+  let mut unfinished = HashSet::new();
+  let mut order = vec![];
+  let mut planned = vec![];
+  let mut plan_of = HashMap::new();
+  for record in fields.chunks_exact(4) {
+    let [kind, original, redundant, last] = record else {
+      unreachable!()
+    };
+    let (original, redundant) = (
+      bytes_to_path(original.to_vec())?,
+      bytes_to_path(redundant.to_vec())?,
+    );
+    if *kind == PLAN {
+      let (size, digest) = parse_plan(last)?;
+      if let Some(previous) = plan_of.insert(redundant.clone(), planned.len()) {
+        planned[previous] = None;
+      }
+      planned.push(Some((original, redundant, size, digest)));
+      continue;
+    }
+    // A merge that was started is finished or rolled back as an unfinished one instead.
+    if let Some(plan) = plan_of.remove(&redundant) {
+      planned[plan] = None;
+    }
+    let merge = (original, redundant, bytes_to_path(last.to_vec())?);
+    match *kind {
+      BEGIN => {
+        order.push(merge.clone());
+        unfinished.insert(merge);
+      }
+      DONE => {
+        unfinished.remove(&merge);
+      }
+      _ => bail!("Invalid journal record"),
+    }
+  }
+  order.retain(|merge| unfinished.remove(merge));
+  Ok((order, planned.into_iter().flatten().collect()))
+}
+
+/// Reads both files of a merge, or returns `None` if they can no longer be merged.
+async fn read_mergeable(
+  original: &Path,
+  redundant: &Path,
+) -> Result<Option<(FileStorageData, FileStorageData)>> {
+  let (original_data, redundant_data) = (
+    FileStorageData::new(original).await?,
+    FileStorageData::new(redundant).await?,
+  );
+  if original_data.storage_uid != redundant_data.storage_uid
+    || original_data.file_id == redundant_data.file_id
+    || original_data.size != redundant_data.size
+  {
+    return Ok(None);
+  }
+  Ok(Some((original_data, redundant_data)))
+}
+
+/// Returns the saved storage, or `None` if the files are no longer identical.
+async fn resume_merge(
+  original: &Path,
+  redundant: &Path,
+  temporary: &Path,
+) -> Result<Option<Filesize>> {
+  let dry_run = DedupArgs::get().dry_run;
+  if fs::try_exists(temporary).await? {
+    output::print_info(format!(
+      "Removing dangling temporary file {}",
+      temporary.display()
+    ))?;
+    if !dry_run {
+      fs::remove_file(temporary).await?;
+    }
+  }
+  let rollback = temporary_path(redundant, Some("rollback"), temporary_suffix(temporary));
+  // Where the content of the redundant file is, which is still the rollback file in a dry run.
+  let mut current = redundant;
+  if fs::try_exists(&rollback).await? {
+    if fs::try_exists(redundant).await? {
+      output::print_info(format!(
+        "Removing dangling rollback file {}",
+        rollback.display()
+      ))?;
+      if !dry_run {
+        fs::remove_file(&rollback).await?;
+      }
+    } else {
+      output::print_info(format!(
+        "Restoring {} from its rollback file",
+        redundant.display()
+      ))?;
+      if dry_run {
+        current = &rollback;
+      } else {
+        fs::rename(&rollback, redundant).await?;
+      }
+    }
+  }
+  let Some((original_data, redundant_data)) = read_mergeable(original, current).await? else {
+    return Ok(None);
+  };
+  // The files may have changed since they were compared, so they are compared again.
+  let original_hash =
+    calculate_file_hash(original, original_data.size, original_data.storage_uid).await?;
+  let redundant_hash =
+    calculate_file_hash(current, redundant_data.size, redundant_data.storage_uid).await?;
+  if original_hash != redundant_hash
+    || !verify_identical_with_context(
+      original,
+      current,
+      original_data.size,
+      original_data.storage_uid,
+    )
+    .await?
+  {
+    return Ok(None);
+  }
+  if !merge_with_hard_link_with_context(original, redundant, redundant_data.size).await? {
+    return Ok(None);
+  }
+  Ok(Some(redundant_data.size))
+}
+
+/// Performs a merge that was planned but never started. Either file may have been rewritten since,
+/// so they are always compared byte by byte instead of hashed again, and the digest of the plan
+/// only rules out files whose cached digest differs from it without reading them.
+async fn replay_merge(
+  original: &Path,
+  redundant: &Path,
+  size: Filesize,
+  digest: Option<HashDigest>,
+) -> Result<Option<Filesize>> {
+  let Some((original_data, redundant_data)) = read_mergeable(original, redundant).await? else {
+    return Ok(None);
+  };
+  let changed = |file: &FileStorageData| {
+    digest.is_some_and(|digest| cache::get(file).is_some_and(|cached| cached != digest))
+  };
+  if original_data.size != size
+    || changed(&original_data)
+    || changed(&redundant_data)
+    || !storage::compare_files(original, redundant, size, original_data.storage_uid).await?
+  {
+    return Ok(None);
+  }
+  if !merge_with_hard_link_with_context(original, redundant, size).await? {
+    return Ok(None);
+  }
+  Ok(Some(size))
+}
+
+/// Counts a resumed merge, or reports why it wasn't made.
+async fn count_resumed(
+  result: Result<Option<Filesize>>,
+  original: &Path,
+  redundant: &Path,
+  stats: &mut Stats,
+) -> Result<()> {
+  let result = result.with_context(|| {
+    format!(
+      "Could not resume merge of {} to {}",
+      redundant.display(),
+      original.display()
+    )
+  });
+  match result {
+    Ok(Some(saved_storage)) => {
+      stats.links_created += 1;
+      stats.saved_storage += saved_storage;
+      // The content is only left in the original.
+      stats.saved_allocated += storage::allocated_size(original)
+        .await
+        .unwrap_or(saved_storage);
+    }
+    Ok(None) => output::print_info(format!(
+      "Skipping resumed merge of {} to {}, the files are no longer identical",
+      redundant.display(),
+      original.display()
+    ))?,
+    Err(e) if DedupArgs::get().ignore_hash_errors => output::print_error(e),
+    Err(e) => return Err(e),
+  }
+  Ok(())
+}
+
+/// Cleans up and finishes the merges that a previous run left half done, and then makes those it
+/// planned but never started.
+pub async fn resume(stats: &mut Stats) -> Result<()> {
+  let args = DedupArgs::get();
+  let Some(ref path) = args.journal else {
+    return Ok(());
+  };
+  let (unfinished, planned) =
+    read_outstanding(path).with_context(|| format!("Could not read journal {}", path.display()))?;
+  for (original, redundant, temporary) in unfinished {
+    let result = resume_merge(&original, &redundant, &temporary).await;
+    count_resumed(result, &original, &redundant, stats).await?;
+  }
+  for (original, redundant, size, digest) in planned {
+    let result = replay_merge(&original, &redundant, size, digest).await;
+    count_resumed(result, &original, &redundant, stats).await?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::HASH_LEN;
+
+  /// An empty directory for the test `name`.
+  fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+      "hard-link-dedup-journal-{name}-{}",
+      std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  fn record(kind: &[u8], fields: [&[u8]; 3]) -> Vec<u8> {
+    let mut record = vec![];
+    for field in std::iter::once(kind).chain(fields) {
+      record.extend_from_slice(field);
+      record.push(b'\0');
+    }
+    record
+  }
+
+  #[test]
+  fn outstanding_merges_are_read_back() {
+    let dir = test_dir("outstanding");
+    let digest = format!("1024:{}", "ab".repeat(HASH_LEN));
+    let content = [
+      record(PLAN, [b"/o", b"/planned", digest.as_bytes()]),
+      record(PLAN, [b"/o", b"/started", digest.as_bytes()]),
+      record(BEGIN, [b"/o", b"/started", b"/started.tmp"]),
+      record(PLAN, [b"/o", b"/finished", digest.as_bytes()]),
+      record(BEGIN, [b"/o", b"/finished", b"/finished.tmp"]),
+      record(DONE, [b"/o", b"/finished", b"/finished.tmp"]),
+    ]
+    .concat();
+    let journal = dir.join("journal");
+    std::fs::write(&journal, content).unwrap();
+    let (unfinished, planned) = read_outstanding(&journal).unwrap();
+    assert_eq!(
+      unfinished,
+      [("/o".into(), "/started".into(), "/started.tmp".into())]
+    );
+    assert_eq!(
+      planned,
+      [("/o".into(), "/planned".into(), 1024, Some([0xab; HASH_LEN]))]
+    );
+    std::fs::write(&journal, &record(BEGIN, [b"/o", b"/r", b"/t"])[1..]).unwrap();
+    assert!(read_outstanding(&journal).is_err());
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[tokio::test]
+  async fn planned_merges_are_compared_before_they_are_replayed() {
+    let dir = test_dir("replay");
+    let (original, identical, changed) = (dir.join("o"), dir.join("i"), dir.join("c"));
+    std::fs::write(&original, b"content").unwrap();
+    std::fs::write(&identical, b"content").unwrap();
+    // Rewritten after the plan, with the same size.
+    std::fs::write(&changed, b"CONTENT").unwrap();
+    let digest = Some([0; HASH_LEN]);
+    assert_eq!(
+      replay_merge(&original, &changed, 7, digest).await.unwrap(),
+      None
+    );
+    assert_eq!(std::fs::read(&changed).unwrap(), b"CONTENT");
+    assert_eq!(
+      replay_merge(&original, &identical, 8, digest)
+        .await
+        .unwrap(),
+      None
+    );
+    assert_eq!(
+      replay_merge(&original, &identical, 7, digest)
+        .await
+        .unwrap(),
+      Some(7)
+    );
+    let (original, identical) = (
+      FileStorageData::new(&original).await.unwrap(),
+      FileStorageData::new(&identical).await.unwrap(),
+    );
+    assert_eq!(original.file_id, identical.file_id);
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
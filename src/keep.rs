@@ -0,0 +1,292 @@
+use std::{
+  cmp::Reverse,
+  env,
+  path::{Path, PathBuf},
+  sync::{Arc, OnceLock},
+  time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use tokio::fs;
+
+use crate::{
+  os::{self, read_link_metadata, FileLinkBackend, StorageUid},
+  DedupArgs, FileId, Filesize, HashDigest,
+};
+
+/// Which file of a duplicate group becomes the original that the others are merged into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Keep {
+  /// The first file that was hashed, which lets files be merged as soon as they are hashed.
+  First,
+  /// The file with the oldest modification time.
+  Oldest,
+  /// The file with the most hard links, so that the fewest paths have to be replaced.
+  MostLinked,
+}
+
+impl Keep {
+  pub fn get() -> Self {
+    DedupArgs::get().keep
+  }
+
+  /// Whether merges wait until every duplicate is known, so that the original can be chosen among
+  /// all of them.
+  pub fn is_deferred() -> bool {
+    let dirs = get_dirs();
+    Keep::get() != Keep::First
+      || !dirs.preferred.is_empty()
+      || !dirs.protected.is_empty()
+      || !dirs.references.is_empty()
+      || DedupArgs::get().uses_tui()
+      || DedupArgs::get().min_savings.is_some()
+  }
+}
+
+struct Dirs {
+  current_dir: PathBuf,
+  /// The absolute and canonical `--prefer-path` directories, most preferred first.
+  preferred: Vec<[PathBuf; 2]>,
+  /// The absolute and canonical `--protect-path` directories.
+  protected: Vec<[PathBuf; 2]>,
+  /// The canonical `--reference` directories, and the other scanned paths.
+  references: Vec<PathBuf>,
+  paths: Vec<PathBuf>,
+}
+
+static DIRS: OnceLock<Dirs> = OnceLock::new();
+
+#[cfg(not(test))]
+fn get_dirs() -> &'static Dirs {
+  DIRS
+    .get()
+    .expect("Preferred paths are initialized at startup")
+}
+
+/// Unit tests use the directories of the default arguments.
+#[cfg(test)]
+fn get_dirs() -> &'static Dirs {
+  DIRS.get_or_init(|| read_dirs().expect("The current directory can be read"))
+}
+
+pub fn init() -> Result<()> {
+  let _ = DIRS.set(read_dirs()?);
+  Ok(())
+}
+
+fn read_dirs() -> Result<Dirs> {
+  let args = DedupArgs::get();
+  let current_dir = env::current_dir().context("Could not read the current directory")?;
+  // The scanned paths are canonical, while listed files may be found through other paths.
+  let absolute = |dirs: &[PathBuf]| {
+    dirs
+      .iter()
+      .map(|dir| {
+        let dir = current_dir.join(dir);
+        let canonical = std::fs::canonicalize(&dir).unwrap_or_else(|_| dir.clone());
+        Ok([os::extended_path(&dir)?, os::extended_path(&canonical)?])
+      })
+      .collect::<std::io::Result<Vec<_>>>()
+  };
+  let (preferred, protected) = (absolute(&args.prefer_path)?, absolute(&args.protect_path)?);
+  let references = args
+    .reference
+    .iter()
+    .map(|dir| os::extended_path(dir))
+    .collect::<std::io::Result<Vec<_>>>()?;
+  let paths = args
+    .path
+    .iter()
+    .filter(|path| !args.reference.contains(path))
+    .map(|dir| os::extended_path(dir))
+    .collect::<std::io::Result<Vec<_>>>()?;
+  Ok(Dirs {
+    current_dir,
+    preferred,
+    protected,
+    references,
+    paths,
+  })
+}
+
+fn is_below(path: &Path, [absolute, canonical]: &[PathBuf; 2]) -> bool {
+  path.starts_with(absolute) || path.starts_with(canonical)
+}
+
+/// Whether `path` is in a `--reference` directory, but not in a scanned path below it.
+fn is_in_reference(dirs: &Dirs, path: &Path) -> bool {
+  let deepest = |dirs: &[PathBuf]| {
+    dirs
+      .iter()
+      .filter(|dir| path.starts_with(dir))
+      .map(|dir| dir.components().count())
+      .max()
+  };
+  match (deepest(&dirs.references), deepest(&dirs.paths)) {
+    (Some(reference), Some(path)) => reference >= path,
+    (reference, None) => reference.is_some(),
+    (None, Some(_)) => false,
+  }
+}
+
+/// Whether `path` is in a `--protect-path` or `--reference` directory, and must never be replaced
+/// or modified.
+pub fn is_protected(path: &Path) -> bool {
+  let dirs = get_dirs();
+  let path = dirs.current_dir.join(path);
+  dirs.protected.iter().any(|dir| is_below(&path, dir)) || is_in_reference(dirs, &path)
+}
+
+/// The index of the first preferred directory containing any path of `candidate`, or the number
+/// of preferred directories if there is none.
+fn preference(candidate: &Candidate) -> usize {
+  let dirs = get_dirs();
+  candidate
+    .paths
+    .iter()
+    .filter_map(|path| {
+      let path = dirs.current_dir.join(path);
+      dirs.preferred.iter().position(|dir| is_below(&path, dir))
+    })
+    .min()
+    .unwrap_or(dirs.preferred.len())
+}
+
+/// A file that may become the original of its group, with every path it was found through.
+#[derive(Debug)]
+pub struct Candidate {
+  pub id: FileId,
+  pub paths: Vec<Arc<Path>>,
+}
+
+impl Candidate {
+  /// The path to merge other files into, which is a protected path if there is one.
+  pub fn path(&self) -> &Arc<Path> {
+    self
+      .paths
+      .iter()
+      .find(|path| is_protected(path))
+      .unwrap_or(&self.paths[0])
+  }
+
+  /// Whether any path of the file is protected, so that it can only be an original.
+  pub fn is_protected(&self) -> bool {
+    self.paths.iter().any(|path| is_protected(path))
+  }
+}
+
+/// The candidates of a duplicate group whose merges were deferred.
+#[derive(Debug)]
+pub struct Group {
+  pub storage_uid: StorageUid,
+  pub size: Filesize,
+  pub digest: HashDigest,
+  pub candidates: Vec<Candidate>,
+  /// The index of the candidate that the others are merged into.
+  pub kept: usize,
+  /// Whether the group is merged, or skipped as chosen in `--tui`.
+  pub apply: bool,
+}
+
+impl Group {
+  /// The space that merging the group saves.
+  pub fn wasted(&self) -> Filesize {
+    self.size * (self.candidates.len() as Filesize - 1)
+  }
+}
+
+async fn modified(candidate: &Candidate) -> Result<SystemTime> {
+  let path = candidate.path();
+  fs::metadata(path)
+    .await
+    .and_then(|metadata| metadata.modified())
+    .with_context(|| format!("Could not read modification time of {}", path.display()))
+}
+
+async fn link_count(candidate: &Candidate) -> Result<u64> {
+  let path = candidate.path();
+  Ok(
+    read_link_metadata(path)
+      .await
+      .with_context(|| format!("Could not read metadata for {}", path.display()))?
+      .get_link_count(),
+  )
+}
+
+/// The index of the first of the smallest keys.
+fn first_min<K: Ord>(keys: Vec<K>) -> usize {
+  keys
+    .into_iter()
+    .enumerate()
+    .min_by(|(_, a), (_, b)| a.cmp(b))
+    .map_or(0, |(index, _)| index)
+}
+
+/// The index of the candidate that should be kept. Protected candidates win, then candidates in
+/// preferred directories, and ties after `--keep` are won by the candidate found first.
+pub async fn choose(candidates: &[Candidate]) -> Result<usize> {
+  let mut keys = vec![];
+  for candidate in candidates {
+    let rank = (!candidate.is_protected(), preference(candidate));
+    keys.push(match Keep::get() {
+      Keep::First => (rank, None, None),
+      Keep::Oldest => (rank, Some(modified(candidate).await?), None),
+      Keep::MostLinked => (rank, None, Some(Reverse(link_count(candidate).await?))),
+    });
+  }
+  Ok(first_min(keys))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn first_of_the_smallest_keys_wins() {
+    assert_eq!(first_min(vec![3, 1, 2, 1]), 1);
+    assert_eq!(first_min(vec![Reverse(1), Reverse(3), Reverse(3)]), 1);
+    assert_eq!(first_min(Vec::<u8>::new()), 0);
+  }
+
+  /// The keys of `choose`: whether the candidate isn't protected, the index of its preferred
+  /// directory and its modification time for `--keep oldest`.
+  #[test]
+  fn protected_and_preferred_candidates_win_over_older_ones() {
+    let (old, new) = (Some(SystemTime::UNIX_EPOCH), Some(SystemTime::now()));
+    let keys = vec![((true, 1), old), ((true, 0), new), ((false, 1), new)];
+    assert_eq!(first_min(keys[..1].to_vec()), 0);
+    assert_eq!(first_min(keys[..2].to_vec()), 1);
+    assert_eq!(first_min(keys), 2);
+  }
+
+  #[test]
+  fn paths_below_a_reference_are_only_protected_outside_scanned_paths() {
+    let dirs = Dirs {
+      current_dir: "/".into(),
+      preferred: vec![],
+      protected: vec![],
+      references: vec!["/ref".into(), "/data/ref".into()],
+      paths: vec!["/data".into(), "/ref/scanned".into()],
+    };
+    assert!(is_in_reference(&dirs, Path::new("/ref/file")));
+    assert!(!is_in_reference(&dirs, Path::new("/ref/scanned/file")));
+    assert!(!is_in_reference(&dirs, Path::new("/data/file")));
+    assert!(is_in_reference(&dirs, Path::new("/data/ref/file")));
+    assert!(!is_in_reference(&dirs, Path::new("/other/file")));
+  }
+
+  #[tokio::test]
+  async fn first_candidate_is_kept_by_default() {
+    let candidates = ["b", "a", "c"]
+      .into_iter()
+      .enumerate()
+      .map(|(id, name)| Candidate {
+        id: id as FileId,
+        paths: vec![Path::new(name).into()],
+      })
+      .collect::<Vec<_>>();
+    assert!(!candidates[1].is_protected());
+    assert_eq!(choose(&candidates).await.unwrap(), 0);
+  }
+}
@@ -0,0 +1,2265 @@
+//! Finds duplicate files and replaces them with hard links, or with one of the other
+//! [`Strategy`]s.
+//!
+//! [`DedupEngine`] is configured with the same options as the command line, through a
+//! [`DedupEngineBuilder`] or from a parsed [`DedupArgs`], and returns a [`Report`] of what was
+//! merged.
+//!
+//! ```no_run
+//! # async fn dedup() -> anyhow::Result<()> {
+//! let engine = hardlinkdedup::DedupEngine::builder()
+//!   .path("/srv/backups")
+//!   .exclude_glob("*.tmp")?
+//!   .dry_run(true)
+//!   .build()?;
+//! let report = engine.run().await?;
+//! println!("{} bytes can be saved", report.stats.saved_storage);
+//! # Ok(())
+//! # }
+//! ```
+#![cfg_attr(all(windows, not(feature = "stable")), feature(windows_by_handle))]
+use anyhow::{bail, Context, Result};
+use clap::{ArgAction, Parser, Subcommand};
+use globset::Glob;
+use regex::Regex;
+use std::{
+  collections::{
+    hash_map::{Entry, RandomState},
+    HashMap, HashSet,
+  },
+  ffi::{OsStr, OsString},
+  hash::{BuildHasher, Hasher},
+  path::{Path, PathBuf},
+  sync::{Arc, OnceLock},
+  time::{Instant, SystemTime},
+};
+use tokio::{
+  fs,
+  io::AsyncReadExt,
+  sync::{Mutex, Semaphore},
+  task::JoinSet,
+};
+
+mod acl;
+mod apply;
+mod backup;
+mod cache;
+mod config;
+mod control;
+mod cross_device;
+mod engine;
+mod fd_limit;
+mod filter;
+mod hash;
+mod input;
+mod interactive;
+mod journal;
+mod keep;
+mod lock;
+mod log_file;
+mod manifest;
+mod observer;
+mod os;
+mod output;
+mod priority;
+mod progress;
+mod report;
+mod storage;
+mod strategy;
+mod throttle;
+mod timestamps;
+#[cfg(feature = "tui")]
+mod tui;
+mod undo;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod uring;
+mod watch;
+mod xattrs;
+pub use acl::AclPolicy;
+use control::Request;
+pub use engine::{DedupEngine, DedupEngineBuilder};
+use filter::IgnoreStack;
+pub use hash::HashAlgorithm;
+use keep::Candidate;
+pub use keep::Keep;
+pub use observer::{Event, Observer};
+use os::{read_link_metadata, FileId, FileLinkBackend, StorageUid};
+pub use output::OutputFormat;
+pub use report::{DirSavings, DuplicateGroup, DuplicateGroups, Report};
+use serde::Serialize;
+pub use storage::Prefilter;
+use storage::{
+  calculate_file_hash_with_context, calculate_prefilter_hash_with_context, FileStorageData,
+  StorageKey,
+};
+pub use strategy::Strategy;
+pub use timestamps::TimestampPolicy;
+use timestamps::Timestamps;
+pub use xattrs::XattrPolicy;
+
+/// The length of the longest digest of the supported hash algorithms. Shorter digests are padded
+/// with zeroes.
+pub const HASH_LEN: usize = 32;
+pub type HashDigest = [u8; HASH_LEN];
+pub type Filesize = u64;
+type FileUid = (StorageUid, FileId);
+
+/// Parses a size such as `100MiB`, `2G` or `500KB`. Suffixes without an `i` are binary except
+/// `KB`, `MB`, `GB` and `TB`, and a plain number is in bytes.
+pub(crate) fn parse_size(size: &str) -> Result<Filesize> {
+  let split = size
+    .find(|c: char| !c.is_ascii_digit())
+    .unwrap_or(size.len());
+  let (number, unit) = size.split_at(split);
+  let number: Filesize = number
+    .parse()
+    .with_context(|| format!("Invalid size {size:?}"))?;
+  let multiplier: Filesize = match unit.trim().to_ascii_lowercase().as_str() {
+    "" | "b" => 1,
+    "k" | "kib" => 1 << 10,
+    "m" | "mib" => 1 << 20,
+    "g" | "gib" => 1 << 30,
+    "t" | "tib" => 1 << 40,
+    "kb" => 1_000,
+    "mb" => 1_000_000,
+    "gb" => 1_000_000_000,
+    "tb" => 1_000_000_000_000,
+    _ => bail!("Unknown unit {unit:?}, expected e.g. KiB, MiB, GiB or TiB"),
+  };
+  number
+    .checked_mul(multiplier)
+    .with_context(|| format!("Size {size:?} is too large"))
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DedupCommand {
+  /// Reverse the merges recorded in an undo log, by copying the content of each original back to
+  /// the redundant path, or giving reflinked and deduped files storage of their own, and restoring
+  /// the modes of both files.
+  Undo {
+    /// Undo log written by `--undo-log`.
+    #[arg(value_hint = clap::ValueHint::FilePath)]
+    undo_log: PathBuf,
+  },
+  /// Merge duplicate groups found by another tool, in the output format of `fdupes` and `jdupes`:
+  /// one path per line, with a blank line after each group. The first path of each group is
+  /// kept, and the others are only merged into it if they still have its size and hash.
+  ApplyGroups {
+    /// The duplicate groups, or `-` for stdin.
+    #[arg(default_value = "-", value_hint = clap::ValueHint::FilePath)]
+    groups: PathBuf,
+
+    /// Only check that the files still have the same size, instead of hashing them.
+    #[arg(long, action = ArgAction::SetTrue)]
+    size_only: bool,
+  },
+}
+
+#[derive(Debug, Parser)]
+#[command(
+  author,
+  version,
+  about,
+  long_about = None,
+  subcommand_negates_reqs = true,
+  args_override_self = true
+)]
+pub struct DedupArgs {
+  #[command(subcommand)]
+  pub command: Option<DedupCommand>,
+
+  /// Config file with defaults for every option, which are overridden by the command line. Lists
+  /// are extended by the command line rather than replaced. Defaults to
+  /// `~/.config/hardlinkdedup/config.toml` (`%APPDATA%\hardlinkdedup\config.toml` on Windows), if
+  /// it exists. Every `[[rule]]` table has a `path` that it makes `prefer` or `protect`.
+  #[arg(long, value_hint = clap::ValueHint::FilePath)]
+  pub config: Option<PathBuf>,
+
+  /// Regex pattern files must match to be included in the dedup. May be given multiple times, to
+  /// include files matching any of them.
+  #[arg(short, long)]
+  pub pattern: Vec<Regex>,
+
+  /// Regex pattern for file and directory names that should be excluded from the dedup.
+  #[arg(short, long)]
+  pub exclude: Option<Regex>,
+
+  /// Only include files with one of these extensions, e.g. `iso,qcow2,mkv`. Other files are
+  /// skipped without reading their metadata.
+  #[arg(long, value_delimiter = ',')]
+  pub ext: Vec<String>,
+
+  /// Glob pattern files must match to be included in the dedup (e.g. `**/*.iso`). May be given
+  /// multiple times.
+  #[arg(long, value_parser = filter::parse_glob)]
+  pub glob: Vec<Glob>,
+
+  /// Glob pattern for files and directories that should be excluded from the dedup. May be given
+  /// multiple times.
+  #[arg(long, value_parser = filter::parse_glob)]
+  pub exclude_glob: Vec<Glob>,
+
+  /// Glob pattern for directories that should not be descended into. May be given multiple times.
+  #[arg(long, value_parser = filter::parse_glob)]
+  pub exclude_dir: Vec<Glob>,
+
+  /// Scan directories tagged as caches by a `CACHEDIR.TAG` file as well. They are skipped by
+  /// default, since caches are full of transient copies that shouldn't be made readonly or linked.
+  #[arg(long = "scan-caches", action = ArgAction::SetFalse)]
+  pub skip_caches: bool,
+
+  /// Skip dotfiles and directories, and on Windows also those with the hidden or system attribute.
+  /// Such files are mostly configuration, which is rarely worth linking.
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub skip_hidden: bool,
+
+  /// Match `--pattern`, `--exclude` and the globs regardless of case.
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub ignore_case: bool,
+
+  /// Match `--pattern` and `--exclude` against the full path instead of just the file name, e.g.
+  /// `.*/photos/\d{4}/.*\.jpg`. The paths are absolute, since the given paths are canonicalized,
+  /// with symlinks in them resolved.
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub match_full_path: bool,
+
+  /// Don't actually do anything, just print what would have been done.
+  #[arg(short, long, action = ArgAction::SetTrue)]
+  pub dry_run: bool,
+
+  /// Don't hash or merge anything, just report how much could at most be saved going by the sizes
+  /// of the files alone. A fast preview of huge trees.
+  #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["watch", "every"])]
+  pub estimate: bool,
+
+  /// Don't merge anything, and list every duplicate group with the storage it wastes once the run
+  /// is done, instead of a line per merge.
+  #[arg(
+    long,
+    action = ArgAction::SetTrue,
+    conflicts_with_all = ["estimate", "watch", "every", "control_socket"]
+  )]
+  pub report: bool,
+
+  /// Ignore files smaller than this (in KiB).
+  #[arg(long, default_value = "1024")]
+  pub min_file_size: Filesize,
+
+  /// Ignore files larger than this (in KiB).
+  #[arg(long)]
+  pub max_file_size: Option<Filesize>,
+
+  /// Ignore files that already have more than this many hard links, such as those in snapshot
+  /// trees.
+  #[arg(long)]
+  pub max_existing_links: Option<u64>,
+
+  /// Ignore sparse (or compressed) files with less than half of their size allocated on disk,
+  /// since merging them saves less than their size suggests while the holes still have to be read.
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub skip_sparse: bool,
+
+  /// Ignore files modified more recently than this, e.g. `30d`, so that files that are still
+  /// written (such as logs and databases) are never linked.
+  #[arg(long)]
+  pub older_than: Option<humantime::Duration>,
+
+  /// Report but don't merge duplicate groups that would save less than this in total, e.g.
+  /// `100MiB`. Merges wait until every duplicate is known.
+  #[arg(long, value_parser = parse_size)]
+  pub min_savings: Option<Filesize>,
+
+  /// Only merge files that are below different paths, e.g. to link `backup-2024/` to
+  /// `backup-2023/` but never two files within either.
+  #[arg(long, action = ArgAction::SetTrue, requires = "path")]
+  pub across_roots_only: bool,
+
+  /// Only merge files with the same file name, e.g. for mirrored photo libraries. Files are only
+  /// hashed when another file has the same name and size.
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub same_name: bool,
+
+  /// Only merge files with the same modification time, e.g. for trees copied by `rsync -t`.
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub same_mtime: bool,
+
+  /// Only deduplicate files owned by this user.
+  #[cfg(unix)]
+  #[arg(long, value_parser = filter::parse_user, conflicts_with = "uid")]
+  pub owner: Option<u32>,
+
+  /// Only deduplicate files owned by this user ID.
+  #[cfg(unix)]
+  #[arg(long)]
+  pub uid: Option<u32>,
+
+  /// File buffer size per file (in KiB).
+  #[arg(short, long, default_value = "2048")]
+  pub buffer_size: usize,
+
+  /// The memory used by the read buffers of every hashing thread combined, e.g. `512MiB`. The
+  /// buffers are sized to fit, instead of by --buffer-size.
+  #[arg(long, value_parser = parse_size, conflicts_with = "buffer_size")]
+  pub max_memory: Option<Filesize>,
+
+  /// Max threads allowed to hash files at the same time. This in combination with --max-memory or
+  /// limiting the buffer size can be used to limit memory usage.
+  #[arg(
+    short,
+    long,
+    default_value = "10",
+    value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+  )]
+  pub max_hash_threads: usize,
+
+  /// Max threads allowed to hash files on the same storage device at the same time, within
+  /// --max-hash-threads. A single spinning disk is usually fastest with 1, while an SSD array can
+  /// keep up with many.
+  #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+  pub per_device_threads: Option<usize>,
+
+  /// Max threads allowed to hash files on the same spinning disk at the same time, instead of
+  /// --per-device-threads. Concurrent reads make the disk seek back and forth between the files.
+  /// Spinning disks are only detected on Linux.
+  #[arg(
+    long,
+    default_value = "1",
+    value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+  )]
+  pub hdd_threads: usize,
+
+  /// Max directories allowed to be read at the same time, to avoid running out of file handles on
+  /// wide trees.
+  #[arg(
+    long,
+    default_value = "64",
+    value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+  )]
+  pub max_scan_tasks: usize,
+
+  /// The extension to apply to the hard link before it's renamed to the original filename. A
+  /// random suffix is appended to it, e.g. `file.bin.hard_link.k3x9q0`.
+  #[arg(short, long, default_value = "hard_link")]
+  pub temporary_extension: OsString,
+
+  /// How redundant files are replaced.
+  #[arg(long, value_enum, default_value_t = Strategy::HardLink)]
+  pub strategy: Strategy,
+
+  /// Which file of every group of duplicates the others are merged into.
+  #[arg(long, value_enum, default_value_t = Keep::First)]
+  pub keep: Keep,
+
+  /// Prefer files in this directory as originals, over files in directories given after it and
+  /// files outside of them. Can be given multiple times.
+  #[arg(long, value_hint = clap::ValueHint::DirPath)]
+  pub prefer_path: Vec<PathBuf>,
+
+  /// Only merge files with the same owner, group and mode, since the merged files share them.
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub require_same_metadata: bool,
+
+  /// How extended attributes are handled when files are merged. Not supported on Windows.
+  #[arg(long, value_enum, default_value_t = XattrPolicy::Ignore)]
+  pub xattr_policy: XattrPolicy,
+
+  /// How files with POSIX ACLs (on Linux) or explicit NTFS ACL entries (on Windows) are handled.
+  #[arg(long, value_enum, default_value_t = AclPolicy::Ignore)]
+  pub acl_policy: AclPolicy,
+
+  /// Never replace or change the permissions of files in this directory, but let them be the
+  /// originals of files outside of it. Can be given multiple times.
+  #[arg(long, value_hint = clap::ValueHint::DirPath)]
+  pub protect_path: Vec<PathBuf>,
+
+  /// Also scan this directory, and link the duplicates found in the paths to the copies in it,
+  /// without ever modifying it. E.g. to deduplicate a new backup against a known good copy. Can be
+  /// given multiple times.
+  #[arg(long, value_hint = clap::ValueHint::DirPath)]
+  pub reference: Vec<PathBuf>,
+
+  /// Make the links of `--strategy symlink` relative to the directory of the redundant file.
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub relative_symlinks: bool,
+
+  /// Which modification time the redundant path gets after it is merged. Hard linked files share
+  /// it with the original, so it's left unchanged when the original is protected.
+  #[arg(long, value_enum, default_value_t = TimestampPolicy::Original)]
+  pub preserve_timestamps: TimestampPolicy,
+
+  /// By default, all hardlinked files will be set readonly (to avoid confusing file interactions).
+  /// This flags makes it so that this program doesn't affect file permissions beyond the effect of
+  /// hard linking the files.
+  #[arg(short, long, action = ArgAction::SetTrue)]
+  pub not_readonly: bool,
+
+  /// Don't ask for confirmation before `--strategy delete` removes redundant files.
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub force: bool,
+
+  /// Ask before each merge, showing the paths and their size. Answering `all` merges the rest
+  /// without asking, and `quit` stops the run.
+  #[arg(short, long, action = ArgAction::SetTrue)]
+  pub interactive: bool,
+
+  /// Browse the duplicate groups in a terminal UI once they are all found, sorted by wasted space,
+  /// to choose the file to keep and which groups to merge.
+  #[cfg(feature = "tui")]
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub tui: bool,
+
+  /// Keep going even if not all file's metadata can be read.
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub ignore_scan_errors: bool,
+
+  /// Keep going even if not all files can be read.
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub ignore_hash_errors: bool,
+
+  /// The algorithm used to hash the files.
+  #[arg(long, value_enum, default_value_t = HashAlgorithm::Blake3)]
+  pub hash: HashAlgorithm,
+
+  /// Hash files of at least this many MiB with every core instead of one, by memory mapping them.
+  /// Only supported by blake3.
+  #[arg(long)]
+  pub parallel_hash_size: Option<Filesize>,
+
+  /// Hash files by memory mapping them instead of reading them into buffers. Files that can't be
+  /// mapped are read as usual.
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub mmap: bool,
+
+  /// Read files through io_uring when hashing them.
+  #[cfg(all(target_os = "linux", feature = "io-uring"))]
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub io_uring: bool,
+
+  /// Run in the background, with idle I/O scheduling and the lowest CPU priority.
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub idle_io: bool,
+
+  /// Limit the combined reads of the hashing threads to this many MiB per second.
+  #[arg(long, value_parser = throttle::parse_rate)]
+  pub max_read_mbps: Option<f64>,
+
+  /// Evict files from the page cache after reading them, so that a background run doesn't push out
+  /// the cached data of other programs.
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub no_cache_pollution: bool,
+
+  /// Compare files byte by byte before merging them, instead of trusting equal hashes.
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub paranoid: bool,
+
+  /// The size of the blocks compared by the prefilters (in KiB, 64 by default). On its own this
+  /// enables the prefix prefilter.
+  #[arg(long)]
+  pub prefix_size: Option<usize>,
+
+  /// Compare these blocks of files with equal sizes, in order, and hash the full files only if the
+  /// blocks are equal too. This avoids reading most of files that only have sizes in common.
+  #[arg(long, value_enum, value_delimiter = ',')]
+  pub prefilter: Vec<Prefilter>,
+
+  /// Print debug information about file IDs.
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub debug: bool,
+
+  /// Don't descend more than this many directory levels below the given paths.
+  #[arg(long)]
+  pub max_depth: Option<usize>,
+
+  /// Follow symbolic links to files and directories instead of skipping them. Symlinked files are
+  /// deduplicated through their resolved path.
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub follow_symlinks: bool,
+
+  /// Also honor `.gitignore` files. `.dedupignore` files (using the same syntax) are always
+  /// honored.
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub gitignore: bool,
+
+  /// Read a newline separated list of files to deduplicate from this file (or `-` for stdin)
+  /// instead of, or in addition to, scanning directories.
+  #[arg(long, value_hint = clap::ValueHint::FilePath)]
+  pub files_from: Option<PathBuf>,
+
+  /// Read `--files-from` lists as NUL separated paths, and print merges as NUL terminated
+  /// original and redundant path pairs. Other output is moved to stderr.
+  #[arg(short = '0', long, action = ArgAction::SetTrue)]
+  pub null: bool,
+
+  /// The output format. Non-text formats are printed once the run is done.
+  #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+  pub format: OutputFormat,
+
+  /// Show a progress display on stderr while scanning and hashing.
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub progress: bool,
+
+  /// Print more details, such as skipped files and how long each file took to hash.
+  #[arg(short, long, action = ArgAction::Count, conflicts_with = "quiet")]
+  pub verbose: u8,
+
+  /// Also find duplicates on different storage devices, which can't be linked, and list them with
+  /// the storage they waste after the run. Files whose size is found on another device are hashed
+  /// for this.
+  #[arg(
+    long,
+    action = ArgAction::SetTrue,
+    conflicts_with_all = ["estimate", "watch", "every", "control_socket"]
+  )]
+  pub cross_device: bool,
+
+  /// Treat the storage of A and B as the same, where each is a path on it or its storage ID. For
+  /// bind mounts or volumes mounted more than once, which may report different IDs. May be given
+  /// multiple times. Linux refuses to link across mount points even of the same file system, so
+  /// this mostly helps where the IDs differ for another reason.
+  #[arg(long, num_args = 2, value_names = ["A", "B"])]
+  pub same_storage: Vec<PathBuf>,
+
+  /// Show sizes in powers of 1000, e.g. MB.
+  #[arg(long, action = ArgAction::SetTrue, overrides_with = "binary")]
+  pub si: bool,
+
+  /// Show sizes in powers of 1024, e.g. MiB. This is the default.
+  #[arg(long, action = ArgAction::SetTrue, overrides_with = "si")]
+  pub binary: bool,
+
+  /// Don't print every merge, only the summary.
+  #[arg(short, long, action = ArgAction::SetTrue)]
+  pub quiet: bool,
+
+  /// Write a JSON summary of the run to this file (`--summary-json=FILE`), or to stdout if no file
+  /// (or `-`) is given. The summary is then all that's printed on stdout, like with `--format`,
+  /// which it can't be combined with.
+  #[arg(
+    long,
+    num_args = 0..=1,
+    require_equals = true,
+    default_missing_value = "-",
+    value_hint = clap::ValueHint::FilePath
+  )]
+  pub summary_json: Option<PathBuf>,
+
+  /// Append all merges, errors and summaries to this file, with timestamps.
+  #[arg(long, value_hint = clap::ValueHint::FilePath)]
+  pub log_file: Option<PathBuf>,
+
+  /// Print how much storage was saved in each path argument, or in each top-level directory below
+  /// it.
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub breakdown: bool,
+
+  /// List the N duplicate groups wasting the most storage after the run.
+  #[arg(long, value_name = "N")]
+  pub top: Option<usize>,
+
+  /// Keep file hashes in this file between runs, so that unmodified files don't have to be hashed
+  /// again. The hashes of files that a complete run didn't come across are dropped.
+  #[arg(long, value_hint = clap::ValueHint::FilePath)]
+  pub hash_cache: Option<PathBuf>,
+
+  /// Record every merge in this journal when it's planned and while it's performed, so that an
+  /// interrupted run can be cleaned up and finished with `--resume`. The journal is emptied after
+  /// a successful run that isn't a dry run.
+  #[arg(long, value_hint = clap::ValueHint::FilePath)]
+  pub journal: Option<PathBuf>,
+
+  /// Before scanning (if any paths are given), remove the temporary files of merges the journal
+  /// shows were interrupted and retry them if the files are still identical. Merges that were
+  /// planned but never started are then made without scanning or hashing, after comparing the
+  /// files.
+  #[arg(long, action = ArgAction::SetTrue, requires = "journal")]
+  pub resume: bool,
+
+  /// Keep running after the first pass, and deduplicate files as they are created or modified
+  /// below the paths.
+  #[arg(long, action = ArgAction::SetTrue, requires = "path")]
+  pub watch: bool,
+
+  /// Keep running after the first pass, taking commands on this Unix socket (or named pipe on
+  /// Windows, e.g. `\\.\pipe\hardlinkdedup`). Every line is one of `status`, `pause`, `resume`
+  /// or `rescan PATH`, and is answered with one line.
+  #[arg(long, value_hint = clap::ValueHint::FilePath)]
+  pub control_socket: Option<PathBuf>,
+
+  /// Keep running, and scan the paths again this long after each pass is done, e.g. `24h`. Only
+  /// files that are new or modified since they were last seen are hashed again.
+  #[arg(long, requires = "path")]
+  pub every: Option<humantime::Duration>,
+
+  /// Stop after running this long, e.g. `2h`, once the merges in progress are done. The summary
+  /// covers what was done until then.
+  #[arg(long)]
+  pub max_runtime: Option<humantime::Duration>,
+
+  /// Stop after replacing this many redundant files, e.g. to work through a large archive a part
+  /// at a time.
+  #[arg(long)]
+  pub max_files: Option<usize>,
+
+  /// Stop after saving this much storage, e.g. `50GiB`.
+  #[arg(long, value_parser = parse_size)]
+  pub max_savings: Option<Filesize>,
+
+  /// Remove temporary files left behind by interrupted runs when they are found, if their content
+  /// is linked from another path and it has the random suffix of a merge that isn't in progress,
+  /// e.g. `file.bin.hard_link.k3x9q0`. Other temporary files are reported and kept.
+  #[arg(long, action = ArgAction::SetTrue)]
+  pub cleanup_temp: bool,
+
+  /// Use the hashes in this `b3sum`, `xxh128sum` or `sha256sum` output (matching `--hash`) instead
+  /// of hashing the listed files, unless their size or modification time differs from the `.stat`
+  /// file next to it. Files it has no `.stat` line for are hashed if they were modified after it
+  /// was written.
+  #[arg(long, value_hint = clap::ValueHint::FilePath)]
+  pub hashes_from: Option<PathBuf>,
+
+  /// Write the hash of every hashed file to this file, in the format of `b3sum`, `xxh128sum` or
+  /// `sha256sum` (matching `--hash`), so that it can be used with `--hashes-from` later. The size
+  /// and modification time of each file is written to a `.stat` file next to it. Both are only
+  /// replaced once the run is complete.
+  #[arg(long, value_hint = clap::ValueHint::FilePath)]
+  pub emit_checksums: Option<PathBuf>,
+
+  /// Use the hashes in this file like `--hashes-from` if it exists, and replace it with the hashes
+  /// of this run like `--emit-checksums`, so that repeated runs only hash modified files.
+  #[arg(
+    long,
+    value_hint = clap::ValueHint::FilePath,
+    conflicts_with_all = ["hashes_from", "emit_checksums"]
+  )]
+  pub checksums: Option<PathBuf>,
+
+  /// Append every merge to this undo log, so that it can be reversed with the `undo` subcommand.
+  #[arg(long, value_hint = clap::ValueHint::FilePath)]
+  pub undo_log: Option<PathBuf>,
+
+  /// Keep the replaced files in a timestamped directory inside this directory, as hard links when
+  /// possible and as copies otherwise.
+  #[arg(long, value_hint = clap::ValueHint::DirPath)]
+  pub backup_dir: Option<PathBuf>,
+
+  /// Remove the backups in `--backup-dir` that are older than this, e.g. `30days`.
+  #[arg(long, requires = "backup_dir")]
+  pub backup_retention: Option<humantime::Duration>,
+
+  /// Paths where files will be deduplicated. Paths below other paths are only scanned once.
+  #[arg(required_unless_present_any = ["files_from", "resume"], value_hint = clap::ValueHint::DirPath)]
+  pub path: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone)]
+struct ScanDir {
+  path: Arc<Path>,
+  depth: usize,
+  /// The ID of the directory, so that it's only scanned once even if it's found through several
+  /// paths. `None` for directories that are scanned again while watching.
+  uid: Option<FileUid>,
+  ignores: Option<Arc<IgnoreStack>>,
+  /// Whether the directory is scanned again while watching, so that the files in it that are
+  /// known and unchanged are left out.
+  rescan: bool,
+}
+
+#[derive(Debug, Clone)]
+enum ScanDirResult {
+  Dir(ScanDir),
+  File(FileStorageData),
+}
+
+static ARGS: OnceLock<DedupArgs> = OnceLock::new();
+
+impl DedupArgs {
+  /// Parses the command line, on top of the settings of the config file.
+  pub fn parse_with_config() -> Self {
+    DedupArgs::parse_from(config::with_config_args(std::env::args_os()))
+  }
+
+  /// Whether stdout is for text, rather than a document of `--format` or `--summary-json`.
+  pub(crate) fn prints_text(&self) -> bool {
+    self.format == OutputFormat::Text
+      && self
+        .summary_json
+        .as_ref()
+        .map_or(true, |path| path != Path::new("-"))
+  }
+
+  /// Whether the run continues after the first pass.
+  pub(crate) fn keeps_running(&self) -> bool {
+    self.watch || self.control_socket.is_some() || self.every.is_some()
+  }
+
+  #[cfg(feature = "tui")]
+  pub(crate) fn uses_tui(&self) -> bool {
+    self.tui
+  }
+
+  #[cfg(not(feature = "tui"))]
+  pub(crate) fn uses_tui(&self) -> bool {
+    false
+  }
+
+  #[cfg(not(test))]
+  pub(crate) fn get() -> &'static Self {
+    ARGS
+      .get()
+      .expect("The arguments are set when the engine is built")
+  }
+
+  /// Unit tests run with the default arguments for the current directory.
+  #[cfg(test)]
+  pub(crate) fn get() -> &'static Self {
+    ARGS.get_or_init(|| DedupArgs::parse_from(["hard-link-dedup", "."]))
+  }
+}
+
+static SCAN_SEMAPHORE: OnceLock<Semaphore> = OnceLock::new();
+
+fn get_scan_lock() -> &'static Semaphore {
+  SCAN_SEMAPHORE.get_or_init(|| Semaphore::new(DedupArgs::get().max_scan_tasks))
+}
+
+async fn scan_dir(dir: &ScanDir) -> Result<Arc<[ScanDirResult]>> {
+  control::wait_if_paused().await;
+  let _lock = get_scan_lock().acquire().await?;
+  observer::notify(Event::DirScanned { path: &dir.path });
+  // The entries are read up front, so that the directory handle is closed before the metadata of
+  // the entries is read.
+  let entries = {
+    let _handle = fd_limit::acquire().await;
+    let mut reader = Box::new(fs::read_dir(&dir.path).await?);
+    let mut entries = vec![];
+    while let Some(entry) = reader.next_entry().await? {
+      // The file type usually comes with the entry, so other files are skipped without a stat.
+      if !filter::has_extension(entry.file_name()) && entry.file_type().await?.is_file() {
+        output::print_skipped(&entry.path(), "not included");
+        continue;
+      }
+      entries.push((entry.path(), entry.file_name()));
+    }
+    entries
+  };
+  let mut result = vec![];
+  let ignores = IgnoreStack::read_dir(&dir.path, dir.ignores.clone()).await?;
+  for (path, file_name) in entries {
+    result.extend(scan_entry(dir, &ignores, path, file_name).await?);
+  }
+  Ok(result.into())
+}
+
+/// Whether `dir` is tagged as a cache by a `CACHEDIR.TAG`, as in
+/// <https://bford.info/cachedir/>.
+async fn is_cache_dir(dir: &Path) -> bool {
+  const SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+  let _handle = fd_limit::acquire().await;
+  let Ok(mut tag) = fs::File::open(dir.join("CACHEDIR.TAG")).await else {
+    return false;
+  };
+  let mut signature = [0; SIGNATURE.len()];
+  tag.read_exact(&mut signature).await.is_ok() && signature == SIGNATURE
+}
+
+/// Scans an entry of `dir`, unless it's filtered out.
+async fn scan_entry(
+  dir: &ScanDir,
+  ignores: &Option<Arc<IgnoreStack>>,
+  mut path: PathBuf,
+  file_name: OsString,
+) -> Result<Option<ScanDirResult>> {
+  let args = DedupArgs::get();
+  let file_name = if args.match_full_path {
+    path.as_os_str().to_owned()
+  } else {
+    file_name
+  };
+  let file_name = file_name.to_string_lossy();
+  if filter::is_excluded(&file_name) {
+    output::print_skipped(&path, "excluded");
+    return Ok(None);
+  }
+  let mut metadata = fs::symlink_metadata(&path).await?;
+  if args.skip_hidden
+    && path
+      .file_name()
+      .is_some_and(|name| os::is_hidden(name, &metadata))
+  {
+    output::print_skipped(&path, "hidden");
+    return Ok(None);
+  }
+  if metadata.is_symlink() {
+    if !args.follow_symlinks {
+      output::print_skipped(&path, "symlink");
+      return Ok(None);
+    }
+    let Ok(target) = fs::canonicalize(&path).await else {
+      output::print_skipped(&path, "dangling symlink");
+      return Ok(None);
+    };
+    metadata = fs::metadata(&target).await?;
+    if ignores
+      .as_ref()
+      .is_some_and(|ignores| ignores.is_ignored(&path, metadata.is_dir()))
+    {
+      output::print_skipped(&path, "ignored by ignore file");
+      return Ok(None);
+    }
+    if metadata.is_file() {
+      path = target;
+    }
+  } else if ignores
+    .as_ref()
+    .is_some_and(|ignores| ignores.is_ignored(&path, metadata.is_dir()))
+  {
+    output::print_skipped(&path, "ignored by ignore file");
+    return Ok(None);
+  }
+  if metadata.is_dir() {
+    if filter::is_excluded_dir(&file_name) {
+      output::print_skipped(&path, "excluded directory");
+      return Ok(None);
+    }
+    if args.skip_caches && is_cache_dir(&path).await {
+      output::print_skipped(&path, "cache directory");
+      return Ok(None);
+    }
+    if args
+      .max_depth
+      .map_or(true, |max_depth| dir.depth < max_depth)
+    {
+      let uid = read_link_metadata(&path).await?.get_file_uid();
+      return Ok(Some(ScanDirResult::Dir(ScanDir {
+        path: path.into(),
+        depth: dir.depth + 1,
+        uid: Some(uid),
+        ignores: ignores.clone(),
+        rescan: dir.rescan,
+      })));
+    }
+    output::print_skipped(&path, "deeper than --max-depth");
+  } else if metadata.is_file() {
+    if !filter::is_included(&file_name) {
+      output::print_skipped(&path, "not included");
+      return Ok(None);
+    }
+    return Ok(scan_file(path).await?.map(ScanDirResult::File));
+  }
+  Ok(None)
+}
+
+/// Scans a file or directory that changed below `root` while watching it, with the same filters
+/// as if the directories leading to it were scanned.
+async fn scan_changed(root: &Path, path: &Path) -> Result<Arc<[ScanDirResult]>> {
+  let Ok(relative) = path.strip_prefix(root) else {
+    return Ok(Arc::new([]));
+  };
+  let mut dir = ScanDir {
+    path: root.into(),
+    depth: 0,
+    uid: None,
+    ignores: None,
+    rescan: true,
+  };
+  if relative.as_os_str().is_empty() {
+    return Ok(Arc::new([ScanDirResult::Dir(dir)]));
+  }
+  let mut components = relative.components().peekable();
+  while let Some(component) = components.next() {
+    let ignores = IgnoreStack::read_dir(&dir.path, dir.ignores.clone()).await?;
+    let entry_path = dir.path.join(component);
+    let file_name = component.as_os_str().to_owned();
+    match scan_entry(&dir, &ignores, entry_path, file_name).await? {
+      Some(ScanDirResult::Dir(child)) if components.peek().is_some() => dir = child,
+      Some(entry) if components.peek().is_none() => return Ok(Arc::new([entry])),
+      _ => break,
+    }
+  }
+  Ok(Arc::new([]))
+}
+
+async fn scan_changed_with_context(root: PathBuf, path: PathBuf) -> Result<Arc<[ScanDirResult]>> {
+  let result = scan_changed(&root, &path)
+    .await
+    .with_context(move || format!("Could not scan changed file {}", path.display()));
+  match (result, DedupArgs::get().ignore_scan_errors) {
+    (result, false) => result,
+    (Ok(result), true) => Ok(result),
+    (Err(e), true) => {
+      output::print_error(e);
+      Ok(Arc::new([]))
+    }
+  }
+}
+
+/// The suffixes of the temporary paths of the merges in progress, whose files aren't stale.
+static MERGE_SUFFIXES: OnceLock<std::sync::Mutex<HashSet<OsString>>> = OnceLock::new();
+
+fn merge_suffixes() -> std::sync::MutexGuard<'static, HashSet<OsString>> {
+  MERGE_SUFFIXES
+    .get_or_init(Default::default)
+    .lock()
+    .unwrap_or_else(|e| e.into_inner())
+}
+
+/// Keeps the temporary paths with its suffix from being removed as stale until it's dropped.
+struct MergeInProgress(OsString);
+
+impl Drop for MergeInProgress {
+  fn drop(&mut self) {
+    merge_suffixes().remove(&self.0);
+  }
+}
+
+/// Whether `path` was left behind by an interrupted merge. Only paths with the random suffix of
+/// `temporary_path` qualify, since a user may well have a file named like the older form.
+fn is_stale_temporary(path: &Path) -> bool {
+  temporary_suffix(path).is_some_and(|suffix| !merge_suffixes().contains(suffix))
+}
+
+/// Removes a temporary file of an interrupted merge, unless it's the only link to its content.
+async fn remove_stale_temporary(file: &FileStorageData) -> Result<()> {
+  if file.link_count < 2 {
+    output::print_info(format!(
+      "Keeping stale temporary file {}, since it's the only link to its content",
+      file.path.display()
+    ))?;
+    return Ok(());
+  }
+  output::print_info(format!(
+    "Removing stale temporary file {}",
+    file.path.display()
+  ))?;
+  if !DedupArgs::get().dry_run {
+    fs::remove_file(&file.path).await?;
+  }
+  Ok(())
+}
+
+async fn scan_file(path: impl AsRef<Path>) -> Result<Option<FileStorageData>> {
+  let args = DedupArgs::get();
+  let file = FileStorageData::new(path).await?;
+  let skip_reason = if is_temporary(&file.path) {
+    if args.cleanup_temp && !keep::is_protected(&file.path) && is_stale_temporary(&file.path) {
+      remove_stale_temporary(&file).await?;
+      return Ok(None);
+    }
+    Some("temporary file")
+  } else if file.size == 0 {
+    Some("empty")
+  } else if AclPolicy::get() == AclPolicy::Skip && file.acl != Some(acl::TRIVIAL) {
+    Some("has an ACL")
+  } else if file.size < args.min_file_size * 1024 {
+    Some("smaller than --min-file-size")
+  } else if args
+    .max_file_size
+    .is_some_and(|max_file_size| file.size > max_file_size * 1024)
+  {
+    Some("larger than --max-file-size")
+  } else if args
+    .max_existing_links
+    .is_some_and(|max_existing_links| file.link_count > max_existing_links)
+  {
+    Some("more links than --max-existing-links")
+  } else if args.skip_sparse && file.allocated < file.size / 2 {
+    Some("sparse")
+  } else if args.older_than.is_some_and(|age| {
+    // Files without a modification time, or with one in the future, may be written any time.
+    file
+      .modified
+      .and_then(|modified| modified.elapsed().ok())
+      .map_or(true, |elapsed| elapsed < *age)
+  }) {
+    Some("newer than --older-than")
+  } else if !filter::is_owned(&file) {
+    Some("owned by another user")
+  } else {
+    None
+  };
+  if let Some(reason) = skip_reason {
+    output::print_skipped(&file.path, reason);
+    Ok(None)
+  } else {
+    Ok(Some(file))
+  }
+}
+
+async fn scan_listed_file(path: &Path) -> Result<Arc<[ScanDirResult]>> {
+  let args = DedupArgs::get();
+  let file_name = if args.match_full_path {
+    Some(path.as_os_str())
+  } else {
+    path.file_name()
+  };
+  if let Some(file_name) = file_name.map(|name| name.to_string_lossy()) {
+    if filter::is_excluded(&file_name) || !filter::is_included(&file_name) {
+      output::print_skipped(path, "excluded");
+      return Ok(Arc::new([]));
+    }
+  }
+  if !fs::metadata(path).await?.is_file() {
+    return Ok(Arc::new([]));
+  }
+  Ok(
+    scan_file(path)
+      .await?
+      .map(ScanDirResult::File)
+      .into_iter()
+      .collect(),
+  )
+}
+
+async fn scan_listed_file_with_context(path: PathBuf) -> Result<Arc<[ScanDirResult]>> {
+  let result = scan_listed_file(&path)
+    .await
+    .with_context(move || format!("Could not read metadata for file {}", path.display()));
+  match (result, DedupArgs::get().ignore_scan_errors) {
+    (result, false) => result,
+    (Ok(result), true) => Ok(result),
+    (Err(e), true) => {
+      output::print_error(e);
+      Ok(Arc::new([]))
+    }
+  }
+}
+
+async fn scan_dir_with_context(dir: ScanDir) -> Result<Arc<[ScanDirResult]>> {
+  let result = scan_dir(&dir)
+    .await
+    .with_context(move || format!("Could not scan dir {}", dir.path.display()));
+  match (result, DedupArgs::get().ignore_scan_errors) {
+    (result, false) => result,
+    (Ok(result), true) => Ok(result),
+    (Err(e), true) => {
+      output::print_error(e);
+      Ok(Arc::new([]))
+    }
+  }
+}
+
+const SUFFIX_LEN: usize = 6;
+
+/// A random suffix that keeps the temporary files of a merge apart from those of other runs.
+fn random_suffix() -> OsString {
+  const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+  // Every `RandomState` is seeded differently, which is random enough for unique names.
+  let mut random = RandomState::new().build_hasher().finish();
+  let mut suffix = String::with_capacity(SUFFIX_LEN);
+  for _ in 0..SUFFIX_LEN {
+    suffix.push(ALPHABET[(random % ALPHABET.len() as u64) as usize] as char);
+    random /= ALPHABET.len() as u64;
+  }
+  suffix.into()
+}
+
+/// The path next to `file` that is used while merging it, e.g. `file.bin.hard_link.k3x9q0` or,
+/// with a `role`, `file.bin.rollback.hard_link.k3x9q0`. Journals of older versions may refer to
+/// paths without a `suffix`.
+fn temporary_path(file: &Path, role: Option<&str>, suffix: Option<&OsStr>) -> PathBuf {
+  let Some(file_name) = file.file_name() else {
+    unreachable!()
+  };
+  let mut temporary_name = file_name.to_owned();
+  if let Some(role) = role {
+    temporary_name.push(".");
+    temporary_name.push(role);
+  }
+  temporary_name.push(".");
+  temporary_name.push(&DedupArgs::get().temporary_extension);
+  if let Some(suffix) = suffix {
+    temporary_name.push(".");
+    temporary_name.push(suffix);
+  }
+  file.with_file_name(temporary_name)
+}
+
+/// The suffix of a path created by `temporary_path`, or `None` if it doesn't have one.
+fn temporary_suffix(path: &Path) -> Option<&OsStr> {
+  let suffix = path.extension()?;
+  let is_suffix = suffix.len() == SUFFIX_LEN
+    && suffix.to_str().is_some_and(|suffix| {
+      suffix
+        .bytes()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit())
+    });
+  let extension = path.file_stem().map(Path::new).and_then(Path::extension);
+  (is_suffix && extension == Some(&DedupArgs::get().temporary_extension)).then_some(suffix)
+}
+
+/// Whether `path` is a temporary file of a merge, which is never scanned.
+fn is_temporary(path: &Path) -> bool {
+  path.extension() == Some(&DedupArgs::get().temporary_extension)
+    || temporary_suffix(path).is_some()
+}
+
+/// Picks the temporary paths of a merge of `redundant`, neither of which may exist already. They
+/// are not removed as stale while the returned guard is alive.
+async fn unused_temporary_paths(redundant: &Path) -> Result<(PathBuf, PathBuf, MergeInProgress)> {
+  loop {
+    let suffix = random_suffix();
+    if !merge_suffixes().insert(suffix.clone()) {
+      continue;
+    }
+    let in_progress = MergeInProgress(suffix);
+    let new_file = temporary_path(redundant, None, Some(&in_progress.0));
+    let rollback = temporary_path(redundant, Some("rollback"), Some(&in_progress.0));
+    if !fs::try_exists(&new_file).await? && !fs::try_exists(&rollback).await? {
+      return Ok((new_file, rollback, in_progress));
+    }
+  }
+}
+
+/// Creates `new_file` as the replacement of `redundant`, according to `--strategy`.
+async fn create_replacement(original: &Path, redundant: &Path, new_file: &Path) -> Result<()> {
+  match Strategy::get() {
+    Strategy::HardLink => fs::hard_link(original, new_file).await?,
+    Strategy::Reflink => {
+      let (original, new_file) = (original.to_owned(), new_file.to_owned());
+      tokio::task::spawn_blocking(move || strategy::reflink(&original, &new_file)).await??;
+    }
+    Strategy::Symlink => strategy::symlink(original, new_file).await?,
+    Strategy::DedupeIoctl | Strategy::Delete => unreachable!("Files are not replaced"),
+  }
+  if !Strategy::get().shares_file() {
+    // The replacement is a file of its own, which takes over the owner, mode and attributes of the
+    // redundant file. It's not used if they can't all be copied.
+    let (redundant, replacement) = (redundant.to_owned(), new_file.to_owned());
+    let copied =
+      tokio::task::spawn_blocking(move || strategy::copy_metadata(&redundant, &replacement))
+        .await?;
+    if let Err(e) = copied {
+      fs::remove_file(new_file).await?;
+      return Err(e).context("Could not copy the owner, mode and attributes of the redundant file");
+    }
+  }
+  Ok(())
+}
+
+/// Replaces `redundant` with `original` as a two-phase commit. The replacement and a rollback link
+/// to the redundant file are prepared first, and after the rename the redundant path must resolve
+/// to the replacement. If it doesn't, the redundant file is restored from the rollback.
+async fn replace_redundant(
+  original: &Path,
+  redundant: &Path,
+  (new_file, rollback): (&Path, &Path),
+) -> Result<()> {
+  let original_uid = read_link_metadata(original).await?.get_file_uid();
+  let redundant_uid = read_link_metadata(redundant).await?.get_file_uid();
+
+  create_replacement(original, redundant, new_file).await?;
+  let new_uid = read_link_metadata(new_file).await?.get_file_uid();
+  if Strategy::get().shares_file() && new_uid != original_uid {
+    fs::remove_file(new_file).await?;
+    bail!("The files were replaced while the merge was prepared");
+  }
+
+  let paths = (new_file.to_owned(), redundant.to_owned());
+  let exchange = move || strategy::exchange(&paths.0, &paths.1);
+  if tokio::task::spawn_blocking(exchange.clone()).await?? {
+    // The redundant path was never missing, and the replaced file is now at the temporary path.
+    if read_link_metadata(new_file).await?.get_file_uid() != redundant_uid {
+      tokio::task::spawn_blocking(exchange).await??;
+      fs::remove_file(new_file).await?;
+      bail!(
+        "{} was replaced during the merge and has been restored",
+        redundant.display()
+      );
+    }
+    fs::remove_file(new_file).await?;
+    return Ok(());
+  }
+
+  if let Err(e) = fs::hard_link(redundant, rollback).await {
+    fs::remove_file(new_file).await?;
+    return Err(e)?;
+  }
+  if read_link_metadata(rollback).await?.get_file_uid() != redundant_uid {
+    fs::remove_file(new_file).await?;
+    fs::remove_file(rollback).await?;
+    bail!("The files were replaced while the merge was prepared");
+  }
+
+  let redundant_permissions = fs::metadata(redundant).await?.permissions();
+  if redundant_permissions.readonly() {
+    let mut permissions = redundant_permissions.clone();
+    // The redundant file is replaced right after this, so the permissive mode is never observed.
+    #[allow(clippy::permissions_set_readonly_false)]
+    permissions.set_readonly(false);
+    fs::set_permissions(redundant, permissions).await?;
+  }
+  if let Err(e) = fs::rename(new_file, redundant).await {
+    fs::remove_file(new_file).await?;
+    fs::remove_file(rollback).await?;
+    fs::set_permissions(redundant, redundant_permissions).await?;
+    return Err(e)?;
+  }
+
+  if read_link_metadata(redundant).await?.get_file_uid() != new_uid {
+    // Someone else replaced the file after the rename, so their version is kept.
+    fs::remove_file(rollback).await?;
+    bail!("{} was replaced during the merge", redundant.display());
+  }
+  if read_link_metadata(rollback).await?.get_file_uid() != redundant_uid {
+    fs::rename(rollback, redundant).await?;
+    bail!(
+      "{} was replaced during the merge and has been restored",
+      redundant.display()
+    );
+  }
+  fs::remove_file(rollback).await?;
+  Ok(())
+}
+
+/// Whether `--max-files` or `--max-savings` has been reached, after which the run stops.
+fn is_limit_reached(stats: &Stats) -> Result<bool> {
+  let args = DedupArgs::get();
+  let reached = if args.max_files.is_some_and(|max| stats.links_created >= max) {
+    format!("Stopping after replacing {} files", stats.links_created)
+  } else if args.max_savings.is_some_and(|max| stats.reclaimed >= max) {
+    format!(
+      "Stopping after saving {}",
+      output::format_size(stats.reclaimed)
+    )
+  } else {
+    return Ok(false);
+  };
+  output::print_info(reached)?;
+  Ok(true)
+}
+
+/// Whether `--across-roots-only` keeps `a` and `b` apart, since they are below the same path.
+fn is_same_root(roots: &[PathBuf], a: &Path, b: &Path) -> bool {
+  DedupArgs::get().across_roots_only && watch::root_of(roots, a) == watch::root_of(roots, b)
+}
+
+/// Whether merging `group` would save less than `--min-savings`.
+fn is_small(group: &keep::Group) -> bool {
+  DedupArgs::get()
+    .min_savings
+    .is_some_and(|min_savings| group.wasted() < min_savings)
+}
+
+/// Why `candidate` of a deferred `group` isn't merged into `original`, if it isn't.
+fn deferred_skip_reason(
+  roots: &[PathBuf],
+  group: &keep::Group,
+  original: &Path,
+  candidate: &Candidate,
+) -> Option<&'static str> {
+  if candidate.id == group.candidates[group.kept].id {
+    Some("kept")
+  } else if !group.apply {
+    Some("skipped in the browser")
+  } else if is_small(group) {
+    Some("group saves less than --min-savings")
+  } else if is_same_root(roots, original, candidate.path()) {
+    Some("below the same path as the original")
+  } else if candidate.is_protected() {
+    Some("protected")
+  } else {
+    None
+  }
+}
+
+/// The root of `roots` that the `path` of a `rescan` command is below, and the path as it's found
+/// from the root.
+async fn rescan_target<'a>(roots: &'a [PathBuf], path: &Path) -> Result<(&'a Path, PathBuf)> {
+  // A relative path would never be below a root, and `..` could lead out of it.
+  let canonical = fs::canonicalize(path)
+    .await
+    .with_context(|| format!("Could not find {}", path.display()))?;
+  let canonical = os::extended_path(&canonical)?;
+  let Some(root) = watch::root_of(roots, &canonical) else {
+    bail!("{} is not below any of the scanned paths", path.display());
+  };
+  Ok((root, canonical))
+}
+
+/// Held by every merge in progress, so that a stopped run can let them finish.
+static MERGING: OnceLock<tokio::sync::RwLock<()>> = OnceLock::new();
+
+/// Waits for the merges in progress to finish. No merge is started while the guard is held.
+pub(crate) async fn stop_merging() -> tokio::sync::RwLockWriteGuard<'static, ()> {
+  MERGING.get_or_init(Default::default).write().await
+}
+
+/// Replaces `redundant` with `original`, or returns `false` if it's skipped since another process
+/// holds a lock on it or the user declined it.
+async fn merge_with_hard_link(
+  original: impl AsRef<Path>,
+  redundant: impl AsRef<Path>,
+  size: Filesize,
+) -> Result<bool> {
+  let args = DedupArgs::get();
+  if keep::is_protected(redundant.as_ref()) {
+    bail!("{} is protected", redundant.as_ref().display());
+  }
+  let display = (original.as_ref(), redundant.as_ref());
+  // The temporary file name may be the one pushing the path over `MAX_PATH`.
+  let (original, redundant) = (
+    os::extended_path(original.as_ref())?,
+    os::extended_path(redundant.as_ref())?,
+  );
+  // Writes from other processes would otherwise land on the file that is replaced.
+  let _lock = if args.dry_run {
+    None
+  } else {
+    let path = redundant.clone();
+    match tokio::task::spawn_blocking(move || lock::try_lock(&path)).await?? {
+      Some(lock) => Some(lock),
+      None => {
+        output::print_skipped(display.1, "locked by another process");
+        return Ok(false);
+      }
+    }
+  };
+  if !interactive::confirm_merge(display.0, display.1, size).await? {
+    return Ok(false);
+  }
+  let _merging = MERGING.get_or_init(Default::default).read().await;
+  output::print_merge(display.0, display.1, size)?;
+
+  if !args.dry_run {
+    let timestamps = Timestamps::read(&original, &redundant).await?;
+    let attributes = if XattrPolicy::get() == XattrPolicy::CopyMissing {
+      let redundant = redundant.clone();
+      Some(tokio::task::spawn_blocking(move || xattrs::read(&redundant)).await??)
+    } else {
+      None
+    };
+    undo::record(&original, &redundant).await?;
+    backup::preserve(&redundant).await?;
+    if Strategy::get() == Strategy::DedupeIoctl {
+      // The files are deduped in place, so there is nothing to journal or roll back.
+      let size = fs::metadata(&original).await?.len();
+      let (original, redundant) = (original.clone(), redundant.clone());
+      tokio::task::spawn_blocking(move || strategy::dedupe_range(&original, &redundant, size))
+        .await??;
+    } else if Strategy::get() == Strategy::Delete {
+      // Make sure that the original didn't disappear before its last copy is removed.
+      read_link_metadata(&original).await?;
+      let mut permissions = fs::metadata(&redundant).await?.permissions();
+      if permissions.readonly() {
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        fs::set_permissions(&redundant, permissions).await?;
+      }
+      fs::remove_file(&redundant).await?;
+    } else {
+      let (new_file, rollback, _in_progress) = unused_temporary_paths(&redundant).await?;
+      journal::begin(&original, &redundant, &new_file)?;
+      let result = replace_redundant(&original, &redundant, (&new_file, &rollback)).await;
+      journal::done(&original, &redundant, &new_file)?;
+      result?;
+    }
+    // A shared file is the original as well, whose modification time is kept when it's protected.
+    let shares_protected = Strategy::get().shares_file() && keep::is_protected(&original);
+    if Strategy::get() != Strategy::Delete && !shares_protected {
+      timestamps.apply(&redundant).await?;
+    }
+    if let Some(attributes) = attributes {
+      let merged = if Strategy::get() == Strategy::Delete {
+        original.clone()
+      } else {
+        redundant.clone()
+      };
+      // The attributes would be set on the original, unless the redundant path is a copy.
+      let is_protected =
+        shares_protected || (Strategy::get() == Strategy::Delete && keep::is_protected(&original));
+      tokio::task::spawn_blocking(move || {
+        if is_protected {
+          xattrs::report_missing(&attributes, &redundant, &merged)
+        } else {
+          xattrs::copy_missing(&attributes, &redundant, &merged)
+        }
+      })
+      .await??;
+    }
+  }
+  if !args.not_readonly && Strategy::get().shares_file() && !keep::is_protected(&original) {
+    let metadata_original = fs::metadata(&original).await?;
+    if args.dry_run {
+      if !args.report && !metadata_original.permissions().readonly() {
+        output::print_info(format!("Applying readonly to {} ", &original.display()))?;
+      }
+    } else {
+      let mut permissions = metadata_original.permissions();
+      if !permissions.readonly() {
+        permissions.set_readonly(true);
+        fs::set_permissions(&original, permissions).await?;
+      }
+    }
+  }
+  observer::notify(Event::Merged {
+    original: display.0,
+    redundant: display.1,
+  });
+  Ok(true)
+}
+
+async fn merge_with_hard_link_with_context(
+  original: impl AsRef<Path>,
+  redundant: impl AsRef<Path>,
+  size: Filesize,
+) -> Result<bool> {
+  merge_with_hard_link(original.as_ref(), redundant.as_ref(), size)
+    .await
+    .with_context(move || {
+      format!(
+        "Could not merge {} to {}",
+        redundant.as_ref().display(),
+        original.as_ref().display()
+      )
+    })
+}
+
+/// Whether merging failed because the original has as many hard links as the file system allows.
+fn is_link_limit(error: &anyhow::Error) -> bool {
+  #[cfg(unix)]
+  const EMLINK: i32 = libc::EMLINK;
+  /// `ERROR_TOO_MANY_LINKS`
+  #[cfg(windows)]
+  const EMLINK: i32 = 1142;
+  error.chain().any(|cause| {
+    cause
+      .downcast_ref::<std::io::Error>()
+      .is_some_and(|e| e.raw_os_error() == Some(EMLINK))
+  })
+}
+
+/// Whether merging failed because the paths are on different mounts, which happens when
+/// `--same-storage` joins storages that can't share hard links.
+fn is_cross_device(error: &anyhow::Error) -> bool {
+  #[cfg(unix)]
+  const EXDEV: i32 = libc::EXDEV;
+  /// `ERROR_NOT_SAME_DEVICE`
+  #[cfg(windows)]
+  const EXDEV: i32 = 17;
+  error.chain().any(|cause| {
+    cause
+      .downcast_ref::<std::io::Error>()
+      .is_some_and(|e| e.raw_os_error() == Some(EXDEV))
+  })
+}
+
+/// Merges every path of a redundant file into `original`. If `original` reaches the link limit of
+/// the file system, the path that failed is returned to become a new original in its place. Paths
+/// that are locked by other processes or on a different mount are skipped.
+async fn merge_paths(
+  (original_uid, original): (FileUid, &Arc<Path>),
+  paths: impl IntoIterator<Item = Arc<Path>>,
+  (size, digest): (Filesize, HashDigest),
+  stats: &mut Stats,
+  groups: &mut DuplicateGroups,
+  savings: &mut DirSavings,
+) -> Result<Option<Arc<Path>>> {
+  let paths = paths.into_iter().collect::<Vec<_>>();
+  // A file that is gone fails its merge below instead.
+  let allocated = match paths.first() {
+    Some(path) => storage::allocated_size(path).await.unwrap_or(size),
+    None => 0,
+  };
+  let (mut merged, mut skipped) = (None, false);
+  for redundant in paths {
+    let started = Instant::now();
+    let result = merge_with_hard_link_with_context(original, &redundant, size).await;
+    stats.merge_seconds += started.elapsed().as_secs_f64();
+    match result {
+      Err(e) if is_link_limit(&e) => {
+        output::print_info(format!(
+          "{} has too many links, so {} becomes a new original",
+          original.display(),
+          redundant.display()
+        ))?;
+        // The redundant file is kept, so no storage is saved.
+        stats.saved_storage -= size;
+        return Ok(Some(redundant));
+      }
+      Err(e) if is_cross_device(&e) => {
+        output::print_skipped(&redundant, "different mount");
+        skipped = true;
+        continue;
+      }
+      Ok(false) => {
+        skipped = true;
+        continue;
+      }
+      result => result?,
+    };
+    stats.links_created += 1;
+    groups.add_link(original_uid, original, (size, digest), redundant.clone());
+    merged.get_or_insert(redundant);
+  }
+  if let Some(redundant) = merged {
+    groups.add_wasted(original_uid, original, (size, digest));
+    if !skipped {
+      savings.add(&redundant, size);
+      stats.reclaimed += size;
+      stats.saved_allocated += allocated;
+    }
+  }
+  if skipped {
+    // The file is still in use through the skipped paths.
+    stats.saved_storage -= size;
+  }
+  Ok(None)
+}
+
+#[derive(Debug)]
+enum FileEntry {
+  OriginalFile(Arc<Path>),
+  Files(Arc<Path>, HashSet<Arc<Path>>),
+  LinkTo(FileId),
+}
+
+#[derive(Debug, Default)]
+struct StorageContent {
+  /// The first file found of every size, until a second file of that size needs it hashed.
+  file_sizes: HashMap<Filesize, Option<FileStorageData>>,
+  /// The first file found of every prefilter stage, size and digest, until a second one needs it
+  /// hashed by the next stage.
+  prefilters: HashMap<(usize, Filesize, HashDigest), Option<FileStorageData>>,
+  hashes: HashMap<(Filesize, HashDigest), FileId>,
+  files: HashMap<FileId, FileEntry>,
+  /// Every path of every file, while merges are deferred until all duplicates are known.
+  paths: HashMap<FileId, Vec<Arc<Path>>>,
+  /// The duplicates of every original, while merges are deferred.
+  duplicates: HashMap<FileId, (Filesize, HashDigest, Vec<FileId>)>,
+}
+
+impl StorageContent {
+  /// Drops everything known about a file whose content changed, so that it's scanned as a new file.
+  fn forget(&mut self, file_id: FileId) {
+    // Links to the file would point to nothing.
+    let mut forgotten = HashSet::from([file_id]);
+    loop {
+      let count = forgotten.len();
+      for (id, entry) in &self.files {
+        if matches!(entry, FileEntry::LinkTo(target) if forgotten.contains(target)) {
+          forgotten.insert(*id);
+        }
+      }
+      if forgotten.len() == count {
+        break;
+      }
+    }
+    self.files.retain(|id, _| !forgotten.contains(id));
+    self.hashes.retain(|_, id| !forgotten.contains(id));
+    let is_other =
+      |file: &Option<FileStorageData>| file.as_ref().map_or(true, |file| file.file_id != file_id);
+    self.file_sizes.retain(|_, file| is_other(file));
+    self.prefilters.retain(|_, file| is_other(file));
+  }
+}
+
+#[derive(Default, Clone, Serialize)]
+pub struct Stats {
+  pub saved_storage: Filesize,
+  /// The storage that the merged files had allocated on disk, which is less than `saved_storage`
+  /// for sparse and compressed files.
+  pub saved_allocated: Filesize,
+  /// Storage that files found through more than one path were sharing before this run.
+  pub shared_storage: Filesize,
+  pub existing_links: usize,
+  pub files_queued: usize,
+  pub links_created: usize,
+  pub files_hashed: usize,
+  pub bytes_hashed: Filesize,
+  pub prefilters_hashed: usize,
+  pub files_processed: usize,
+  pub dirs_scanned: usize,
+  /// The time from the start of the run until the last directory was scanned.
+  pub scan_seconds: f64,
+  /// The time from when the first file was queued for hashing until the last one was hashed.
+  pub hash_seconds: f64,
+  /// The time spent replacing redundant files.
+  pub merge_seconds: f64,
+  #[serde(skip)]
+  hash_started: Option<Instant>,
+  /// The storage freed by the merges so far, unlike `saved_storage` which also counts deferred
+  /// merges when they are found.
+  #[serde(skip)]
+  reclaimed: Filesize,
+}
+
+/// Scans and merges every path, and returns whether that was completed instead of stopped by
+/// `--max-files` or `--max-savings`.
+async fn run(report: Arc<Mutex<Report>>) -> Result<bool> {
+  let args = DedupArgs::get();
+  let started = Instant::now();
+
+  enum WorkerResult {
+    ScanResult(Arc<[ScanDirResult]>),
+    /// Files and directories that were created or modified while watching.
+    Changed(Arc<[ScanDirResult]>),
+    NewPrefilterReceived(usize, FileStorageData, Option<HashDigest>),
+    NewHashReceived(StorageKey, FileId, (Filesize, Option<HashDigest>)),
+  }
+  /// Prints what a pass of `--every` did.
+  fn print_pass(before: &Stats, after: &Stats) -> Result<()> {
+    output::print_info(format!(
+      "Pass done: {} files processed, {} links created, {} {} saved",
+      after.files_processed - before.files_processed,
+      after.links_created - before.links_created,
+      output::format_size(after.saved_storage.saturating_sub(before.saved_storage)),
+      if DedupArgs::get().dry_run {
+        "can be"
+      } else {
+        "was"
+      }
+    ))?;
+    Ok(())
+  }
+  fn spawn_changed(worker: &mut JoinSet<Result<WorkerResult>>, root: &Path, path: PathBuf) {
+    let root = root.to_owned();
+    worker.spawn(async move {
+      Ok(WorkerResult::Changed(
+        scan_changed_with_context(root, path).await?,
+      ))
+    });
+  }
+  fn spawn_full_hash(
+    worker: &mut JoinSet<Result<WorkerResult>>,
+    stats: &mut Stats,
+    file: FileStorageData,
+  ) {
+    stats.files_queued += 1;
+    stats.hash_started.get_or_insert_with(Instant::now);
+    worker.spawn(async move {
+      Ok(WorkerResult::NewHashReceived(
+        file.storage_key(),
+        file.file_id,
+        (file.size, calculate_file_hash_with_context(&file).await?),
+      ))
+    });
+  }
+  /// Hashes a file that matched every prefilter before `stage`, with the next prefilter if that's
+  /// cheaper than the full hash.
+  fn spawn_hash(
+    worker: &mut JoinSet<Result<WorkerResult>>,
+    stats: &mut Stats,
+    stage: usize,
+    file: FileStorageData,
+    previous: Option<HashDigest>,
+  ) {
+    match Prefilter::stages().get(stage) {
+      Some(&prefilter) if file.size > Prefilter::block_size() as Filesize => {
+        stats.hash_started.get_or_insert_with(Instant::now);
+        worker.spawn(async move {
+          let digest = calculate_prefilter_hash_with_context(&file, prefilter, previous).await?;
+          Ok(WorkerResult::NewPrefilterReceived(stage, file, digest))
+        });
+      }
+      _ => spawn_full_hash(worker, stats, file),
+    }
+  }
+  let mut worker = JoinSet::<Result<WorkerResult>>::new();
+  let mut report = report.as_ref().lock().await;
+  let Report {
+    stats,
+    groups,
+    savings,
+    cross_device_groups,
+  } = &mut *report;
+
+  if args.resume {
+    journal::resume(stats).await?;
+  }
+
+  if let Some(DedupCommand::ApplyGroups {
+    groups: ref source,
+    size_only,
+  }) = args.command
+  {
+    apply::apply_groups(source, size_only, stats, groups).await?;
+    return Ok(true);
+  }
+
+  if let Some(ref files_from) = args.files_from {
+    let separator = if args.null { b'\0' } else { b'\n' };
+    for path in input::read_path_list(files_from, separator).await? {
+      let path = os::extended_path(&path)?;
+      worker.spawn(async move {
+        Ok(WorkerResult::ScanResult(
+          scan_listed_file_with_context(path).await?,
+        ))
+      });
+    }
+  }
+
+  let roots = args
+    .path
+    .iter()
+    .map(|path| os::extended_path(path))
+    .collect::<std::io::Result<Vec<_>>>()?;
+  // Deferred merges happen once every duplicate is known, which is never while the run continues.
+  if args.keeps_running() && Keep::is_deferred() {
+    bail!(
+      "--watch, --control-socket and --every can't be combined with --keep, --prefer-path or \
+       --protect-path"
+    );
+  }
+  let mut watcher = if args.watch {
+    Some(watch::Watcher::new(&roots)?)
+  } else {
+    None
+  };
+  // The size and modification time of every file seen while watching, to tell new content from
+  // new paths of known files.
+  let mut watched = HashMap::<FileUid, (Filesize, Option<SystemTime>)>::new();
+
+  let mut visited_dirs = HashSet::<FileUid>::new();
+  for path in &roots {
+    let uid = read_link_metadata(path)
+      .await
+      .with_context(|| format!("Could not read metadata for {}", path.display()))?
+      .get_file_uid();
+    if !visited_dirs.insert(uid) {
+      output::print_skipped(path, "already scanned");
+      continue;
+    }
+    stats.dirs_scanned += 1;
+    let dir = ScanDir {
+      path: path.as_path().into(),
+      depth: 0,
+      uid: Some(uid),
+      ignores: None,
+      rescan: false,
+    };
+    worker.spawn(async move { Ok(WorkerResult::ScanResult(scan_dir_with_context(dir).await?)) });
+  }
+
+  let mut known_files = HashMap::<StorageKey, StorageContent>::new();
+  // The stats at the start of the current pass with `--every`, and when the next pass starts.
+  let mut pass_start = args.every.map(|_| stats.clone());
+  let mut next_pass = None;
+  loop {
+    let idle = worker.is_empty();
+    if let (true, Some(every), None) = (idle, args.every, next_pass) {
+      if let Some(before) = pass_start.take() {
+        print_pass(&before, stats)?;
+      }
+      next_pass = Some(tokio::time::Instant::now() + *every);
+    }
+    let scheduled = next_pass;
+    let found_files = tokio::select! {
+      Some(found_files) = worker.join_next() => found_files,
+      changed = async { watcher.as_mut().expect("Checked").changed_files().await },
+        if idle && watcher.is_some() => {
+        for path in changed? {
+          // Temporary files may belong to merges that are in progress.
+          match watch::root_of(&roots, &path) {
+            Some(root) if !is_temporary(&path) => spawn_changed(&mut worker, root, path),
+            _ => (),
+          }
+        }
+        continue;
+      }
+      request = control::next_request(), if control::is_enabled() => {
+        match request {
+          Request::Status(reply) => {
+            let status = serde_json::json!({
+              "paused": control::is_paused(),
+              "pending_tasks": worker.len(),
+              "stats": &*stats,
+            });
+            let _ = reply.send(status.to_string());
+          }
+          Request::Rescan(path, reply) => {
+            let rescan = rescan_target(&roots, &path)
+              .await
+              .map(|(root, path)| spawn_changed(&mut worker, root, path));
+            let _ = reply.send(rescan);
+          }
+        }
+        continue;
+      }
+      () = async { tokio::time::sleep_until(scheduled.expect("Checked")).await },
+        if idle && scheduled.is_some() => {
+        next_pass = None;
+        pass_start = Some(stats.clone());
+        visited_dirs.clear();
+        for root in &roots {
+          spawn_changed(&mut worker, root, root.clone());
+        }
+        continue;
+      }
+      else => break,
+    };
+    let found_files = found_files??;
+    if matches!(
+      found_files,
+      WorkerResult::ScanResult(_) | WorkerResult::Changed(_)
+    ) {
+      stats.scan_seconds = started.elapsed().as_secs_f64();
+    } else if let Some(hash_started) = stats.hash_started {
+      stats.hash_seconds = hash_started.elapsed().as_secs_f64();
+    }
+    match found_files {
+      WorkerResult::Changed(files) => {
+        let mut changed = vec![];
+        for file in files.iter() {
+          if let ScanDirResult::Dir(ScanDir { uid: Some(uid), .. }) = file {
+            // Directories are scanned again, even if they were seen before.
+            visited_dirs.remove(uid);
+          }
+          if let ScanDirResult::File(file) = file {
+            let uid = (file.storage_uid, file.file_id);
+            match watched.get(&uid) {
+              // A new path of a known file, or the path of a merge.
+              Some(seen) if *seen == (file.size, file.modified) => continue,
+              Some(_) => {
+                for ((storage_uid, ..), storage) in known_files.iter_mut() {
+                  if *storage_uid == file.storage_uid {
+                    storage.forget(file.file_id);
+                  }
+                }
+              }
+              None => (),
+            }
+          }
+          changed.push(file.clone());
+        }
+        let changed = Arc::from(changed);
+        worker.spawn(async move { Ok(WorkerResult::ScanResult(changed)) });
+      }
+      WorkerResult::ScanResult(files) => {
+        for file in files.iter().map(ToOwned::to_owned) {
+          match file {
+            ScanDirResult::Dir(dir) => {
+              // Directories that are scanned again were visited before, but symlinks could still
+              // lead them into a loop.
+              if let Some(uid) = dir.uid {
+                if !visited_dirs.insert(uid) && (!dir.rescan || args.follow_symlinks) {
+                  output::print_skipped(&dir.path, "already scanned");
+                  continue;
+                }
+              }
+              stats.dirs_scanned += 1;
+              worker.spawn(async move {
+                let rescan = dir.rescan;
+                let files = scan_dir_with_context(dir).await?;
+                Ok(if rescan {
+                  WorkerResult::Changed(files)
+                } else {
+                  WorkerResult::ScanResult(files)
+                })
+              });
+            }
+            ScanDirResult::File(storage_data) => {
+              stats.files_processed += 1;
+              if args.keeps_running() {
+                watched.insert(
+                  (storage_data.storage_uid, storage_data.file_id),
+                  (storage_data.size, storage_data.modified),
+                );
+              }
+              observer::notify(Event::FileScanned {
+                path: &storage_data.path,
+                size: storage_data.size,
+              });
+              let storage = known_files.entry(storage_data.storage_key()).or_default();
+              if Keep::is_deferred() {
+                storage
+                  .paths
+                  .entry(storage_data.file_id)
+                  .or_default()
+                  .push(storage_data.path.clone());
+              }
+              match storage.files.entry(storage_data.file_id) {
+                Entry::Occupied(current_file_entry) => {
+                  stats.existing_links += 1;
+                  stats.shared_storage += storage_data.size;
+                  let mut promoted = None;
+                  let mut id = storage_data.file_id;
+                  let mut current_entry = current_file_entry;
+                  loop {
+                    let make_link = id != storage_data.file_id;
+                    match current_entry.get_mut() {
+                      FileEntry::LinkTo(ref file_id) if file_id == &storage_data.file_id => {
+                        unreachable!("File links will never loop")
+                      }
+                      FileEntry::LinkTo(ref file_id) => {
+                        id = *file_id;
+                      }
+                      FileEntry::OriginalFile(ref target_file) => {
+                        if make_link && !Keep::is_deferred() {
+                          match merge_with_hard_link_with_context(
+                            target_file,
+                            &storage_data.path,
+                            storage_data.size,
+                          )
+                          .await
+                          {
+                            Err(e) if is_link_limit(&e) => {
+                              output::print_info(format!(
+                                "{} has too many links, so {} becomes a new original",
+                                target_file.display(),
+                                storage_data.path.display()
+                              ))?;
+                              stats.saved_storage -= storage_data.size;
+                              promoted = Some(storage_data.path);
+                              break;
+                            }
+                            Err(e) if is_cross_device(&e) => {
+                              output::print_skipped(&storage_data.path, "different mount");
+                              break;
+                            }
+                            Ok(false) => break,
+                            result => result?,
+                          };
+                          stats.links_created += 1;
+                          groups
+                            .add_existing_link((storage_data.storage_uid, id), storage_data.path);
+                          if is_limit_reached(stats)? {
+                            return Ok(false);
+                          }
+                        }
+                        break;
+                      }
+                      FileEntry::Files(_, ref mut links) if !make_link => {
+                        links.insert(storage_data.path);
+                        break;
+                      }
+                      FileEntry::Files(..) => {
+                        unreachable!("Tried to create link to non-original file");
+                      }
+                    }
+                    let Entry::Occupied(new_entry) = storage.files.entry(id) else {
+                      unreachable!("Files will never point to invalid file id's")
+                    };
+                    current_entry = new_entry;
+                  }
+                  if let Some(path) = promoted {
+                    storage
+                      .files
+                      .insert(storage_data.file_id, FileEntry::OriginalFile(path));
+                  }
+                }
+                Entry::Vacant(entry) => {
+                  entry.insert(FileEntry::Files(
+                    storage_data.path.to_owned(),
+                    Default::default(),
+                  ));
+                  match storage.file_sizes.entry(storage_data.size) {
+                    // Every file that has the size of another could be a duplicate of it.
+                    Entry::Occupied(_) if args.estimate => {
+                      stats.saved_storage += storage_data.size;
+                    }
+                    Entry::Occupied(mut entry) => {
+                      if let Some(first_file) = entry.get_mut().take() {
+                        spawn_hash(&mut worker, stats, 0, first_file, None);
+                      }
+                      spawn_hash(&mut worker, stats, 0, storage_data, None);
+                    }
+                    Entry::Vacant(entry) => {
+                      entry.insert(Some(storage_data));
+                    }
+                  }
+                }
+              }
+            }
+          }
+        }
+      }
+      WorkerResult::NewPrefilterReceived(stage, file, Some(digest)) => {
+        stats.prefilters_hashed += 1;
+        let storage = known_files
+          .get_mut(&file.storage_key())
+          .expect("Always set by this point");
+        match storage.prefilters.entry((stage, file.size, digest)) {
+          Entry::Occupied(mut entry) => {
+            if let Some(first_file) = entry.get_mut().take() {
+              spawn_hash(&mut worker, stats, stage + 1, first_file, Some(digest));
+            }
+            spawn_hash(&mut worker, stats, stage + 1, file, Some(digest));
+          }
+          Entry::Vacant(entry) => {
+            entry.insert(Some(file));
+          }
+        }
+      }
+      WorkerResult::NewPrefilterReceived(_, _, None) => (),
+      WorkerResult::NewHashReceived(storage_key, file_id, (file_size, Some(digest))) => {
+        stats.files_hashed += 1;
+        stats.bytes_hashed += file_size;
+        let storage_uid = storage_key.0;
+        let storage = known_files
+          .get_mut(&storage_key)
+          .expect("Always set by this point");
+        let identical = match storage.hashes.get(&(file_size, digest)) {
+          Some(original_id) if args.paranoid => {
+            let (Some(FileEntry::OriginalFile(original_file)), Some(FileEntry::Files(new_file, _))) =
+              (storage.files.get(original_id), storage.files.get(&file_id))
+            else {
+              unreachable!("Hash targets are original files, and only files are hashed")
+            };
+            storage::verify_identical_with_context(original_file, new_file, file_size, storage_uid)
+              .await?
+          }
+          _ => true,
+        };
+        let is_same_root =
+          storage
+            .hashes
+            .get(&(file_size, digest))
+            .is_some_and(|original_id| {
+              let (
+                Some(FileEntry::OriginalFile(original_file)),
+                Some(FileEntry::Files(new_file, _)),
+              ) = (storage.files.get(original_id), storage.files.get(&file_id))
+              else {
+                unreachable!("Hash targets are original files, and only files are hashed")
+              };
+              is_same_root(&roots, original_file, new_file)
+            });
+        match storage.hashes.entry((file_size, digest)) {
+          // The file keeps waiting for an original, so that other links to it aren't merged either.
+          Entry::Occupied(_) if !identical || is_same_root => (),
+          Entry::Vacant(entry) => {
+            entry.insert(file_id);
+            let Some(FileEntry::Files(original, _)) = storage.files.remove(&file_id) else {
+              unreachable!("Got vacant hash of invalid file id");
+            };
+            storage
+              .files
+              .insert(file_id, FileEntry::OriginalFile(original));
+          }
+          Entry::Occupied(mut hash_entry) => {
+            let original_id = *hash_entry.get();
+            let FileEntry::Files(new_file, mut new_links) = storage
+              .files
+              .insert(file_id, FileEntry::LinkTo(original_id))
+              .expect("Only known file IDs are hashed")
+            else {
+              unreachable!("Only files are hashed, and only once")
+            };
+            stats.saved_storage += file_size;
+            if Keep::is_deferred() {
+              storage
+                .duplicates
+                .entry(original_id)
+                .or_insert_with(|| (file_size, digest, vec![]))
+                .2
+                .push(file_id);
+            } else {
+              let Some(FileEntry::OriginalFile(original_file)) = storage.files.get(&original_id)
+              else {
+                unreachable!("Hash targets are never converted to links")
+              };
+              new_links.insert(new_file);
+              journal::plan(
+                original_file,
+                new_links.iter().map(|path| &**path),
+                (file_size, digest),
+              )?;
+              if let Some(new_original) = merge_paths(
+                ((storage_uid, original_id), &original_file.clone()),
+                new_links,
+                (file_size, digest),
+                stats,
+                groups,
+                savings,
+              )
+              .await?
+              {
+                hash_entry.insert(file_id);
+                storage
+                  .files
+                  .insert(file_id, FileEntry::OriginalFile(new_original));
+              }
+              if is_limit_reached(stats)? {
+                return Ok(false);
+              }
+            }
+          }
+        }
+      }
+      WorkerResult::NewHashReceived(_, _, (_, None)) => (),
+    }
+    progress::update(stats);
+  }
+
+  let mut deferred = vec![];
+  for ((storage_uid, ..), storage) in known_files.iter_mut() {
+    for (original_id, (size, digest, duplicates)) in storage.duplicates.drain() {
+      let candidates = [original_id]
+        .into_iter()
+        .chain(duplicates)
+        .map(|id| Candidate {
+          id,
+          paths: storage.paths.remove(&id).unwrap_or_default(),
+        })
+        .collect::<Vec<_>>();
+      deferred.push(keep::Group {
+        storage_uid: *storage_uid,
+        size,
+        digest,
+        kept: keep::choose(&candidates).await?,
+        candidates,
+        apply: true,
+      });
+    }
+  }
+  #[cfg(feature = "tui")]
+  if args.tui && !deferred.is_empty() {
+    deferred = tokio::task::spawn_blocking(move || tui::browse(deferred)).await??;
+  }
+  // Every merge is planned before the first one is made, so that `--resume` can make the rest.
+  for group in &deferred {
+    let kept = group.candidates[group.kept].path();
+    let redundant = group
+      .candidates
+      .iter()
+      .filter(|candidate| deferred_skip_reason(&roots, group, kept, candidate).is_none())
+      .flat_map(|candidate| candidate.paths.iter().map(|path| &**path));
+    journal::plan(kept, redundant, (group.size, group.digest))?;
+  }
+  for group in deferred {
+    let (storage_uid, file_size) = (group.storage_uid, group.size);
+    let kept = &group.candidates[group.kept];
+    if is_small(&group) && group.apply {
+      output::print_info(format!(
+        "Not merging {} copies of {}, since they would only save {}",
+        group.candidates.len(),
+        kept.path().display(),
+        output::format_size(group.wasted())
+      ))?;
+    }
+    let (mut original_uid, mut original_file) = ((storage_uid, kept.id), kept.path().clone());
+    for candidate in group
+      .candidates
+      .iter()
+      .filter(|candidate| candidate.id != kept.id)
+    {
+      if let Some(reason) = deferred_skip_reason(&roots, &group, &original_file, candidate) {
+        output::print_skipped(candidate.path(), reason);
+        stats.saved_storage -= file_size;
+        continue;
+      }
+      if let Some(new_original) = merge_paths(
+        (original_uid, &original_file),
+        candidate.paths.iter().cloned(),
+        (file_size, group.digest),
+        stats,
+        groups,
+        savings,
+      )
+      .await?
+      {
+        (original_uid, original_file) = ((storage_uid, candidate.id), new_original);
+      }
+      if is_limit_reached(stats)? {
+        // The duplicates that are left were counted as saved when they were found.
+        stats.saved_storage = stats.reclaimed;
+        return Ok(false);
+      }
+    }
+  }
+
+  if args.cross_device {
+    let mut found = vec![];
+    for ((storage_uid, ..), storage) in &known_files {
+      let unhashed = storage
+        .file_sizes
+        .values()
+        .chain(storage.prefilters.values())
+        .flatten();
+      found.extend(unhashed.cloned().map(cross_device::Found::Unhashed));
+      for (&key, original_id) in &storage.hashes {
+        if let Some(FileEntry::OriginalFile(path)) = storage.files.get(original_id) {
+          found.push(cross_device::Found::Hashed(*storage_uid, path.clone(), key));
+        }
+      }
+    }
+    *cross_device_groups = cross_device::find(found, stats).await?;
+  }
+
+  if args.debug {
+    let debug = known_files
+      .into_values()
+      .flat_map(|x| x.files)
+      .collect::<HashMap<_, _>>();
+    println!("{debug:#?}");
+  }
+
+  Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn sizes_are_parsed_with_units() {
+    assert_eq!(parse_size("0").unwrap(), 0);
+    assert_eq!(parse_size("512").unwrap(), 512);
+    assert_eq!(parse_size("1b").unwrap(), 1);
+    assert_eq!(parse_size("4k").unwrap(), 4 << 10);
+    assert_eq!(parse_size("4KiB").unwrap(), 4 << 10);
+    assert_eq!(parse_size("10 MiB").unwrap(), 10 << 20);
+    assert_eq!(parse_size("2G").unwrap(), 2 << 30);
+    assert_eq!(parse_size("1TiB").unwrap(), 1 << 40);
+    assert_eq!(parse_size("3kB").unwrap(), 3_000);
+    assert_eq!(parse_size("5MB").unwrap(), 5_000_000);
+    assert_eq!(parse_size("1gb").unwrap(), 1_000_000_000);
+    assert_eq!(parse_size("1TB").unwrap(), 1_000_000_000_000);
+  }
+
+  #[test]
+  fn invalid_sizes_are_rejected() {
+    assert!(parse_size("").is_err());
+    assert!(parse_size("MiB").is_err());
+    assert!(parse_size("-1").is_err());
+    assert!(parse_size("1.5M").is_err());
+    assert!(parse_size("1 PiB").is_err());
+    assert!(parse_size("99999999999TiB").is_err());
+  }
+
+  #[test]
+  fn temporary_paths_have_a_suffix() {
+    let file = Path::new("dir").join("file.bin");
+    let suffix = random_suffix();
+    assert_eq!(suffix.len(), SUFFIX_LEN);
+    let path = temporary_path(&file, None, Some(&suffix));
+    assert_eq!(
+      path,
+      Path::new("dir").join(format!("file.bin.hard_link.{}", suffix.to_str().unwrap()))
+    );
+    assert_eq!(temporary_suffix(&path), Some(suffix.as_os_str()));
+    assert!(is_temporary(&path));
+    let rollback = temporary_path(&file, Some("rollback"), Some(&suffix));
+    assert_eq!(
+      rollback.file_name().unwrap(),
+      OsStr::new(&format!(
+        "file.bin.rollback.hard_link.{}",
+        suffix.to_str().unwrap()
+      ))
+    );
+    assert_eq!(temporary_suffix(&rollback), Some(suffix.as_os_str()));
+  }
+
+  #[test]
+  fn temporary_paths_of_older_versions_have_no_suffix() {
+    let path = temporary_path(Path::new("file.bin"), None, None);
+    assert_eq!(path, Path::new("file.bin.hard_link"));
+    assert_eq!(temporary_suffix(&path), None);
+    assert!(is_temporary(&path));
+  }
+
+  #[test]
+  fn other_paths_have_no_temporary_suffix() {
+    for path in [
+      "file.bin",
+      "file.hard_link.bin",
+      "file.bin.abcdef",
+      "file.bin.hard_link.ABCDEF",
+      "file.bin.hard_link.abcdefg",
+      "file.bin.other.abcdef",
+    ] {
+      assert_eq!(temporary_suffix(Path::new(path)), None, "{path}");
+    }
+    assert!(!is_temporary(Path::new("file.bin")));
+  }
+}
@@ -0,0 +1,66 @@
+use std::{fs::File, io, path::Path};
+
+/// An advisory lock on a file that is about to be replaced, which is released when dropped.
+pub struct FileLock(#[allow(dead_code)] File);
+
+/// Whether another process holds a POSIX record lock on any part of `file`, as databases such as
+/// SQLite do. Those are invisible to `flock` on Linux.
+#[cfg(unix)]
+fn has_record_lock(file: &File) -> io::Result<bool> {
+  use std::os::fd::AsRawFd;
+  // Unlike `F_GETLK`, this also sees the locks of other open files of this process.
+  #[cfg(target_os = "linux")]
+  const GETLK: libc::c_int = libc::F_OFD_GETLK;
+  #[cfg(not(target_os = "linux"))]
+  const GETLK: libc::c_int = libc::F_GETLK;
+  // SAFETY: `flock` is plain data, for which zeroes are valid.
+  let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+  lock.l_type = libc::F_WRLCK as _;
+  lock.l_whence = libc::SEEK_SET as _;
+  // A length of 0 covers the whole file, however large it grows.
+  (lock.l_start, lock.l_len) = (0, 0);
+  // SAFETY: The file descriptor stays open and `lock` outlives the call.
+  if unsafe { libc::fcntl(file.as_raw_fd(), GETLK, &mut lock) } != 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(lock.l_type as libc::c_int != libc::F_UNLCK)
+}
+
+/// Takes an exclusive `flock` on `path`, or returns `None` if another process holds a lock on it,
+/// either a `flock` or a record lock.
+#[cfg(unix)]
+pub fn try_lock(path: &Path) -> io::Result<Option<FileLock>> {
+  use std::os::fd::AsRawFd;
+  let file = File::open(path)?;
+  if has_record_lock(&file)? {
+    return Ok(None);
+  }
+  // SAFETY: The file descriptor stays open for the duration of the call.
+  if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+    let error = io::Error::last_os_error();
+    return match error.raw_os_error() {
+      Some(libc::EWOULDBLOCK) => Ok(None),
+      _ => Err(error),
+    };
+  }
+  Ok(Some(FileLock(file)))
+}
+
+/// Opens `path` without sharing write access, or returns `None` if another process has it open for
+/// writing. Deletion is shared, so that the file can still be replaced.
+#[cfg(windows)]
+pub fn try_lock(path: &Path) -> io::Result<Option<FileLock>> {
+  use std::{fs::OpenOptions, os::windows::fs::OpenOptionsExt};
+  const FILE_SHARE_READ: u32 = 0x1;
+  const FILE_SHARE_DELETE: u32 = 0x4;
+  const ERROR_SHARING_VIOLATION: i32 = 32;
+  match OpenOptions::new()
+    .read(true)
+    .share_mode(FILE_SHARE_READ | FILE_SHARE_DELETE)
+    .open(path)
+  {
+    Ok(file) => Ok(Some(FileLock(file))),
+    Err(e) if e.raw_os_error() == Some(ERROR_SHARING_VIOLATION) => Ok(None),
+    Err(e) => Err(e),
+  }
+}
@@ -0,0 +1,39 @@
+use std::{
+  fs::{File, OpenOptions},
+  io::{self, Write},
+  sync::{Mutex, OnceLock},
+  time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+
+use crate::DedupArgs;
+
+static LOG_FILE: OnceLock<Option<Mutex<File>>> = OnceLock::new();
+
+/// Opens the `--log-file`, if any. Called before the run starts so that a bad path fails early.
+pub fn init() -> Result<()> {
+  let args = DedupArgs::get();
+  let file = match args.log_file {
+    Some(ref path) => Some(Mutex::new(
+      OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Could not open log file {}", path.display()))?,
+    )),
+    None => None,
+  };
+  let _ = LOG_FILE.set(file);
+  Ok(())
+}
+
+/// Appends a timestamped line to the `--log-file`, if any.
+pub fn log(message: &str) -> io::Result<()> {
+  let Some(Some(file)) = LOG_FILE.get() else {
+    return Ok(());
+  };
+  let timestamp = humantime::format_rfc3339_seconds(SystemTime::now());
+  let mut file = file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+  writeln!(file, "{timestamp} {message}")
+}
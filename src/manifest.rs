@@ -0,0 +1,422 @@
+use std::{
+  collections::HashMap,
+  env,
+  fs::File,
+  io::{self, Write},
+  path::{Path, PathBuf},
+  sync::{Mutex, OnceLock},
+  time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+  hash::HashAlgorithm, input::bytes_to_path, output::path_bytes, report::hex,
+  storage::FileStorageData, DedupArgs, Filesize, HashDigest, HASH_LEN,
+};
+
+struct Manifest {
+  hashes: HashMap<PathBuf, HashDigest>,
+  /// The size and modification time of each file when it was hashed, from the `.stat` file next to
+  /// the manifest.
+  stats: HashMap<PathBuf, (Filesize, SystemTime)>,
+  /// Files without a recorded size and modification time that were modified after the manifest
+  /// was written may no longer match it.
+  written: SystemTime,
+}
+
+static MANIFEST: OnceLock<Option<Manifest>> = OnceLock::new();
+/// A file being written, which replaces the one at `path` once the run is complete.
+struct Replacement {
+  file: File,
+  path: PathBuf,
+  temporary_path: PathBuf,
+}
+
+impl Replacement {
+  fn create(path: &Path) -> Result<Self> {
+    let mut temporary_path = path.to_owned().into_os_string();
+    temporary_path.push(".tmp");
+    let temporary_path = PathBuf::from(temporary_path);
+    let file = File::create(&temporary_path).with_context(|| {
+      format!(
+        "Could not create checksum file {}",
+        temporary_path.display()
+      )
+    })?;
+    Ok(Replacement {
+      file,
+      path: path.to_owned(),
+      temporary_path,
+    })
+  }
+
+  fn finish(&self) -> Result<()> {
+    let replace = || -> io::Result<()> {
+      self.file.sync_all()?;
+      std::fs::rename(&self.temporary_path, &self.path)
+    };
+    replace().with_context(|| format!("Could not write checksum file {}", self.path.display()))
+  }
+}
+
+/// The checksum file being written, and the `.stat` file next to it.
+struct Emitted {
+  checksums: Replacement,
+  stats: Replacement,
+}
+
+static EMITTED: OnceLock<Option<Mutex<Emitted>>> = OnceLock::new();
+
+fn absolute(path: &Path) -> PathBuf {
+  if path.is_absolute() {
+    path.to_owned()
+  } else {
+    env::current_dir()
+      .map(|dir| dir.join(path))
+      .unwrap_or_else(|_| path.to_owned())
+  }
+}
+
+pub fn parse_hex(hex: &[u8]) -> Option<HashDigest> {
+  let mut digest = [0; HASH_LEN];
+  if hex.len() != HashAlgorithm::get().digest_len() * 2 {
+    return None;
+  }
+  for (byte, pair) in digest.iter_mut().zip(hex.chunks_exact(2)) {
+    *byte = u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok()?;
+  }
+  Some(digest)
+}
+
+fn unescape(path: &[u8]) -> Result<Vec<u8>> {
+  let mut unescaped = Vec::with_capacity(path.len());
+  let mut bytes = path.iter();
+  while let Some(&byte) = bytes.next() {
+    if byte == b'\\' {
+      match bytes.next() {
+        Some(b'\\') => unescaped.push(b'\\'),
+        Some(b'n') => unescaped.push(b'\n'),
+        _ => bail!("Invalid escape sequence"),
+      }
+    } else {
+      unescaped.push(byte);
+    }
+  }
+  Ok(unescaped)
+}
+
+/// Splits a line in the format written by `b3sum` and `sha256sum` into its first field and the
+/// path, separated by two spaces. Lines starting with a backslash have `\\` and `\n` escaped in the
+/// path.
+fn split_line(line: &[u8]) -> Result<(&[u8], PathBuf)> {
+  let (escaped, line) = match line.strip_prefix(b"\\") {
+    Some(line) => (true, line),
+    None => (false, line),
+  };
+  let Some(separator) = line.windows(2).position(|window| window == b"  ") else {
+    bail!("Missing separator");
+  };
+  let path = &line[separator + 2..];
+  let path = if escaped {
+    unescape(path)?
+  } else {
+    path.to_vec()
+  };
+  Ok((&line[..separator], absolute(&bytes_to_path(path)?)))
+}
+
+/// Parses a line of a checksum file: a hex digest, two spaces and the path.
+fn parse_line(line: &[u8]) -> Result<(PathBuf, HashDigest)> {
+  let (digest, path) = split_line(line)?;
+  let Some(digest) = parse_hex(digest) else {
+    bail!("Invalid digest");
+  };
+  Ok((path, digest))
+}
+
+/// Parses a line of a `.stat` file: the size and the modification time as seconds and nanoseconds
+/// since the epoch, two spaces and the path.
+fn parse_stat_line(line: &[u8]) -> Result<(PathBuf, (Filesize, SystemTime))> {
+  let (stat, path) = split_line(line)?;
+  let parse = || {
+    let (size, modified) = std::str::from_utf8(stat).ok()?.split_once(' ')?;
+    let (secs, nanos) = modified.split_once('.')?;
+    let modified = Duration::new(secs.parse().ok()?, nanos.parse().ok()?);
+    Some((size.parse().ok()?, UNIX_EPOCH.checked_add(modified)?))
+  };
+  let Some(stat) = parse() else {
+    bail!("Invalid size or modification time");
+  };
+  Ok((path, stat))
+}
+
+fn read_lines<T>(
+  path: &Path,
+  parse: impl Fn(&[u8]) -> Result<(PathBuf, T)>,
+) -> Result<HashMap<PathBuf, T>> {
+  let content = std::fs::read(path)?;
+  let mut entries = HashMap::new();
+  for (line_number, line) in content.split(|byte| *byte == b'\n').enumerate() {
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    if line.is_empty() {
+      continue;
+    }
+    let (path, entry) = parse(line).with_context(|| format!("Invalid line {}", line_number + 1))?;
+    entries.insert(path, entry);
+  }
+  Ok(entries)
+}
+
+/// The `.stat` file next to the checksum file at `path`.
+fn stat_path(path: &Path) -> PathBuf {
+  let mut stat_path = path.to_owned().into_os_string();
+  stat_path.push(".stat");
+  stat_path.into()
+}
+
+fn read_manifest(path: &Path) -> Result<Manifest> {
+  let written = std::fs::metadata(path)?.modified()?;
+  let hashes = read_lines(path, parse_line)?;
+  // Checksum files written by other tools have no `.stat` file.
+  let stat_path = stat_path(path);
+  let stats = match read_lines(&stat_path, parse_stat_line) {
+    Ok(stats) => stats,
+    Err(e)
+      if e
+        .downcast_ref::<io::Error>()
+        .is_some_and(|e| e.kind() == io::ErrorKind::NotFound) =>
+    {
+      HashMap::new()
+    }
+    Err(e) => return Err(e).with_context(|| format!("Could not read {}", stat_path.display())),
+  };
+  Ok(Manifest {
+    hashes,
+    stats,
+    written,
+  })
+}
+
+/// Formats a line like `b3sum` does, which is the format that `split_line` reads.
+fn format_entry(field: &str, path: &Path) -> Vec<u8> {
+  let path = path_bytes(path);
+  let escaped = path.contains(&b'\\') || path.contains(&b'\n');
+  let mut line = Vec::with_capacity(path.len() + field.len() + 4);
+  if escaped {
+    line.push(b'\\');
+  }
+  line.extend_from_slice(field.as_bytes());
+  line.extend_from_slice(b"  ");
+  for &byte in path.iter() {
+    match byte {
+      b'\\' if escaped => line.extend_from_slice(b"\\\\"),
+      b'\n' => line.extend_from_slice(b"\\n"),
+      byte => line.push(byte),
+    }
+  }
+  line.push(b'\n');
+  line
+}
+
+fn format_line(path: &Path, digest: &HashDigest) -> Vec<u8> {
+  format_entry(&hex(digest), path)
+}
+
+/// The line of the `.stat` file for `file`, or `None` if its modification time is unknown.
+fn format_stat_line(file: &FileStorageData) -> Option<Vec<u8>> {
+  let modified = file.modified?.duration_since(UNIX_EPOCH).ok()?;
+  let stat = format!(
+    "{} {}.{:09}",
+    file.size,
+    modified.as_secs(),
+    modified.subsec_nanos()
+  );
+  Some(format_entry(&stat, &file.path))
+}
+
+/// Loads the `--hashes-from` manifest and starts writing the `--emit-checksums` file, if any. A
+/// `--checksums` file is both, and read before it's replaced.
+pub fn init() -> Result<()> {
+  let args = DedupArgs::get();
+  let imported = args
+    .hashes_from
+    .as_ref()
+    .or(args.checksums.as_ref().filter(|path| path.exists()));
+  let manifest = match imported {
+    Some(path) => Some(
+      read_manifest(path)
+        .with_context(|| format!("Could not read hashes from {}", path.display()))?,
+    ),
+    None => None,
+  };
+  let _ = MANIFEST.set(manifest);
+  let emitted = match args.emit_checksums.as_ref().or(args.checksums.as_ref()) {
+    Some(path) => Some(Mutex::new(Emitted {
+      checksums: Replacement::create(path)?,
+      stats: Replacement::create(&stat_path(path))?,
+    })),
+    None => None,
+  };
+  let _ = EMITTED.set(emitted);
+  Ok(())
+}
+
+/// Appends the digest of `file` to the `--emit-checksums` file, if any, and its size and
+/// modification time to the `.stat` file next to it.
+pub fn emit(file: &FileStorageData, digest: &HashDigest) -> io::Result<()> {
+  let Some(Some(emitted)) = EMITTED.get() else {
+    return Ok(());
+  };
+  let line = format_line(&file.path, digest);
+  let mut emitted = emitted
+    .lock()
+    .unwrap_or_else(|poisoned| poisoned.into_inner());
+  emitted.checksums.file.write_all(&line)?;
+  if let Some(line) = format_stat_line(file) {
+    emitted.stats.file.write_all(&line)?;
+  }
+  Ok(())
+}
+
+/// Replaces the `--emit-checksums` file with the one written by a complete run. An incomplete run
+/// leaves the previous one as it was.
+pub fn finish() -> Result<()> {
+  let Some(Some(emitted)) = EMITTED.get() else {
+    return Ok(());
+  };
+  let emitted = emitted
+    .lock()
+    .unwrap_or_else(|poisoned| poisoned.into_inner());
+  // The checksums are trusted without the `.stat` file, so it's replaced first.
+  emitted.stats.finish()?;
+  emitted.checksums.finish()
+}
+
+/// Whether `file` may have changed since it was hashed for the manifest.
+fn is_modified(manifest: &Manifest, path: &Path, file: &FileStorageData) -> bool {
+  match manifest.stats.get(path) {
+    Some(&(size, modified)) => file.size != size || file.modified != Some(modified),
+    None => file
+      .modified
+      .map_or(true, |modified| modified > manifest.written),
+  }
+}
+
+/// The imported digest of `file`, unless its size or modification time differs from when it was
+/// hashed. Without a recorded size and modification time, it's only trusted if the file wasn't
+/// modified after the manifest was written.
+pub fn get(file: &FileStorageData) -> Option<HashDigest> {
+  let manifest = MANIFEST.get()?.as_ref()?;
+  let path = absolute(&file.path);
+  if is_modified(manifest, &path, file) {
+    return None;
+  }
+  manifest.hashes.get(&path).copied()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn test_file(path: PathBuf, size: Filesize) -> FileStorageData {
+    FileStorageData {
+      path: path.into(),
+      size,
+      storage_uid: 0,
+      file_id: 0,
+      link_count: 1,
+      allocated: size,
+      modified: None,
+      #[cfg(unix)]
+      owner: 0,
+      access: None,
+      xattrs: None,
+      acl: None,
+    }
+  }
+
+  fn round_trip(name: &str) {
+    let path = env::temp_dir().join(name);
+    let digest: HashDigest = std::array::from_fn(|i| i as u8);
+    let line = format_line(&path, &digest);
+    let line = line.strip_suffix(b"\n").expect("Lines end with a newline");
+    let (parsed_path, parsed_digest) = parse_line(line).unwrap();
+    assert_eq!(parsed_path, path);
+    assert_eq!(parsed_digest, digest);
+  }
+
+  #[test]
+  fn plain_line_round_trips() {
+    round_trip("plain file.bin");
+    round_trip("two  spaces");
+  }
+
+  #[test]
+  fn escaped_line_round_trips() {
+    round_trip("new\nline");
+    #[cfg(unix)]
+    round_trip("back\\slash");
+  }
+
+  #[test]
+  fn line_is_sha256sum_compatible() {
+    let line = format_line(Path::new("/file"), &[0xab; HASH_LEN]);
+    assert_eq!(
+      line,
+      format!("{}  /file\n", "ab".repeat(HASH_LEN)).into_bytes()
+    );
+    let line = format_line(Path::new("/a\nb"), &[0; HASH_LEN]);
+    assert_eq!(
+      line,
+      format!("\\{}  /a\\nb\n", "00".repeat(HASH_LEN)).into_bytes()
+    );
+  }
+
+  #[test]
+  fn stat_line_round_trips() {
+    let modified = UNIX_EPOCH + Duration::new(1_700_000_000, 123);
+    let file = FileStorageData {
+      modified: Some(modified),
+      ..test_file(env::temp_dir().join("stat"), 2_000_000)
+    };
+    let line = format_stat_line(&file).unwrap();
+    assert!(line.starts_with(b"2000000 1700000000.000000123  "));
+    let (path, stat) = parse_stat_line(line.strip_suffix(b"\n").unwrap()).unwrap();
+    assert_eq!(path, *file.path);
+    assert_eq!(stat, (2_000_000, modified));
+    assert!(parse_stat_line(b"2000000  /file").is_err());
+  }
+
+  #[test]
+  fn digests_of_changed_files_are_not_reused() {
+    let (path, modified) = (
+      PathBuf::from("/file"),
+      UNIX_EPOCH + Duration::from_secs(100),
+    );
+    let mut manifest = Manifest {
+      hashes: HashMap::new(),
+      stats: HashMap::from([(path.clone(), (1024, modified))]),
+      written: UNIX_EPOCH + Duration::from_secs(200),
+    };
+    let file = |size, secs| FileStorageData {
+      modified: Some(UNIX_EPOCH + Duration::from_secs(secs)),
+      ..test_file(path.clone(), size)
+    };
+    assert!(!is_modified(&manifest, &path, &file(1024, 100)));
+    // Replaced by content with an older modification time, or another size.
+    assert!(is_modified(&manifest, &path, &file(1024, 50)));
+    assert!(is_modified(&manifest, &path, &file(2048, 100)));
+    // Without a `.stat` line, only the time the manifest was written is known.
+    manifest.stats.clear();
+    assert!(!is_modified(&manifest, &path, &file(2048, 150)));
+    assert!(is_modified(&manifest, &path, &file(1024, 250)));
+  }
+
+  #[test]
+  fn invalid_lines_are_rejected() {
+    assert!(parse_line(b"abcd /file").is_err());
+    assert!(parse_line(b"xyz  /file").is_err());
+    assert!(parse_line(format!("{}  /file", "ab".repeat(HASH_LEN - 1)).as_bytes()).is_err());
+  }
+}
@@ -0,0 +1,66 @@
+use std::{path::Path, sync::OnceLock, time::Duration};
+
+use crate::Filesize;
+
+/// Something that happened during a run.
+#[derive(Debug, Clone, Copy)]
+#[non_exhaustive]
+pub enum Event<'a> {
+  /// A directory is about to be scanned.
+  DirScanned {
+    path: &'a Path,
+  },
+  /// A file was found that passed the filters.
+  FileScanned {
+    path: &'a Path,
+    size: Filesize,
+  },
+  HashStarted {
+    path: &'a Path,
+    size: Filesize,
+  },
+  HashFinished {
+    path: &'a Path,
+    size: Filesize,
+    elapsed: Duration,
+  },
+  /// `redundant` was merged into `original`, or would have been without `--dry-run`.
+  Merged {
+    original: &'a Path,
+    redundant: &'a Path,
+  },
+  /// A file or directory was left out of the dedup.
+  Skipped {
+    path: &'a Path,
+    reason: &'a str,
+  },
+  /// A non-fatal error, which doesn't stop the run.
+  Error {
+    message: &'a str,
+  },
+}
+
+/// Receives the events of a run, e.g. to render progress. Closures taking an `&Event` are
+/// observers as well.
+pub trait Observer: Send + Sync {
+  fn event(&self, event: &Event<'_>);
+}
+
+impl<F: Fn(&Event<'_>) + Send + Sync> Observer for F {
+  fn event(&self, event: &Event<'_>) {
+    self(event)
+  }
+}
+
+static OBSERVER: OnceLock<Option<Box<dyn Observer>>> = OnceLock::new();
+
+pub fn init(observer: Option<Box<dyn Observer>>) {
+  let _ = OBSERVER.set(observer);
+}
+
+/// Passes `event` to the observer, if there is one.
+pub fn notify(event: Event<'_>) {
+  if let Some(observer) = OBSERVER.get().and_then(Option::as_ref) {
+    observer.event(&event);
+  }
+}
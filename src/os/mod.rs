@@ -1,36 +1,38 @@
 use async_trait::async_trait;
-use std::{hash::Hash, io::Result, path::Path};
+use std::{
+  collections::HashMap,
+  hash::Hash,
+  io::Result,
+  path::{Path, PathBuf},
+  sync::OnceLock,
+};
 use tokio::fs::DirEntry;
 
 #[cfg(unix)]
 mod unix;
-#[cfg(unix)]
-pub use unix::*;
 #[cfg(windows)]
 #[cfg(feature = "stable")]
 mod windows;
 #[cfg(windows)]
-#[cfg(feature = "stable")]
-pub use self::windows::*;
-#[cfg(windows)]
 #[cfg(not(feature = "stable"))]
 mod windows_unstable;
-#[cfg(windows)]
-#[cfg(not(feature = "stable"))]
-pub use self::windows_unstable::*;
 
 pub trait FileLinkBackend {
   type StorageUid: Eq + Send + Hash;
   type FileId: Eq + Send + Hash;
   fn get_storage_uid(&self) -> Self::StorageUid;
   fn get_file_id(&self) -> Self::FileId;
+  /// The number of hard links to the file.
+  fn get_link_count(&self) -> u64;
   fn get_file_uid(&self) -> (Self::StorageUid, Self::FileId) {
     (self.get_storage_uid(), self.get_file_id())
   }
+  #[allow(dead_code)]
   fn same_storage(&self, other: &Self) -> bool {
     let (file1_uid, file2_uid) = (self.get_storage_uid(), other.get_storage_uid());
     file1_uid == file2_uid
   }
+  #[allow(dead_code)]
   fn same_file(&self, other: &Self) -> bool {
     let (file1_storage, file2_storage, file1_uid, file2_uid) = (
       self.get_storage_uid(),
@@ -51,10 +53,138 @@ where
   async fn link_metadata(self) -> Result<Self::Metadata>;
 }
 
+/// The storage that each `--same-storage` alias is the same as.
+static STORAGE_ALIASES: OnceLock<HashMap<StorageUid, StorageUid>> = OnceLock::new();
+
+/// Makes every storage in `aliases` report the ID it's mapped to.
+pub fn set_storage_aliases(aliases: HashMap<StorageUid, StorageUid>) {
+  let _ = STORAGE_ALIASES.set(aliases);
+}
+
+/// The ID that `storage_uid` is known by, which is its own unless it's an alias of another.
+pub fn resolve_storage_alias(storage_uid: StorageUid) -> StorageUid {
+  STORAGE_ALIASES
+    .get()
+    .and_then(|aliases| aliases.get(&storage_uid))
+    .copied()
+    .unwrap_or(storage_uid)
+}
+
 pub async fn read_link_metadata<'a>(from: impl AsRef<Path> + 'a) -> Result<CurrentFileLinkBackend> {
+  let _handle = crate::fd_limit::acquire().await;
   from.as_ref().link_metadata().await
 }
 
+/// Converts `path` to the extended-length `\\?\` form on Windows, which isn't limited to
+/// `MAX_PATH`. Such paths are passed to the file system as is, so they are made absolute and `.`
+/// and `..` are resolved first.
+#[cfg(windows)]
+pub fn extended_path(path: &Path) -> Result<PathBuf> {
+  use std::{
+    ffi::OsString,
+    path::{Component, Prefix},
+  };
+  let path = std::env::current_dir()?.join(path);
+  let mut components = path.components();
+  let mut extended = match components.next() {
+    Some(Component::Prefix(prefix)) => match prefix.kind() {
+      Prefix::Disk(_) => {
+        let mut extended = OsString::from(r"\\?\");
+        extended.push(prefix.as_os_str());
+        extended
+      }
+      Prefix::UNC(server, share) => {
+        let mut extended = OsString::from(r"\\?\UNC\");
+        extended.push(server);
+        extended.push(r"\");
+        extended.push(share);
+        extended
+      }
+      // Verbatim and device paths are already passed as is.
+      _ => return Ok(path),
+    },
+    _ => return Ok(path),
+  };
+  let mut names = vec![];
+  for component in components {
+    match component {
+      Component::Normal(name) => names.push(name),
+      Component::ParentDir => {
+        names.pop();
+      }
+      Component::Prefix(_) | Component::RootDir | Component::CurDir => (),
+    }
+  }
+  if names.is_empty() {
+    extended.push(r"\");
+  }
+  for name in names {
+    extended.push(r"\");
+    extended.push(name);
+  }
+  Ok(extended.into())
+}
+
+/// The storage allocated for a file, which is less than its size for sparse and compressed files.
+#[cfg(unix)]
+pub fn allocated_size(_path: &Path, metadata: &std::fs::Metadata) -> u64 {
+  use std::os::unix::fs::MetadataExt;
+  // `st_blocks` is in 512 byte units, whatever the block size of the file system.
+  metadata.blocks() * 512
+}
+
+/// The storage allocated for a file, which is less than its size for sparse and compressed files.
+#[cfg(all(windows, feature = "stable"))]
+pub fn allocated_size(path: &Path, metadata: &std::fs::Metadata) -> u64 {
+  use ::windows::{core::PCWSTR, Win32::Storage::FileSystem::GetCompressedFileSizeW};
+  use std::{iter::once, os::windows::ffi::OsStrExt};
+  let wide = path
+    .as_os_str()
+    .encode_wide()
+    .chain(once(0))
+    .collect::<Vec<_>>();
+  let mut high = 0;
+  let low = unsafe { GetCompressedFileSizeW(PCWSTR(wide.as_ptr()), Some(&mut high)) };
+  // `INVALID_FILE_SIZE`, which may also be the low half of a valid size.
+  if low == u32::MAX {
+    return metadata.len();
+  }
+  (u64::from(high) << 32) | u64::from(low)
+}
+
+/// Sparse and compressed files can't be told apart without the `stable` Windows API.
+#[cfg(all(windows, not(feature = "stable")))]
+pub fn allocated_size(_path: &Path, metadata: &std::fs::Metadata) -> u64 {
+  metadata.len()
+}
+
+/// Whether a file named `name` is hidden, which is a dotfile everywhere and also one with the
+/// hidden or system attribute on Windows.
+pub fn is_hidden(
+  name: &std::ffi::OsStr,
+  #[cfg_attr(not(windows), allow(unused_variables))] metadata: &std::fs::Metadata,
+) -> bool {
+  if name.to_string_lossy().starts_with('.') {
+    return true;
+  }
+  #[cfg(windows)]
+  {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+    const FILE_ATTRIBUTE_SYSTEM: u32 = 0x4;
+    if metadata.file_attributes() & (FILE_ATTRIBUTE_HIDDEN | FILE_ATTRIBUTE_SYSTEM) != 0 {
+      return true;
+    }
+  }
+  false
+}
+
+/// Paths aren't length limited outside of Windows.
+#[cfg(not(windows))]
+pub fn extended_path(path: &Path) -> Result<PathBuf> {
+  Ok(path.to_owned())
+}
+
 pub type CurrentFileLinkBackend = <&'static DirEntry as FileBackend>::Metadata;
 pub type StorageUid = <CurrentFileLinkBackend as FileLinkBackend>::StorageUid;
 pub type FileId = <CurrentFileLinkBackend as FileLinkBackend>::FileId;
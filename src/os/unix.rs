@@ -36,10 +36,14 @@ impl FileLinkBackend for Metadata {
   type FileId = u64;
 
   fn get_storage_uid(&self) -> Self::StorageUid {
-    self.dev()
+    super::resolve_storage_alias(self.dev())
   }
 
   fn get_file_id(&self) -> Self::FileId {
     self.ino()
   }
+
+  fn get_link_count(&self) -> u64 {
+    self.nlink()
+  }
 }
@@ -1,20 +1,32 @@
 use super::{FileBackend, FileLinkBackend};
 use async_trait::async_trait;
 use std::{
-  fs::File,
+  fs::OpenOptions,
   io::{Error, Result},
-  os::windows::io::AsRawHandle,
+  os::windows::{fs::OpenOptionsExt, io::AsRawHandle},
   path::Path,
 };
 use tokio::fs;
 use windows::Win32::{
   Foundation::HANDLE,
-  Storage::FileSystem::{GetFileInformationByHandle, BY_HANDLE_FILE_INFORMATION},
+  Storage::FileSystem::{
+    FileIdInfo, GetFileInformationByHandle, GetFileInformationByHandleEx,
+    BY_HANDLE_FILE_INFORMATION, FILE_FLAG_BACKUP_SEMANTICS, FILE_ID_INFO, FILE_READ_ATTRIBUTES,
+  },
 };
 
+/// The 64-bit volume serial number and 128-bit file ID of a file. File indices of
+/// `BY_HANDLE_FILE_INFORMATION` are only used where `FILE_ID_INFO` is not supported, as they are
+/// not unique on ReFS.
+pub struct LinkMetadata {
+  storage: u64,
+  file: u128,
+  links: u32,
+}
+
 #[async_trait]
 impl FileBackend for &fs::DirEntry {
-  type Metadata = BY_HANDLE_FILE_INFORMATION;
+  type Metadata = LinkMetadata;
 
   async fn link_metadata(self) -> Result<Self::Metadata> {
     Ok(self.path().link_metadata().await?)
@@ -23,31 +35,60 @@ impl FileBackend for &fs::DirEntry {
 
 #[async_trait]
 impl FileBackend for &Path {
-  type Metadata = BY_HANDLE_FILE_INFORMATION;
+  type Metadata = LinkMetadata;
 
   async fn link_metadata(self) -> Result<Self::Metadata> {
-    let file = File::open(self)?;
+    // Backup semantics are required to open directories, which are read when following symlinks.
+    let file = OpenOptions::new()
+      .access_mode(FILE_READ_ATTRIBUTES.0)
+      .custom_flags(FILE_FLAG_BACKUP_SEMANTICS.0)
+      .open(self)?;
+    let handle = HANDLE(file.as_raw_handle() as isize);
     let mut info = BY_HANDLE_FILE_INFORMATION::default();
     let info_ptr: *mut BY_HANDLE_FILE_INFORMATION = &mut info;
-    let handle = HANDLE(file.as_raw_handle() as isize);
-    if unsafe { GetFileInformationByHandle(handle, info_ptr).as_bool() } {
-      Ok(info)
-    } else {
+    if !unsafe { GetFileInformationByHandle(handle, info_ptr).as_bool() } {
       return Err(Error::last_os_error())?;
     }
+    let mut id_info = FILE_ID_INFO::default();
+    let id_info_ptr: *mut FILE_ID_INFO = &mut id_info;
+    if unsafe {
+      GetFileInformationByHandleEx(
+        handle,
+        FileIdInfo,
+        id_info_ptr.cast(),
+        std::mem::size_of::<FILE_ID_INFO>() as u32,
+      )
+      .as_bool()
+    } {
+      Ok(LinkMetadata {
+        storage: id_info.VolumeSerialNumber,
+        file: u128::from_le_bytes(id_info.FileId.Identifier),
+        links: info.nNumberOfLinks,
+      })
+    } else {
+      Ok(LinkMetadata {
+        storage: info.dwVolumeSerialNumber.into(),
+        file: (info.nFileIndexHigh as u128) << 32 | (info.nFileIndexLow as u128),
+        links: info.nNumberOfLinks,
+      })
+    }
   }
 }
 
-impl FileLinkBackend for BY_HANDLE_FILE_INFORMATION {
-  type StorageUid = u32;
+impl FileLinkBackend for LinkMetadata {
+  type StorageUid = u64;
 
-  type FileId = u64;
+  type FileId = u128;
 
   fn get_storage_uid(&self) -> Self::StorageUid {
-    self.dwVolumeSerialNumber
+    super::resolve_storage_alias(self.storage)
   }
 
   fn get_file_id(&self) -> Self::FileId {
-    (self.nFileIndexHigh as u64) << 32 | (self.nFileIndexLow as u64)
+    self.file
+  }
+
+  fn get_link_count(&self) -> u64 {
+    self.links.into()
   }
 }
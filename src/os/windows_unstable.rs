@@ -1,28 +1,48 @@
 use super::{FileBackend, FileLinkBackend};
 use async_trait::async_trait;
 use std::{
-  fs::Metadata,
+  ffi::c_void,
+  fs::OpenOptions,
   io::{Error, ErrorKind, Result},
-  os::windows::fs::MetadataExt,
+  os::windows::{
+    fs::{MetadataExt, OpenOptionsExt},
+    io::AsRawHandle,
+  },
   path::Path,
 };
 use tokio::fs;
 
-pub struct LinkMetadata {
-  storage: u32,
-  file: u64,
+/// `FILE_READ_ATTRIBUTES`
+const READ_ATTRIBUTES: u32 = 0x80;
+/// `FILE_FLAG_BACKUP_SEMANTICS`
+const BACKUP_SEMANTICS: u32 = 0x0200_0000;
+/// `FileIdInfo` of `FILE_INFO_BY_HANDLE_CLASS`
+const FILE_ID_INFO_CLASS: i32 = 18;
+
+/// `FILE_ID_INFO`
+#[repr(C)]
+#[derive(Default)]
+struct FileIdInfo {
+  volume_serial_number: u64,
+  file_id: [u8; 16],
 }
 
-impl TryFrom<Metadata> for LinkMetadata {
-  type Error = Error;
+#[link(name = "kernel32")]
+extern "system" {
+  fn GetFileInformationByHandleEx(
+    file: isize,
+    information_class: i32,
+    information: *mut c_void,
+    buffer_size: u32,
+  ) -> i32;
+}
 
-  fn try_from(metadata: Metadata) -> Result<LinkMetadata> {
-    let (Some(storage), Some(file)) = (metadata.volume_serial_number(), metadata.file_index())
-    else {
-      return Err(Error::new(ErrorKind::NotFound, "File metadata not found"));
-    };
-    Ok(LinkMetadata { storage, file })
-  }
+/// The 64-bit volume serial number and 128-bit file ID of a file. File indices of the metadata are
+/// only used where `FILE_ID_INFO` is not supported, as they are not unique on ReFS.
+pub struct LinkMetadata {
+  storage: u64,
+  file: u128,
+  links: u32,
 }
 
 #[async_trait]
@@ -30,7 +50,7 @@ impl FileBackend for &fs::DirEntry {
   type Metadata = LinkMetadata;
 
   async fn link_metadata(self) -> Result<Self::Metadata> {
-    Ok(self.metadata().await?.try_into()?)
+    Ok(self.path().link_metadata().await?)
   }
 }
 
@@ -39,20 +59,59 @@ impl FileBackend for &Path {
   type Metadata = LinkMetadata;
 
   async fn link_metadata(self) -> Result<Self::Metadata> {
-    Ok(fs::metadata(self).await?.try_into()?)
+    // Backup semantics are required to open directories, which are read when following symlinks.
+    let file = OpenOptions::new()
+      .access_mode(READ_ATTRIBUTES)
+      .custom_flags(BACKUP_SEMANTICS)
+      .open(self)?;
+    let metadata = file.metadata()?;
+    let (Some(storage), Some(file_index), Some(links)) = (
+      metadata.volume_serial_number(),
+      metadata.file_index(),
+      metadata.number_of_links(),
+    ) else {
+      return Err(Error::new(ErrorKind::NotFound, "File metadata not found"));
+    };
+    let mut id_info = FileIdInfo::default();
+    let id_info_ptr: *mut FileIdInfo = &mut id_info;
+    if unsafe {
+      GetFileInformationByHandleEx(
+        file.as_raw_handle() as isize,
+        FILE_ID_INFO_CLASS,
+        id_info_ptr.cast(),
+        std::mem::size_of::<FileIdInfo>() as u32,
+      )
+    } != 0
+    {
+      Ok(LinkMetadata {
+        storage: id_info.volume_serial_number,
+        file: u128::from_le_bytes(id_info.file_id),
+        links,
+      })
+    } else {
+      Ok(LinkMetadata {
+        storage: storage.into(),
+        file: file_index.into(),
+        links,
+      })
+    }
   }
 }
 
 impl FileLinkBackend for LinkMetadata {
-  type StorageUid = u32;
+  type StorageUid = u64;
 
-  type FileId = u64;
+  type FileId = u128;
 
   fn get_storage_uid(&self) -> Self::StorageUid {
-    self.storage
+    super::resolve_storage_alias(self.storage)
   }
 
   fn get_file_id(&self) -> Self::FileId {
     self.file
   }
+
+  fn get_link_count(&self) -> u64 {
+    self.links.into()
+  }
 }
@@ -0,0 +1,157 @@
+use std::{
+  borrow::Cow,
+  fmt::Display,
+  io::{self, Write},
+  path::Path,
+  sync::atomic::{AtomicUsize, Ordering},
+};
+
+use clap::ValueEnum;
+
+use crate::{
+  log_file,
+  observer::{self, Event},
+  progress, DedupArgs, Filesize,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+  /// Human readable lines for every merge, followed by a summary.
+  Text,
+  /// A JSON document with every duplicate group and the summary.
+  Json,
+  /// One comma separated row per merge.
+  Csv,
+  /// One tab separated row per merge.
+  Tsv,
+  /// Duplicate groups separated by blank lines, compatible with `fdupes`.
+  Fdupes,
+  /// The JSON written by `rmlint --output json`, which `rmlint --replay` and its GUI read.
+  RmlintJson,
+  /// The JSON written by `jdupes --json`.
+  Jdupes,
+}
+
+#[cfg(unix)]
+pub fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+  use std::os::unix::ffi::OsStrExt;
+  Cow::Borrowed(path.as_os_str().as_bytes())
+}
+
+#[cfg(windows)]
+pub fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+  match path.to_string_lossy() {
+    Cow::Borrowed(path) => Cow::Borrowed(path.as_bytes()),
+    Cow::Owned(path) => Cow::Owned(path.into_bytes()),
+  }
+}
+
+/// Formats `size` in the largest unit that it has at least one of, in powers of 1024 or with
+/// `--si` in powers of 1000.
+pub fn format_size(size: Filesize) -> String {
+  let (base, units) = if DedupArgs::get().si {
+    (1000.0, ["B", "kB", "MB", "GB", "TB", "PB"])
+  } else {
+    (1024.0, ["B", "KiB", "MiB", "GiB", "TiB", "PiB"])
+  };
+  let (mut value, mut unit) = (size as f64, 0);
+  while value >= base && unit < units.len() - 1 {
+    value /= base;
+    unit += 1;
+  }
+  if unit == 0 {
+    format!("{size} B")
+  } else {
+    format!("{value:.1} {}", units[unit])
+  }
+}
+
+/// Prints a performed (or planned) merge of a file of `size` bytes. With `--null` the original and
+/// redundant paths are written as two NUL terminated records instead.
+pub fn print_merge(original: &Path, redundant: &Path, size: Filesize) -> io::Result<()> {
+  let args = DedupArgs::get();
+  let sign = if args.dry_run { '↫' } else { '⇐' };
+  let line = format!(
+    "{original} {sign} {redundant} ({size})",
+    original = original.display(),
+    redundant = redundant.display(),
+    size = format_size(size)
+  );
+  let _ = log_file::log(&line);
+  progress::suspend(|| {
+    let mut stdout = io::stdout().lock();
+    if args.quiet || args.report || !args.prints_text() {
+      Ok(())
+    } else if args.null {
+      stdout.write_all(&path_bytes(original))?;
+      stdout.write_all(b"\0")?;
+      stdout.write_all(&path_bytes(redundant))?;
+      stdout.write_all(b"\0")
+    } else {
+      writeln!(stdout, "{line}")
+    }
+  })
+}
+
+fn print_line(message: &str) -> io::Result<()> {
+  let args = DedupArgs::get();
+  progress::suspend(|| {
+    if args.null || !args.prints_text() {
+      writeln!(io::stderr().lock(), "{message}")
+    } else {
+      writeln!(io::stdout().lock(), "{message}")
+    }
+  })
+}
+
+/// Prints an informational line, which is moved to stderr with `--null` or non-text formats to
+/// keep stdout parsable. Suppressed by `--quiet`.
+pub fn print_info(message: impl AsRef<str>) -> io::Result<()> {
+  let _ = log_file::log(message.as_ref());
+  if DedupArgs::get().quiet {
+    return Ok(());
+  }
+  print_line(message.as_ref())
+}
+
+/// Prints a line of the final summary, which is never suppressed by `--quiet`. Non-text formats
+/// only log it.
+pub fn print_summary(message: impl AsRef<str>) -> io::Result<()> {
+  if !message.as_ref().is_empty() {
+    let _ = log_file::log(message.as_ref());
+  }
+  if !DedupArgs::get().prints_text() {
+    return Ok(());
+  }
+  print_line(message.as_ref())
+}
+
+/// Prints a diagnostic line to stderr if the verbosity is at least `level`.
+pub fn print_verbose(level: u8, message: impl Display) {
+  if DedupArgs::get().verbose >= level {
+    progress::suspend(|| eprintln!("{message}"));
+  }
+}
+
+/// Prints why a file or directory was left out of the dedup, with `--verbose`.
+pub fn print_skipped(path: &Path, reason: &str) {
+  observer::notify(Event::Skipped { path, reason });
+  print_verbose(1, format_args!("Skipping {} ({reason})", path.display()));
+}
+
+static ERROR_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Prints a non-fatal error to stderr.
+pub fn print_error(error: impl Display) {
+  ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
+  observer::notify(Event::Error {
+    message: &error.to_string(),
+  });
+  let _ = log_file::log(&format!("error: {error}"));
+  progress::suspend(|| eprintln!("{error}"));
+}
+
+/// The number of non-fatal errors printed so far.
+pub fn error_count() -> usize {
+  ERROR_COUNT.load(Ordering::Relaxed)
+}
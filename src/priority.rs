@@ -0,0 +1,71 @@
+use std::io;
+
+use anyhow::{Context, Result};
+
+use crate::DedupArgs;
+
+/// The lowest CPU priority, i.e. the highest nice value.
+#[cfg(unix)]
+const IDLE_NICE: libc::c_int = 19;
+
+/// On Linux both priorities belong to threads, so every thread that exists is changed. Threads
+/// started after this inherit the priorities of the thread starting them.
+#[cfg(target_os = "linux")]
+fn set_idle_priority() -> io::Result<()> {
+  const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+  const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+  const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+  for entry in std::fs::read_dir("/proc/self/task")? {
+    let Ok(thread_id) = entry?.file_name().to_string_lossy().parse::<libc::pid_t>() else {
+      continue;
+    };
+    // SAFETY: Neither call takes any pointers.
+    unsafe {
+      if libc::syscall(
+        libc::SYS_ioprio_set,
+        IOPRIO_WHO_PROCESS,
+        thread_id,
+        IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+      ) < 0
+        || libc::setpriority(libc::PRIO_PROCESS, thread_id as libc::id_t, IDLE_NICE) < 0
+      {
+        return Err(io::Error::last_os_error());
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Other Unix systems have no idle I/O class, but often schedule I/O by CPU priority.
+#[cfg(all(unix, not(target_os = "linux")))]
+fn set_idle_priority() -> io::Result<()> {
+  // SAFETY: The call takes no pointers.
+  if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, IDLE_NICE) } < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(())
+}
+
+/// Background mode lowers both the CPU and the I/O priority of the process.
+#[cfg(windows)]
+fn set_idle_priority() -> io::Result<()> {
+  const PROCESS_MODE_BACKGROUND_BEGIN: u32 = 0x0010_0000;
+  #[link(name = "kernel32")]
+  extern "system" {
+    fn GetCurrentProcess() -> isize;
+    fn SetPriorityClass(process: isize, priority_class: u32) -> i32;
+  }
+  // SAFETY: The pseudo handle of the current process is always valid.
+  if unsafe { SetPriorityClass(GetCurrentProcess(), PROCESS_MODE_BACKGROUND_BEGIN) } == 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(())
+}
+
+/// Lowers the CPU and I/O priority of the process with `--idle-io`.
+pub fn init() -> Result<()> {
+  if DedupArgs::get().idle_io {
+    set_idle_priority().context("Could not lower the process priority")?;
+  }
+  Ok(())
+}
@@ -0,0 +1,53 @@
+use std::{sync::OnceLock, time::Duration};
+
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+
+use crate::{output, DedupArgs, Stats};
+
+static PROGRESS: OnceLock<Option<ProgressBar>> = OnceLock::new();
+
+fn get_progress() -> Option<&'static ProgressBar> {
+  PROGRESS
+    .get_or_init(|| {
+      if !DedupArgs::get().progress {
+        return None;
+      }
+      let progress = ProgressBar::with_draw_target(None, ProgressDrawTarget::stderr());
+      progress.set_style(
+        ProgressStyle::with_template("{spinner} [{elapsed_precise}] {msg}")
+          .expect("The template is valid"),
+      );
+      progress.enable_steady_tick(Duration::from_millis(100));
+      Some(progress)
+    })
+    .as_ref()
+}
+
+/// Refreshes the progress display from the current stats.
+pub fn update(stats: &Stats) {
+  if let Some(progress) = get_progress() {
+    progress.set_message(format!(
+      "{} dirs scanned, {} files found, {}/{} files hashed ({}), {} links",
+      stats.dirs_scanned,
+      stats.files_processed,
+      stats.files_hashed,
+      stats.files_queued,
+      output::format_size(stats.bytes_hashed),
+      stats.links_created,
+    ));
+  }
+}
+
+/// Runs `f` with the progress display hidden, so that it can print without garbling it.
+pub fn suspend<R>(f: impl FnOnce() -> R) -> R {
+  match get_progress() {
+    Some(progress) => progress.suspend(f),
+    None => f(),
+  }
+}
+
+pub fn finish() {
+  if let Some(progress) = get_progress() {
+    progress.finish_and_clear();
+  }
+}
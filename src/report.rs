@@ -0,0 +1,545 @@
+use std::{
+  borrow::Cow,
+  cmp::Reverse,
+  collections::HashMap,
+  io::{self, Write},
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::Duration,
+};
+
+use anyhow::{Context, Result};
+use serde_json::json;
+
+use crate::{
+  cross_device::CrossDeviceGroup, hash::HashAlgorithm, os::StorageUid, output, storage::Prefilter,
+  DedupArgs, FileUid, Filesize, HashDigest, OutputFormat, Stats,
+};
+
+#[derive(Debug)]
+pub struct DuplicateGroup {
+  pub storage_uid: StorageUid,
+  pub size: Filesize,
+  pub hash: HashDigest,
+  pub original: Arc<Path>,
+  pub linked: Vec<Arc<Path>>,
+  /// The storage taken up by the redundant copies, `size × (copies - 1)`.
+  pub wasted: Filesize,
+}
+
+/// Duplicate groups keyed by the storage and file id of their original.
+#[derive(Debug, Default)]
+pub struct DuplicateGroups(HashMap<FileUid, DuplicateGroup>);
+
+/// Saved storage per root path argument, or per top-level directory below it.
+#[derive(Debug, Default)]
+pub struct DirSavings(HashMap<PathBuf, Filesize>);
+
+#[derive(Default)]
+pub struct Report {
+  pub stats: Stats,
+  pub groups: DuplicateGroups,
+  pub savings: DirSavings,
+  /// The duplicates on different storage devices, with `--cross-device`.
+  pub cross_device_groups: Vec<CrossDeviceGroup>,
+}
+
+pub fn hex(digest: &HashDigest) -> String {
+  digest[..HashAlgorithm::get().digest_len()]
+    .iter()
+    .map(|byte| format!("{byte:02x}"))
+    .collect()
+}
+
+impl DuplicateGroups {
+  /// Records that `redundant` was (or would have been) merged into the original of a group.
+  pub fn add_link(
+    &mut self,
+    original_uid: FileUid,
+    original: &Arc<Path>,
+    (size, hash): (Filesize, HashDigest),
+    redundant: Arc<Path>,
+  ) {
+    self
+      .get_or_insert(original_uid, original, (size, hash))
+      .linked
+      .push(redundant);
+  }
+
+  /// Records that another file (with all of its paths) was merged into the original of a group,
+  /// wasting `size` bytes before the merge.
+  pub fn add_wasted(
+    &mut self,
+    original_uid: FileUid,
+    original: &Arc<Path>,
+    (size, hash): (Filesize, HashDigest),
+  ) {
+    self
+      .get_or_insert(original_uid, original, (size, hash))
+      .wasted += size;
+  }
+
+  fn get_or_insert(
+    &mut self,
+    original_uid: FileUid,
+    original: &Arc<Path>,
+    (size, hash): (Filesize, HashDigest),
+  ) -> &mut DuplicateGroup {
+    self
+      .0
+      .entry(original_uid)
+      .or_insert_with(|| DuplicateGroup {
+        storage_uid: original_uid.0,
+        size,
+        hash,
+        original: original.clone(),
+        linked: vec![],
+        wasted: 0,
+      })
+  }
+
+  /// Records a merge of a path whose file already belongs to an existing group.
+  pub fn add_existing_link(&mut self, original_uid: FileUid, redundant: Arc<Path>) {
+    if let Some(group) = self.0.get_mut(&original_uid) {
+      group.linked.push(redundant);
+    }
+  }
+
+  /// The duplicate groups, ordered by the path of their original.
+  pub fn sorted(&self) -> Vec<&DuplicateGroup> {
+    let mut groups = self.0.values().collect::<Vec<_>>();
+    groups.sort_by(|a, b| a.original.cmp(&b.original));
+    groups
+  }
+
+  /// The `count` groups wasting the most storage.
+  pub fn top(&self, count: usize) -> Vec<&DuplicateGroup> {
+    let mut groups = self.sorted();
+    groups.sort_by_key(|group| Reverse(group.wasted));
+    groups.truncate(count);
+    groups
+  }
+}
+
+impl DirSavings {
+  fn breakdown_dir(path: &Path) -> PathBuf {
+    let root = DedupArgs::get()
+      .path
+      .iter()
+      .filter(|root| path.starts_with(root))
+      .max_by_key(|root| root.components().count());
+    let Some(root) = root else {
+      return path.parent().map(Path::to_path_buf).unwrap_or_default();
+    };
+    let mut below_root = path
+      .strip_prefix(root)
+      .expect("The root is a prefix")
+      .components();
+    match (below_root.next(), below_root.next()) {
+      (Some(top_level), Some(_)) => root.join(top_level),
+      _ => root.clone(),
+    }
+  }
+
+  /// Attributes `size` saved bytes to the directory `redundant` belongs to.
+  pub fn add(&mut self, redundant: &Path, size: Filesize) {
+    *self.0.entry(Self::breakdown_dir(redundant)).or_default() += size;
+  }
+
+  /// The directories ordered by the most saved storage first.
+  pub fn sorted(&self) -> Vec<(&Path, Filesize)> {
+    let mut savings = self
+      .0
+      .iter()
+      .map(|(dir, size)| (dir.as_path(), *size))
+      .collect::<Vec<_>>();
+    savings.sort_by(|(a_dir, a_size), (b_dir, b_size)| b_size.cmp(a_size).then(a_dir.cmp(b_dir)));
+    savings
+  }
+}
+
+fn csv_field(field: &str) -> Cow<'_, str> {
+  if field.contains([',', '"', '\n', '\r']) {
+    Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+  } else {
+    Cow::Borrowed(field)
+  }
+}
+
+fn tsv_field(field: &str) -> Cow<'_, str> {
+  if field.contains(['\\', '\t', '\n', '\r']) {
+    Cow::Owned(
+      field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r"),
+    )
+  } else {
+    Cow::Borrowed(field)
+  }
+}
+
+impl Report {
+  /// The summary of the run as a JSON object.
+  pub fn summary(&self, elapsed: Duration) -> serde_json::Value {
+    let mut summary = serde_json::to_value(&self.stats).expect("Stats are always serializable");
+    summary["groups_found"] = self.groups.0.len().into();
+    summary["errors"] = output::error_count().into();
+    summary["elapsed_seconds"] = elapsed.as_secs_f64().into();
+    summary
+  }
+
+  pub fn write_json(&self, writer: impl io::Write, elapsed: Duration) -> io::Result<()> {
+    let args = DedupArgs::get();
+    let groups = self
+      .groups
+      .sorted()
+      .into_iter()
+      .map(|group| {
+        json!({
+          "storage_uid": group.storage_uid,
+          "size": group.size,
+          "wasted": group.wasted,
+          "hash": hex(&group.hash),
+          "original": group.original.to_string_lossy(),
+          "linked": group
+            .linked
+            .iter()
+            .map(|path| path.to_string_lossy())
+            .collect::<Vec<_>>(),
+        })
+      })
+      .collect::<Vec<_>>();
+    let document = json!({
+      "dry_run": args.dry_run,
+      "groups": groups,
+      "summary": self.summary(elapsed),
+      "cross_device": self
+        .cross_device_groups
+        .iter()
+        .map(|group| {
+          json!({
+            "size": group.size,
+            "wasted": group.wasted(),
+            "hash": hex(&group.hash),
+            "paths": group
+              .paths
+              .iter()
+              .map(|(storage_uid, path)| json!({
+                "storage_uid": storage_uid,
+                "path": path.to_string_lossy(),
+              }))
+              .collect::<Vec<_>>(),
+          })
+        })
+        .collect::<Vec<_>>(),
+      "breakdown": self
+        .savings
+        .sorted()
+        .into_iter()
+        .map(|(dir, size)| json!({ "dir": dir.to_string_lossy(), "saved_storage": size }))
+        .collect::<Vec<_>>(),
+    });
+    serde_json::to_writer_pretty(writer, &document)?;
+    Ok(())
+  }
+
+  /// Writes one row per merge. `separator` is either `,` (CSV, RFC 4180 quoting) or `\t` (TSV,
+  /// backslash escapes).
+  pub fn write_rows(&self, mut writer: impl io::Write, separator: char) -> io::Result<()> {
+    let escape = if separator == '\t' {
+      tsv_field
+    } else {
+      csv_field
+    };
+    writeln!(
+      writer,
+      "original{separator}redundant{separator}size{separator}hash"
+    )?;
+    for group in self.groups.sorted() {
+      let original = group.original.to_string_lossy();
+      let hash = hex(&group.hash);
+      for redundant in &group.linked {
+        writeln!(
+          writer,
+          "{original}{separator}{redundant}{separator}{size}{separator}{hash}",
+          original = escape(&original),
+          redundant = escape(&redundant.to_string_lossy()),
+          size = group.size,
+        )?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Writes every duplicate group as one path per line, original first, with groups separated by
+  /// blank lines like `fdupes` does.
+  pub fn write_fdupes(&self, mut writer: impl io::Write) -> io::Result<()> {
+    for group in self.groups.sorted() {
+      writeln!(writer, "{}", group.original.display())?;
+      for path in &group.linked {
+        writeln!(writer, "{}", path.display())?;
+      }
+      writeln!(writer)?;
+    }
+    Ok(())
+  }
+
+  /// Writes every duplicate group as an `rmlint` JSON dump: a header, one entry per path with the
+  /// original first in each group, and a footer with the totals.
+  pub fn write_rmlint_json(&self, writer: impl io::Write) -> io::Result<()> {
+    let algorithm = clap::ValueEnum::to_possible_value(&HashAlgorithm::get())
+      .map(|value| value.get_name().to_owned());
+    let mut entries = vec![json!({
+      "description": "rmlint json-dump of lint files",
+      "cwd": std::env::current_dir().unwrap_or_default().to_string_lossy(),
+      "args": std::env::args().collect::<Vec<_>>().join(" "),
+      "version": env!("CARGO_PKG_VERSION"),
+      "progress": 0,
+      "checksum_type": algorithm,
+    })];
+    let groups = self.groups.sorted();
+    let mut duplicates = 0;
+    for group in &groups {
+      let checksum = hex(&group.hash);
+      let paths = std::iter::once(&group.original).chain(&group.linked);
+      for (index, path) in paths.enumerate() {
+        duplicates += usize::from(index > 0);
+        let mut entry = json!({
+          "id": entries.len(),
+          "type": "duplicate_file",
+          "progress": 100,
+          "checksum": checksum,
+          "path": path.to_string_lossy(),
+          "size": group.size,
+          "depth": path.components().count().saturating_sub(1),
+          "disk_id": group.storage_uid,
+          "is_original": index == 0,
+        });
+        // The files are read again, since they may have been replaced by links.
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+          if let Ok(mtime) = metadata.modified() {
+            let mtime = mtime
+              .duration_since(std::time::UNIX_EPOCH)
+              .unwrap_or_default();
+            entry["mtime"] = mtime.as_secs_f64().into();
+          }
+          #[cfg(unix)]
+          {
+            use std::os::unix::fs::MetadataExt;
+            entry["inode"] = metadata.ino().into();
+          }
+        }
+        entries.push(entry);
+      }
+    }
+    let wasted = groups.iter().map(|group| group.wasted).sum::<Filesize>();
+    entries.push(json!({
+      "aborted": false,
+      "progress": 100,
+      "duplicates": duplicates,
+      "duplicate_sets": groups.len(),
+      "total_files": self.stats.files_processed,
+      "total_lint_size": wasted,
+    }));
+    serde_json::to_writer_pretty(writer, &entries)?;
+    Ok(())
+  }
+
+  /// Writes every duplicate group like `jdupes --json` does.
+  pub fn write_jdupes_json(&self, writer: impl io::Write) -> io::Result<()> {
+    let match_sets = self
+      .groups
+      .sorted()
+      .into_iter()
+      .map(|group| {
+        let files = std::iter::once(&group.original)
+          .chain(&group.linked)
+          .map(|path| json!({ "filePath": path.to_string_lossy() }))
+          .collect::<Vec<_>>();
+        json!({ "fileSize": group.size, "fileList": files })
+      })
+      .collect::<Vec<_>>();
+    let document = json!({
+      "commandLine": std::env::args().collect::<Vec<_>>().join(" "),
+      "matchSets": match_sets,
+    });
+    serde_json::to_writer_pretty(writer, &document)?;
+    Ok(())
+  }
+
+  /// Writes every duplicate group for `--report`, wasting the most storage first, and their total.
+  pub fn write_groups(&self, mut writer: impl io::Write) -> io::Result<()> {
+    let groups = self.groups.top(usize::MAX);
+    for group in &groups {
+      writeln!(
+        writer,
+        "{} copies of {}, wasting {}:",
+        group.wasted / group.size + 1,
+        output::format_size(group.size),
+        output::format_size(group.wasted)
+      )?;
+      writeln!(writer, "  {}", group.original.display())?;
+      for path in &group.linked {
+        writeln!(writer, "  {}", path.display())?;
+      }
+      writeln!(writer)?;
+    }
+    writeln!(
+      writer,
+      "{} duplicate groups wasting {} in total",
+      groups.len(),
+      output::format_size(groups.iter().map(|group| group.wasted).sum())
+    )
+  }
+
+  /// Prints the summary and writes the report in every format asked for on the command line.
+  pub fn print(&self, elapsed: Duration) -> Result<()> {
+    let args = DedupArgs::get();
+    if let Some(ref summary_json) = args.summary_json {
+      let summary = serde_json::to_string_pretty(&self.summary(elapsed))?;
+      if summary_json == Path::new("-") {
+        println!("{summary}");
+      } else {
+        std::fs::write(summary_json, summary + "\n")
+          .with_context(|| format!("Could not write summary to {}", summary_json.display()))?;
+      }
+    }
+    if args.report && args.prints_text() {
+      self.write_groups(io::stdout().lock())?;
+    }
+    let stats = &self.stats;
+    output::print_summary("")?;
+    output::print_summary(format!(
+      "{} dirs and {} files processed",
+      stats.dirs_scanned, stats.files_processed
+    ))?;
+    output::print_summary(format!(
+      "{} files hashed ({})",
+      stats.files_hashed,
+      output::format_size(stats.bytes_hashed)
+    ))?;
+    if !Prefilter::stages().is_empty() {
+      output::print_summary(format!("{} blocks prefiltered", stats.prefilters_hashed))?;
+    }
+    if args.estimate {
+      output::print_summary(format!(
+        "At most {} can be saved, going by the file sizes",
+        output::format_size(stats.saved_storage)
+      ))?;
+    } else {
+      output::print_summary(format!(
+        "A total of {} {} saved",
+        output::format_size(stats.saved_storage),
+        if args.dry_run { "can be" } else { "was" }
+      ))?;
+      if stats.saved_allocated < stats.saved_storage {
+        output::print_summary(format!(
+          "Only {} of it is allocated on disk, since some files are sparse or compressed",
+          output::format_size(stats.saved_allocated)
+        ))?;
+      }
+    }
+    let per_second = |count: f64, seconds: f64| {
+      if seconds > 0.0 {
+        count / seconds
+      } else {
+        0.0
+      }
+    };
+    output::print_summary(format!(
+      "Took {:.1}s: scanning {:.1}s ({:.0} files/s), hashing {:.1}s ({}/s), merging {:.1}s",
+      elapsed.as_secs_f64(),
+      stats.scan_seconds,
+      per_second(stats.files_processed as f64, stats.scan_seconds),
+      stats.hash_seconds,
+      output::format_size(per_second(stats.bytes_hashed as f64, stats.hash_seconds) as Filesize),
+      stats.merge_seconds
+    ))?;
+    if stats.existing_links > 0 {
+      output::print_summary(format!(
+        "{} was already shared by {} existing links",
+        output::format_size(stats.shared_storage),
+        stats.existing_links
+      ))?;
+    }
+    if let Some(top) = args.top {
+      output::print_summary("")?;
+      output::print_summary(format!("Top {top} duplicate groups:"))?;
+      for group in self.groups.top(top) {
+        output::print_summary(format!(
+          "  {} wasted by {} copies of {}",
+          output::format_size(group.wasted),
+          group.wasted / group.size + 1,
+          group.original.display()
+        ))?;
+        for path in &group.linked {
+          output::print_summary(format!("    {}", path.display()))?;
+        }
+      }
+    }
+    if args.breakdown {
+      for (dir, size) in self.savings.sorted() {
+        output::print_summary(format!(
+          "  {} in {}",
+          output::format_size(size),
+          dir.display()
+        ))?;
+      }
+    }
+    if !self.cross_device_groups.is_empty() {
+      output::print_summary("")?;
+      output::print_summary("Duplicates on different storage devices, which can't be linked:")?;
+      for group in &self.cross_device_groups {
+        output::print_summary(format!(
+          "  {} wasted by {} copies of {}",
+          output::format_size(group.wasted()),
+          group.paths.len(),
+          output::format_size(group.size)
+        ))?;
+        for (storage_uid, path) in &group.paths {
+          output::print_summary(format!("    {} (device {storage_uid})", path.display()))?;
+        }
+      }
+      output::print_summary(format!(
+        "{} wasted across storage devices in total",
+        output::format_size(
+          self
+            .cross_device_groups
+            .iter()
+            .map(CrossDeviceGroup::wasted)
+            .sum()
+        )
+      ))?;
+    }
+    match args.format {
+      OutputFormat::Text => (),
+      OutputFormat::Json => {
+        let mut stdout = io::stdout().lock();
+        self.write_json(&mut stdout, elapsed)?;
+        writeln!(stdout)?;
+      }
+      OutputFormat::Csv => {
+        self.write_rows(io::stdout().lock(), ',')?;
+      }
+      OutputFormat::Tsv => {
+        self.write_rows(io::stdout().lock(), '\t')?;
+      }
+      OutputFormat::Fdupes => {
+        self.write_fdupes(io::stdout().lock())?;
+      }
+      OutputFormat::RmlintJson => {
+        let mut stdout = io::stdout().lock();
+        self.write_rmlint_json(&mut stdout)?;
+        writeln!(stdout)?;
+      }
+      OutputFormat::Jdupes => {
+        let mut stdout = io::stdout().lock();
+        self.write_jdupes_json(&mut stdout)?;
+        writeln!(stdout)?;
+      }
+    }
+    Ok(())
+  }
+}
@@ -1,49 +1,312 @@
 use std::{
   cmp::min,
+  collections::HashMap,
+  ffi::OsStr,
   io::{Error, ErrorKind},
-  path::Path,
-  sync::{Arc, OnceLock},
+  ops::{Deref, DerefMut},
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex, OnceLock},
+  time::{Instant, SystemTime},
 };
 
 use anyhow::{Context, Result};
-use blake3::Hasher;
-use tokio::{fs, io::AsyncReadExt, join, sync::Semaphore};
+use clap::ValueEnum;
+use tokio::{
+  fs,
+  io::{AsyncRead, AsyncReadExt, AsyncSeekExt, SeekFrom},
+  join,
+  sync::{OwnedSemaphorePermit, Semaphore, SemaphorePermit},
+};
 
 use crate::{
-  os::{read_link_metadata, FileId, FileLinkBackend, StorageUid},
+  acl::{self, AclPolicy},
+  cache, control, fd_limit,
+  hash::{self, HashAlgorithm},
+  manifest,
+  observer::{self, Event},
+  os::{self, read_link_metadata, FileId, FileLinkBackend, StorageUid},
+  output, throttle,
+  xattrs::{self, XattrPolicy},
   DedupArgs, Filesize, HashDigest,
 };
 
+/// The owner, group and mode of a file. Only the readonly attribute is compared on Windows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Access {
+  uid: u32,
+  gid: u32,
+  mode: u32,
+}
+
+impl Access {
+  #[cfg(unix)]
+  fn new(metadata: &std::fs::Metadata) -> Self {
+    use std::os::unix::fs::MetadataExt;
+    Access {
+      uid: metadata.uid(),
+      gid: metadata.gid(),
+      mode: metadata.mode() & 0o7777,
+    }
+  }
+
+  #[cfg(windows)]
+  fn new(metadata: &std::fs::Metadata) -> Self {
+    Access {
+      uid: 0,
+      gid: 0,
+      mode: metadata.permissions().readonly().into(),
+    }
+  }
+}
+
+/// The files that may be merged with each other: those on the same storage, and with
+/// `--require-same-metadata`, `--xattr-policy require-equal`, `--acl-policy`, `--same-name` and
+/// `--same-mtime` only those with the same access, extended attributes, ACL, file name and
+/// modification time.
+pub type StorageKey = (
+  StorageUid,
+  Option<Access>,
+  Option<HashDigest>,
+  Option<HashDigest>,
+  Option<Arc<OsStr>>,
+  Option<SystemTime>,
+);
+
 #[derive(Debug, Clone)]
 pub struct FileStorageData {
   pub path: Arc<Path>,
   pub size: Filesize,
   pub storage_uid: StorageUid,
   pub file_id: FileId,
+  pub link_count: u64,
+  /// The storage allocated on disk, which is less than `size` for sparse and compressed files.
+  pub allocated: Filesize,
+  pub modified: Option<SystemTime>,
+  /// The user ID of the owner.
+  #[cfg(unix)]
+  pub owner: u32,
+  pub access: Option<Access>,
+  pub xattrs: Option<HashDigest>,
+  pub acl: Option<HashDigest>,
 }
 
 impl FileStorageData {
   pub async fn new(path: impl AsRef<Path>) -> Result<Self> {
     let path = path.as_ref().to_owned();
     let (link_metadata, metadata) = join!(read_link_metadata(&path), fs::metadata(&path));
-    let link_metadata = link_metadata?;
+    let (link_metadata, metadata) = (link_metadata?, metadata?);
+    let xattrs = if XattrPolicy::get() == XattrPolicy::RequireEqual {
+      let path = path.clone();
+      Some(tokio::task::spawn_blocking(move || xattrs::digest(&path)).await??)
+    } else {
+      None
+    };
+    let acl = if AclPolicy::get() != AclPolicy::Ignore {
+      let path = path.clone();
+      Some(tokio::task::spawn_blocking(move || acl::digest(&path)).await??)
+    } else {
+      None
+    };
+    let allocated = os::allocated_size(&path, &metadata);
     #[allow(clippy::useless_conversion)]
     Ok(FileStorageData {
       path: path.into(),
-      size: metadata?.len().try_into().unwrap(),
+      size: metadata.len().try_into().unwrap(),
+      allocated,
+      modified: metadata.modified().ok(),
+      #[cfg(unix)]
+      owner: std::os::unix::fs::MetadataExt::uid(&metadata),
       storage_uid: link_metadata.get_storage_uid(),
       file_id: link_metadata.get_file_id(),
+      link_count: link_metadata.get_link_count(),
+      access: DedupArgs::get()
+        .require_same_metadata
+        .then(|| Access::new(&metadata)),
+      xattrs,
+      acl,
     })
   }
 
-  // pub async fn new_with_context(path: impl AsRef<Path>) -> Result<Self> {
-  // Ok(Self::new(path.as_ref()).await.with_context(move || {
-  // format!(
-  // "Could not reat metadata for file {}",
-  // path.as_ref().display()
-  // )
-  // })?)
-  // }
+  /// Whether the file still has the size, modification time and ID it was scanned with.
+  pub async fn is_unchanged(&self) -> Result<bool> {
+    let (link_metadata, metadata) = join!(read_link_metadata(&self.path), fs::metadata(&self.path));
+    let (link_metadata, metadata) = (link_metadata?, metadata?);
+    Ok(
+      metadata.len() == self.size
+        && metadata.modified().ok() == self.modified
+        && link_metadata.get_file_uid() == (self.storage_uid, self.file_id),
+    )
+  }
+
+  pub fn storage_key(&self) -> StorageKey {
+    let args = DedupArgs::get();
+    let name = args
+      .same_name
+      .then(|| self.path.file_name().map(Arc::from))
+      .flatten();
+    let modified = self.modified.filter(|_| args.same_mtime);
+    (
+      self.storage_uid,
+      self.access,
+      self.xattrs,
+      self.acl,
+      name,
+      modified,
+    )
+  }
+}
+
+/// The storage that `value` of `--same-storage` refers to, either a path on it or its ID.
+async fn raw_storage_uid(value: &Path) -> Result<StorageUid> {
+  match read_link_metadata(value).await {
+    Ok(metadata) => Ok(metadata.get_storage_uid()),
+    Err(e) => value
+      .to_str()
+      .and_then(|id| id.parse().ok())
+      .ok_or(e)
+      .with_context(|| format!("Could not find storage {}", value.display())),
+  }
+}
+
+/// Treats the storage of each pair of `--same-storage` as one, so that their files are compared
+/// and linked with each other. Must be called before any storage ID is read.
+pub async fn init_aliases() -> Result<()> {
+  let mut aliases = HashMap::<StorageUid, StorageUid>::new();
+  for pair in DedupArgs::get().same_storage.chunks(2) {
+    let [a, b] = pair else {
+      unreachable!("--same-storage takes two values")
+    };
+    let resolve = |aliases: &HashMap<_, _>, uid| aliases.get(&uid).copied().unwrap_or(uid);
+    let a = resolve(&aliases, raw_storage_uid(a).await?);
+    let b = resolve(&aliases, raw_storage_uid(b).await?);
+    if a != b {
+      for canonical in aliases.values_mut() {
+        if *canonical == b {
+          *canonical = a;
+        }
+      }
+      aliases.insert(b, a);
+    }
+  }
+  os::set_storage_aliases(aliases);
+  Ok(())
+}
+
+/// The storage allocated on disk for the file at `path`.
+pub async fn allocated_size(path: &Path) -> Result<Filesize> {
+  let metadata = fs::metadata(path).await?;
+  Ok(os::allocated_size(path, &metadata))
+}
+
+#[cfg(target_os = "linux")]
+fn evict_from_cache(path: &Path) -> std::io::Result<()> {
+  use std::os::fd::AsRawFd;
+  let file = std::fs::File::open(path)?;
+  // SAFETY: The file descriptor stays open for the duration of the call.
+  unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_DONTNEED) };
+  Ok(())
+}
+
+#[cfg(windows)]
+fn evict_from_cache(path: &Path) -> std::io::Result<()> {
+  use std::os::windows::fs::OpenOptionsExt;
+  /// Opening an unbuffered handle makes the cache manager purge the cached data of the file, unless
+  /// another handle is using it.
+  const FILE_FLAG_NO_BUFFERING: u32 = 0x2000_0000;
+  std::fs::OpenOptions::new()
+    .read(true)
+    .custom_flags(FILE_FLAG_NO_BUFFERING)
+    .open(path)?;
+  Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn evict_from_cache(_path: &Path) -> std::io::Result<()> {
+  Ok(())
+}
+
+/// Evicts a file that was just read from the page cache if `--no-cache-pollution` is set, so that
+/// the cached data of other programs isn't pushed out. This is only a hint, so errors are ignored.
+async fn drop_cache(path: &Path) {
+  if !DedupArgs::get().no_cache_pollution {
+    return;
+  }
+  let path = path.to_owned();
+  let _ = tokio::task::spawn_blocking(move || evict_from_cache(&path)).await;
+}
+
+/// The size of a read buffer, in bytes. Every hashing thread holds up to two buffers, so
+/// `--max-memory` is split into twice as many buffers as `--max-hash-threads`.
+fn buffer_size() -> usize {
+  let args = DedupArgs::get();
+  let size = match args.max_memory {
+    Some(budget) => {
+      let buffers = 2 * args.max_hash_threads as Filesize;
+      (budget / buffers).try_into().unwrap_or(usize::MAX)
+    }
+    None => args.buffer_size * 1024,
+  };
+  size.max(1)
+}
+
+/// Read buffers of `buffer_size()`, kept for the next files to be read.
+static BUFFER_POOL: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+/// A read buffer that is returned to the pool when it's dropped.
+struct PooledBuffer(Vec<u8>);
+
+impl PooledBuffer {
+  /// Takes a buffer from the pool, or allocates a zeroed one of `buffer_size()`. The size is halved
+  /// until the allocation succeeds, down to 512 bytes.
+  fn take() -> Result<Self> {
+    let pooled = BUFFER_POOL
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner())
+      .pop();
+    if let Some(buffer) = pooled {
+      return Ok(PooledBuffer(buffer));
+    }
+    let mut buffer_size = buffer_size();
+    let mut buffer = Vec::new();
+    loop {
+      match buffer.try_reserve_exact(buffer_size) {
+        Ok(()) => break,
+        Err(_) if buffer_size > 512 => {
+          buffer_size >>= 1;
+        }
+        Err(error) => Err(error)?,
+      }
+    }
+    buffer.resize(buffer_size, 0);
+    Ok(PooledBuffer(buffer))
+  }
+}
+
+impl Deref for PooledBuffer {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    &self.0
+  }
+}
+
+impl DerefMut for PooledBuffer {
+  fn deref_mut(&mut self) -> &mut [u8] {
+    &mut self.0
+  }
+}
+
+impl Drop for PooledBuffer {
+  fn drop(&mut self) {
+    let mut pool = BUFFER_POOL
+      .lock()
+      .unwrap_or_else(|poisoned| poisoned.into_inner());
+    // Every hashing thread can hold two buffers while comparing files, but only one is kept per
+    // thread.
+    if pool.len() < DedupArgs::get().max_hash_threads {
+      pool.push(std::mem::take(&mut self.0));
+    }
+  }
 }
 
 static HASH_SEMAPHORE: OnceLock<Semaphore> = OnceLock::new();
@@ -52,69 +315,430 @@ pub fn get_file_hash_lock() -> &'static Semaphore {
   HASH_SEMAPHORE.get_or_init(|| Semaphore::new(DedupArgs::get().max_hash_threads))
 }
 
+/// The `--per-device-threads` or `--hdd-threads` semaphore of each storage device, created on
+/// first use. `None` for devices without a limit of their own.
+static DEVICE_SEMAPHORES: Mutex<Option<HashMap<StorageUid, Option<Arc<Semaphore>>>>> =
+  Mutex::new(None);
+
+/// Whether the device is a spinning disk, going by `/sys/dev/block`. Partitions are looked up
+/// through the disk they are on, and unknown devices like network file systems aren't rotational.
+#[cfg(target_os = "linux")]
+fn is_rotational(storage_uid: StorageUid) -> bool {
+  let device = format!(
+    "/sys/dev/block/{}:{}",
+    libc::major(storage_uid),
+    libc::minor(storage_uid)
+  );
+  let Ok(device) = std::fs::canonicalize(device) else {
+    return false;
+  };
+  [device.join("queue"), device.join("../queue")]
+    .iter()
+    .find_map(|queue| std::fs::read_to_string(queue.join("rotational")).ok())
+    .is_some_and(|rotational| rotational.trim() == "1")
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_rotational(_storage_uid: StorageUid) -> bool {
+  false
+}
+
+fn device_semaphore(storage_uid: StorageUid) -> Option<Arc<Semaphore>> {
+  let args = DedupArgs::get();
+  DEVICE_SEMAPHORES
+    .lock()
+    .unwrap_or_else(|poisoned| poisoned.into_inner())
+    .get_or_insert_with(HashMap::new)
+    .entry(storage_uid)
+    .or_insert_with(|| {
+      let threads = if is_rotational(storage_uid) {
+        output::print_verbose(
+          1,
+          format_args!(
+            "Device {storage_uid} is rotational, limiting it to {} hashing threads",
+            args.hdd_threads
+          ),
+        );
+        Some(args.hdd_threads)
+      } else {
+        args.per_device_threads
+      };
+      threads.map(|threads| Arc::new(Semaphore::new(threads)))
+    })
+    .clone()
+}
+
+/// Allows a file on one storage device to be read, within both `--max-hash-threads` and the limit
+/// of the device.
+pub struct HashPermit {
+  _device: Option<OwnedSemaphorePermit>,
+  _global: SemaphorePermit<'static>,
+}
+
+pub async fn acquire_hash_permit(storage_uid: StorageUid) -> Result<HashPermit> {
+  // The device is waited for first, so that a busy device doesn't hold global permits that other
+  // devices could use.
+  let device = match device_semaphore(storage_uid) {
+    Some(semaphore) => Some(semaphore.acquire_owned().await?),
+    None => None,
+  };
+  Ok(HashPermit {
+    _device: device,
+    _global: get_file_hash_lock().acquire().await?,
+  })
+}
+
+/// Hashes a file with io_uring reads if `--io-uring` is set.
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+async fn hash_with_uring(
+  path: PathBuf,
+  expected_size: Filesize,
+) -> Result<Option<(HashDigest, Filesize)>> {
+  if !DedupArgs::get().io_uring {
+    return Ok(None);
+  }
+  let algorithm = HashAlgorithm::get();
+  let buffer_size = min(buffer_size(), expected_size.try_into().unwrap()).max(1);
+  let hashed = tokio::task::spawn_blocking(move || {
+    crate::uring::hash_with_uring(&path, algorithm, buffer_size)
+  })
+  .await??;
+  Ok(Some(hashed))
+}
+
+#[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+async fn hash_with_uring(
+  _path: PathBuf,
+  _expected_size: Filesize,
+) -> Result<Option<(HashDigest, Filesize)>> {
+  Ok(None)
+}
+
 pub async fn calculate_file_hash(
   path: impl AsRef<Path>,
   expected_size: Filesize,
+  storage_uid: StorageUid,
 ) -> Result<HashDigest> {
-  let lock = get_file_hash_lock().acquire().await?;
-  let hash = {
-    let mut hash = Box::new(Hasher::new());
+  control::wait_if_paused().await;
+  let lock = acquire_hash_permit(storage_uid).await?;
+  let handle = fd_limit::acquire().await;
+  let start = Instant::now();
+  let parallel = DedupArgs::get()
+    .parallel_hash_size
+    .is_some_and(|size| expected_size >= size * 1024 * 1024)
+    && HashAlgorithm::get() == HashAlgorithm::Blake3;
+  let (owned_path, algorithm) = (path.as_ref().to_owned(), HashAlgorithm::get());
+  let hashed = if parallel {
+    Some(tokio::task::spawn_blocking(move || hash::blake3_parallel(&owned_path)).await??)
+  } else if DedupArgs::get().mmap {
+    tokio::task::spawn_blocking(move || hash::hash_mapped(&owned_path, algorithm)).await??
+  } else {
+    hash_with_uring(owned_path, expected_size).await?
+  };
+  let (hash, file_length) = if let Some(hashed) = hashed {
+    throttle::consume(hashed.1).await;
+    hashed
+  } else {
+    let mut hash = HashAlgorithm::get().hasher();
     let mut file_length = 0;
     let mut reader = fs::OpenOptions::new()
       .create(false)
       .read(true)
-      .open(path)
+      .open(&path)
       .await?;
-    let mut buffer_size = min(
-      DedupArgs::get().buffer_size * 1024,
-      expected_size.try_into().unwrap(),
-    );
-    let mut read_buf = Vec::new();
-    loop {
-      let (reserve_remaining, done) = buffer_size.overflowing_sub(read_buf.len());
-      if done {
-        break;
-      }
-      match read_buf.try_reserve_exact(reserve_remaining) {
-        Ok(()) => break,
-        Err(_) if buffer_size > 512 => {
-          buffer_size >>= 1;
+    let mut buffer = PooledBuffer::take()?;
+    let mut buffer_size = min(buffer.len(), expected_size.try_into().unwrap());
+    // Files larger than a buffer are read into a second buffer while the first one is hashed.
+    let mut next_buffer = if expected_size > buffer_size as Filesize {
+      let next_buffer = PooledBuffer::take()?;
+      buffer_size = buffer_size.min(next_buffer.len());
+      Some(next_buffer)
+    } else {
+      None
+    };
+    let mut bytes_read = reader.read(&mut buffer[..buffer_size]).await?;
+    while bytes_read > 0 {
+      file_length += bytes_read;
+      throttle::consume(bytes_read).await;
+      let chunk = &buffer[..bytes_read];
+      bytes_read = match next_buffer {
+        Some(ref mut next_buffer) => {
+          // The read is polled first, which starts it on the blocking pool, so the chunk is hashed
+          // while it runs.
+          let (next_read, ()) = join!(reader.read(&mut next_buffer[..buffer_size]), async {
+            hash.update(chunk)
+          });
+          next_read?
         }
-        Err(error) => Err(error)?,
+        None => {
+          hash.update(chunk);
+          reader.read(&mut buffer[..buffer_size]).await?
+        }
+      };
+      if let Some(ref mut next_buffer) = next_buffer {
+        std::mem::swap(&mut buffer, next_buffer);
       }
     }
-    unsafe {
-      read_buf.set_len(read_buf.capacity());
+    (hash.finalize(), file_length as Filesize)
+  };
+  if file_length != expected_size {
+    return Err(Error::new(
+      ErrorKind::BrokenPipe,
+      "The entire file could not be hashed",
+    ))?;
+  }
+  drop_cache(path.as_ref()).await;
+  drop(handle);
+  drop(lock);
+  output::print_verbose(
+    1,
+    format_args!(
+      "Hashed {} in {:.2?}",
+      path.as_ref().display(),
+      start.elapsed()
+    ),
+  );
+  Ok(hash)
+}
+
+/// A cheap comparison of a single block of files with equal sizes, done before they are fully
+/// hashed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Prefilter {
+  /// The first block of the file.
+  Prefix,
+  /// The last block of the file, where many large files such as disk images differ.
+  Tail,
+}
+
+impl Prefilter {
+  /// The prefilters to run, in order. `--prefix-size` on its own enables the prefix prefilter.
+  pub fn stages() -> &'static [Prefilter] {
+    let args = DedupArgs::get();
+    if args.prefilter.is_empty() && args.prefix_size.is_some() {
+      &[Prefilter::Prefix]
+    } else {
+      &args.prefilter
     }
-    loop {
-      let bytes_read = reader.read(&mut read_buf[..]).await?;
-      file_length += bytes_read;
-      if bytes_read == 0 {
-        break;
-      }
-      hash.update(&read_buf[..bytes_read]);
+  }
+
+  /// The size of the block that is hashed, in bytes.
+  pub fn block_size() -> usize {
+    DedupArgs::get().prefix_size.unwrap_or(64) * 1024
+  }
+}
+
+/// Hashes a single block of a file, chained with the digest of the previous prefilter so that each
+/// prefilter narrows down the candidates of the one before it.
+pub async fn calculate_prefilter_hash(
+  path: impl AsRef<Path>,
+  file_size: Filesize,
+  prefilter: Prefilter,
+  previous: Option<HashDigest>,
+  storage_uid: StorageUid,
+) -> Result<HashDigest> {
+  control::wait_if_paused().await;
+  let lock = acquire_hash_permit(storage_uid).await?;
+  let handle = fd_limit::acquire().await;
+  let block_size = Prefilter::block_size();
+  let mut reader = fs::File::open(&path).await?;
+  if prefilter == Prefilter::Tail {
+    reader
+      .seek(SeekFrom::Start(file_size - block_size as Filesize))
+      .await?;
+  }
+  let mut buffer = vec![0; block_size];
+  let bytes_read = fill_buffer(&mut reader, &mut buffer).await?;
+  throttle::consume(bytes_read).await;
+  if bytes_read != block_size {
+    return Err(Error::new(
+      ErrorKind::UnexpectedEof,
+      "The file is shorter than expected",
+    ))?;
+  }
+  drop(reader);
+  drop_cache(path.as_ref()).await;
+  drop(handle);
+  drop(lock);
+  let mut hash = HashAlgorithm::get().hasher();
+  if let Some(ref previous) = previous {
+    hash.update(previous);
+  }
+  hash.update(&buffer);
+  Ok(hash.finalize())
+}
+
+pub async fn calculate_prefilter_hash_with_context(
+  file: &FileStorageData,
+  prefilter: Prefilter,
+  previous: Option<HashDigest>,
+) -> Result<Option<HashDigest>> {
+  let result =
+    calculate_prefilter_hash(&file.path, file.size, prefilter, previous, file.storage_uid)
+      .await
+      .with_context(|| {
+        format!(
+          "Could not hash {prefilter:?} of file {}",
+          file.path.display()
+        )
+      });
+  match (result, DedupArgs::get().ignore_hash_errors) {
+    (Ok(hash), _) => Ok(Some(hash)),
+    (Err(err), true) => {
+      output::print_error(format!("{err:#}"));
+      Ok(None)
+    }
+    (Err(err), false) => Err(err),
+  }
+}
+
+/// Reads until `buffer` is full or the end of the file is reached.
+async fn fill_buffer(reader: &mut (impl AsyncRead + Unpin), buffer: &mut [u8]) -> Result<usize> {
+  let mut filled = 0;
+  while filled < buffer.len() {
+    let bytes_read = reader.read(&mut buffer[filled..]).await?;
+    if bytes_read == 0 {
+      break;
+    }
+    filled += bytes_read;
+  }
+  Ok(filled)
+}
+
+/// Compares the contents of two files byte by byte.
+pub async fn compare_files(
+  first: impl AsRef<Path>,
+  second: impl AsRef<Path>,
+  expected_size: Filesize,
+  storage_uid: StorageUid,
+) -> Result<bool> {
+  let lock = acquire_hash_permit(storage_uid).await?;
+  let handles = fd_limit::acquire_many(2).await;
+  let buffer_size = min(buffer_size(), expected_size.try_into().unwrap()).max(1);
+  let (mut first_file, mut second_file) = (
+    fs::File::open(&first).await?,
+    fs::File::open(&second).await?,
+  );
+  let (mut first_buffer, mut second_buffer) = (PooledBuffer::take()?, PooledBuffer::take()?);
+  let buffer_size = buffer_size.min(first_buffer.len()).min(second_buffer.len());
+  let (first_buf, second_buf) = (
+    &mut first_buffer[..buffer_size],
+    &mut second_buffer[..buffer_size],
+  );
+  let identical = loop {
+    let (first_read, second_read) = (
+      fill_buffer(&mut first_file, first_buf).await?,
+      fill_buffer(&mut second_file, second_buf).await?,
+    );
+    throttle::consume(first_read + second_read).await;
+    if first_buf[..first_read] != second_buf[..second_read] {
+      break false;
     }
-    if file_length != expected_size as usize {
-      return Err(Error::new(
-        ErrorKind::BrokenPipe,
-        "The entire file could not be hashed",
-      ))?;
+    if first_read == 0 {
+      break true;
     }
-    hash.finalize().into()
   };
+  drop((first_file, second_file));
+  drop_cache(first.as_ref()).await;
+  drop_cache(second.as_ref()).await;
+  drop(handles);
   drop(lock);
-  Ok(hash)
+  Ok(identical)
+}
+
+/// Whether `redundant` may be merged into `original`. Without `--paranoid` the equal hashes are
+/// trusted, otherwise the files are compared byte by byte.
+pub async fn verify_identical_with_context(
+  original: &Path,
+  redundant: &Path,
+  size: Filesize,
+  storage_uid: StorageUid,
+) -> Result<bool> {
+  let args = DedupArgs::get();
+  if !args.paranoid {
+    return Ok(true);
+  }
+  let result = compare_files(original, redundant, size, storage_uid)
+    .await
+    .with_context(|| {
+      format!(
+        "Could not compare {} to {}",
+        redundant.display(),
+        original.display()
+      )
+    });
+  match (result, args.ignore_hash_errors) {
+    (Ok(true), _) => Ok(true),
+    (Ok(false), _) => {
+      output::print_error(format!(
+        "{} differs from {} despite equal hashes, not merging",
+        redundant.display(),
+        original.display()
+      ));
+      Ok(false)
+    }
+    (Err(err), true) => {
+      output::print_error(format!("{err:#}"));
+      Ok(false)
+    }
+    (Err(err), false) => Err(err),
+  }
 }
 
+/// The digest of `file`, which is also written to `--emit-checksums`. `None` if it was modified
+/// while hashing, or failed with `--ignore-hash-errors`.
 pub async fn calculate_file_hash_with_context(
-  path: impl AsRef<Path>,
-  expected_size: Filesize,
+  file: &FileStorageData,
 ) -> Result<Option<HashDigest>> {
-  let result = calculate_file_hash(path.as_ref(), expected_size)
-    .await
-    .with_context(move || format!("Could not hash file {}", path.as_ref().display()));
+  let hash = find_or_calculate_hash(file).await?;
+  if let Some(ref hash) = hash {
+    manifest::emit(file, hash)
+      .context("Could not write to the checksum file given to --emit-checksums")?;
+  }
+  Ok(hash)
+}
+
+async fn find_or_calculate_hash(file: &FileStorageData) -> Result<Option<HashDigest>> {
+  if let Some(hash) = manifest::get(file) {
+    output::print_verbose(
+      1,
+      format_args!("Using imported hash for {}", file.path.display()),
+    );
+    return Ok(Some(hash));
+  }
+  if let Some(hash) = cache::get(file) {
+    output::print_verbose(
+      1,
+      format_args!("Using cached hash for {}", file.path.display()),
+    );
+    return Ok(Some(hash));
+  }
+  observer::notify(Event::HashStarted {
+    path: &file.path,
+    size: file.size,
+  });
+  let start = Instant::now();
+  let result = async {
+    let hash = calculate_file_hash(&file.path, file.size, file.storage_uid).await?;
+    observer::notify(Event::HashFinished {
+      path: &file.path,
+      size: file.size,
+      elapsed: start.elapsed(),
+    });
+    anyhow::Ok((hash, file.is_unchanged().await?))
+  }
+  .await
+  .with_context(move || format!("Could not hash file {}", file.path.display()));
   match (result, DedupArgs::get().ignore_hash_errors) {
-    (Ok(hash), _) => Ok(Some(hash)),
+    // The hash may mix old and new content, so the file is left alone until the next run.
+    (Ok((_, false)), _) => {
+      output::print_skipped(&file.path, "modified while hashing");
+      Ok(None)
+    }
+    (Ok((hash, true)), _) => {
+      cache::insert(file, hash);
+      Ok(Some(hash))
+    }
     (Err(err), true) => {
       let maybe_source = err.source().map(|x| format!("{}", x));
       let real_err = if let Some(ref source) = maybe_source {
@@ -122,7 +746,7 @@ pub async fn calculate_file_hash_with_context(
       } else {
         "unknown error"
       };
-      eprintln!("{err} ({real_err})");
+      output::print_error(format!("{err} ({real_err})"));
       Ok(None)
     }
     (Err(err), false) => Err(err),
@@ -0,0 +1,516 @@
+use std::{
+  env, io,
+  path::{Component, Path, PathBuf},
+};
+
+use clap::ValueEnum;
+
+use crate::DedupArgs;
+
+/// How a redundant file is replaced by the original.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Strategy {
+  /// Replace it with a hard link to the original.
+  HardLink,
+  /// Replace it with a copy-on-write clone of the original, on btrfs, XFS, APFS and ReFS
+  /// (including Dev Drives). The files stay
+  /// independently writable, but can't be told apart from other copies by later runs.
+  Reflink,
+  /// Let the kernel verify the files and share their extents in place with `FIDEDUPERANGE`, on
+  /// btrfs and XFS. Nothing is renamed, so there is no window where the redundant path is
+  /// replaced.
+  DedupeIoctl,
+  /// Replace it with a symbolic link to the original, which is absolute unless
+  /// `--relative-symlinks` is set.
+  Symlink,
+  /// Remove it, keeping only the original. Requires `--force` or a confirmation.
+  Delete,
+}
+
+impl Strategy {
+  pub fn get() -> Self {
+    DedupArgs::get().strategy
+  }
+
+  /// Whether the original and the redundant path end up as the same file, which should be readonly
+  /// to avoid surprises when either of them is written.
+  pub fn shares_file(self) -> bool {
+    match self {
+      Strategy::HardLink | Strategy::Symlink => true,
+      Strategy::Reflink | Strategy::DedupeIoctl | Strategy::Delete => false,
+    }
+  }
+}
+
+fn absolute(path: &Path) -> io::Result<PathBuf> {
+  if path.is_absolute() {
+    Ok(path.to_owned())
+  } else {
+    Ok(env::current_dir()?.join(path))
+  }
+}
+
+/// The path of `target` relative to the directory `base`. Both must be absolute.
+fn relative_to(target: &Path, base: &Path) -> PathBuf {
+  let (target, base) = (
+    target.components().collect::<Vec<_>>(),
+    base.components().collect::<Vec<_>>(),
+  );
+  let common = target
+    .iter()
+    .zip(&base)
+    .take_while(|(target, base)| target == base)
+    .count();
+  base[common..]
+    .iter()
+    .filter(|component| matches!(component, Component::Normal(_)))
+    .map(|_| Component::ParentDir)
+    .chain(target[common..].iter().copied())
+    .collect()
+}
+
+/// Creates `link` as a symbolic link to `original`.
+pub async fn symlink(original: &Path, link: &Path) -> io::Result<()> {
+  let original = absolute(original)?;
+  let target = if DedupArgs::get().relative_symlinks {
+    let link = absolute(link)?;
+    relative_to(&original, link.parent().unwrap_or(&link))
+  } else {
+    original
+  };
+  #[cfg(unix)]
+  tokio::fs::symlink(target, link).await?;
+  #[cfg(windows)]
+  tokio::fs::symlink_file(target, link).await?;
+  Ok(())
+}
+
+/// Atomically swaps the files at `a` and `b` with `renameat2(RENAME_EXCHANGE)`. Returns `false`
+/// if the kernel or file system doesn't support it.
+#[cfg(target_os = "linux")]
+pub fn exchange(a: &Path, b: &Path) -> io::Result<bool> {
+  use std::{ffi::CString, os::unix::ffi::OsStrExt};
+  const RENAME_EXCHANGE: libc::c_uint = 1 << 1;
+  let (a, b) = (
+    CString::new(a.as_os_str().as_bytes())?,
+    CString::new(b.as_os_str().as_bytes())?,
+  );
+  // SAFETY: Both paths are valid C strings for the duration of the call.
+  let result = unsafe {
+    libc::syscall(
+      libc::SYS_renameat2,
+      libc::AT_FDCWD,
+      a.as_ptr(),
+      libc::AT_FDCWD,
+      b.as_ptr(),
+      RENAME_EXCHANGE,
+    )
+  };
+  if result != 0 {
+    let error = io::Error::last_os_error();
+    return match error.raw_os_error() {
+      Some(libc::EINVAL | libc::ENOSYS | libc::EOPNOTSUPP) => Ok(false),
+      _ => Err(error),
+    };
+  }
+  Ok(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn exchange(_a: &Path, _b: &Path) -> io::Result<bool> {
+  Ok(false)
+}
+
+/// Creates `clone` as a copy-on-write clone of `original`, sharing its extents.
+#[cfg(target_os = "linux")]
+pub fn reflink(original: &Path, clone: &Path) -> io::Result<()> {
+  use std::{
+    fs::{self, File, OpenOptions},
+    os::fd::AsRawFd,
+  };
+  const FICLONE: libc::c_ulong = 0x4004_9409;
+  let source = File::open(original)?;
+  let target = OpenOptions::new()
+    .write(true)
+    .create_new(true)
+    .open(clone)?;
+  // SAFETY: Both file descriptors stay open for the duration of the call.
+  if unsafe { libc::ioctl(target.as_raw_fd(), FICLONE as _, source.as_raw_fd()) } != 0 {
+    let error = io::Error::last_os_error();
+    drop(target);
+    let _ = fs::remove_file(clone);
+    return Err(error);
+  }
+  Ok(())
+}
+
+/// Creates `clone` as a copy-on-write clone of `original`, sharing its extents.
+#[cfg(target_os = "macos")]
+pub fn reflink(original: &Path, clone: &Path) -> io::Result<()> {
+  use std::{ffi::CString, os::unix::ffi::OsStrExt};
+  let original = CString::new(original.as_os_str().as_bytes())?;
+  let clone = CString::new(clone.as_os_str().as_bytes())?;
+  // SAFETY: Both paths are valid C strings for the duration of the call.
+  if unsafe { libc::clonefile(original.as_ptr(), clone.as_ptr(), 0) } != 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(())
+}
+
+/// Shares the extents of `original` with `redundant` with `FIDEDUPERANGE`, after the kernel has
+/// verified that their contents are equal.
+#[cfg(target_os = "linux")]
+pub fn dedupe_range(original: &Path, redundant: &Path, size: u64) -> io::Result<()> {
+  use std::{
+    fs::{File, OpenOptions},
+    os::fd::AsRawFd,
+  };
+
+  /// `struct file_dedupe_range_info` from `linux/fs.h`.
+  #[repr(C)]
+  struct DedupeRangeInfo {
+    dest_fd: i64,
+    dest_offset: u64,
+    bytes_deduped: u64,
+    status: i32,
+    reserved: u32,
+  }
+
+  /// `struct file_dedupe_range` from `linux/fs.h`, with room for a single destination.
+  #[repr(C)]
+  struct DedupeRange {
+    src_offset: u64,
+    src_length: u64,
+    dest_count: u16,
+    reserved1: u16,
+    reserved2: u32,
+    info: DedupeRangeInfo,
+  }
+
+  const FIDEDUPERANGE: libc::c_ulong = 0xC018_9436;
+  const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+  /// The kernel may dedupe less than asked for, and some filesystems cap every call at 16 MiB.
+  const MAX_LENGTH: u64 = 16 * 1024 * 1024;
+
+  let source = File::open(original)?;
+  // Only owners and administrators may dedupe into files that aren't open for writing.
+  let target = OpenOptions::new()
+    .read(true)
+    .write(true)
+    .open(redundant)
+    .or_else(|_| File::open(redundant))?;
+  let mut offset = 0;
+  while offset < size {
+    let mut range = DedupeRange {
+      src_offset: offset,
+      src_length: (size - offset).min(MAX_LENGTH),
+      dest_count: 1,
+      reserved1: 0,
+      reserved2: 0,
+      info: DedupeRangeInfo {
+        dest_fd: target.as_raw_fd().into(),
+        dest_offset: offset,
+        bytes_deduped: 0,
+        status: 0,
+        reserved: 0,
+      },
+    };
+    // SAFETY: `range` is a valid `file_dedupe_range` with one destination, and both file
+    // descriptors stay open for the duration of the call.
+    if unsafe { libc::ioctl(source.as_raw_fd(), FIDEDUPERANGE as _, &mut range) } != 0 {
+      return Err(io::Error::last_os_error());
+    }
+    match range.info.status {
+      status if status < 0 => return Err(io::Error::from_raw_os_error(-status)),
+      FILE_DEDUPE_RANGE_DIFFERS => {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidData,
+          "The kernel found that the files differ",
+        ))
+      }
+      _ if range.info.bytes_deduped == 0 => {
+        return Err(io::Error::new(
+          io::ErrorKind::UnexpectedEof,
+          "The kernel stopped deduping before the end of the file",
+        ))
+      }
+      _ => offset += range.info.bytes_deduped,
+    }
+  }
+  Ok(())
+}
+
+/// Creates `clone` as a block clone of `original` with `FSCTL_DUPLICATE_EXTENTS_TO_FILE`, on ReFS.
+#[cfg(windows)]
+pub fn reflink(original: &Path, clone: &Path) -> io::Result<()> {
+  use std::{
+    ffi::c_void,
+    fs::{self, File, OpenOptions},
+    mem,
+    os::windows::{fs::MetadataExt, io::AsRawHandle},
+    ptr,
+  };
+
+  /// `DUPLICATE_EXTENTS_DATA` from `winioctl.h`.
+  #[repr(C)]
+  struct DuplicateExtentsData {
+    file_handle: isize,
+    source_file_offset: i64,
+    target_file_offset: i64,
+    byte_count: i64,
+  }
+
+  /// `FSCTL_GET_INTEGRITY_INFORMATION_BUFFER` from `winioctl.h`.
+  #[repr(C)]
+  #[derive(Default)]
+  struct IntegrityInformation {
+    checksum_algorithm: u16,
+    reserved: u16,
+    flags: u32,
+    checksum_chunk_size_in_bytes: u32,
+    cluster_size_in_bytes: u32,
+  }
+
+  /// `FSCTL_SET_INTEGRITY_INFORMATION_BUFFER` from `winioctl.h`.
+  #[repr(C)]
+  struct SetIntegrityInformation {
+    checksum_algorithm: u16,
+    reserved: u16,
+    flags: u32,
+  }
+
+  #[link(name = "kernel32")]
+  extern "system" {
+    fn DeviceIoControl(
+      device: isize,
+      io_control_code: u32,
+      in_buffer: *const c_void,
+      in_buffer_size: u32,
+      out_buffer: *mut c_void,
+      out_buffer_size: u32,
+      bytes_returned: *mut u32,
+      overlapped: *mut c_void,
+    ) -> i32;
+  }
+
+  const FSCTL_DUPLICATE_EXTENTS_TO_FILE: u32 = 0x0009_8344;
+  const FSCTL_GET_INTEGRITY_INFORMATION: u32 = 0x0009_027C;
+  const FSCTL_SET_INTEGRITY_INFORMATION: u32 = 0x0009_C280;
+  const FSCTL_SET_SPARSE: u32 = 0x0009_00C4;
+  const FILE_ATTRIBUTE_SPARSE_FILE: u32 = 0x200;
+  /// Every call must clone less than 4 GiB.
+  const MAX_LENGTH: u64 = 1024 * 1024 * 1024;
+
+  /// Sends `code` to `file`, where `()` is used for an empty input or output buffer.
+  fn control<I, O>(file: &File, code: u32, input: &I, output: &mut O) -> io::Result<()> {
+    let mut returned = 0;
+    // SAFETY: The buffers are valid for their sizes and the handle stays open for the duration of
+    // the call.
+    let result = unsafe {
+      DeviceIoControl(
+        file.as_raw_handle() as isize,
+        code,
+        (input as *const I).cast(),
+        mem::size_of::<I>() as u32,
+        (output as *mut O).cast(),
+        mem::size_of::<O>() as u32,
+        &mut returned,
+        ptr::null_mut(),
+      )
+    };
+    if result == 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(())
+  }
+
+  let source = File::open(original)?;
+  let metadata = source.metadata()?;
+  let target = OpenOptions::new()
+    .read(true)
+    .write(true)
+    .create_new(true)
+    .open(clone)?;
+  let result = (|| {
+    // The cloned regions must be cluster aligned, and both files need the same integrity and
+    // sparse settings.
+    let mut integrity = IntegrityInformation::default();
+    control(
+      &source,
+      FSCTL_GET_INTEGRITY_INFORMATION,
+      &(),
+      &mut integrity,
+    )?;
+    let set_integrity = SetIntegrityInformation {
+      checksum_algorithm: integrity.checksum_algorithm,
+      reserved: 0,
+      flags: integrity.flags,
+    };
+    control(
+      &target,
+      FSCTL_SET_INTEGRITY_INFORMATION,
+      &set_integrity,
+      &mut (),
+    )?;
+    if metadata.file_attributes() & FILE_ATTRIBUTE_SPARSE_FILE != 0 {
+      control(&target, FSCTL_SET_SPARSE, &(), &mut ())?;
+    }
+    let size = metadata.len();
+    target.set_len(size)?;
+    let cluster_size = u64::from(integrity.cluster_size_in_bytes.max(1));
+    let aligned_size = (size + cluster_size - 1) / cluster_size * cluster_size;
+    let mut offset = 0;
+    while offset < aligned_size {
+      let length = (aligned_size - offset).min(MAX_LENGTH);
+      let extents = DuplicateExtentsData {
+        file_handle: source.as_raw_handle() as isize,
+        source_file_offset: offset as i64,
+        target_file_offset: offset as i64,
+        byte_count: length as i64,
+      };
+      control(&target, FSCTL_DUPLICATE_EXTENTS_TO_FILE, &extents, &mut ())?;
+      offset += length;
+    }
+    Ok(())
+  })();
+  if result.is_err() {
+    drop(target);
+    let _ = fs::remove_file(clone);
+  }
+  result
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn dedupe_range(_original: &Path, _redundant: &Path, _size: u64) -> io::Result<()> {
+  Err(io::Error::new(
+    io::ErrorKind::Unsupported,
+    "FIDEDUPERANGE is only supported on Linux",
+  ))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+pub fn reflink(_original: &Path, _clone: &Path) -> io::Result<()> {
+  Err(io::Error::new(
+    io::ErrorKind::Unsupported,
+    "Reflinks are not supported on this platform",
+  ))
+}
+
+/// Gives `replacement` the owner, group, mode and extended attributes of `redundant`, which include
+/// its POSIX ACL.
+#[cfg(unix)]
+pub fn copy_metadata(redundant: &Path, replacement: &Path) -> io::Result<()> {
+  use std::{
+    ffi::CString,
+    fs,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+  };
+  let metadata = fs::metadata(redundant)?;
+  let path = CString::new(replacement.as_os_str().as_bytes())?;
+  // SAFETY: The path is a valid C string for the duration of the call.
+  if unsafe { libc::chown(path.as_ptr(), metadata.uid(), metadata.gid()) } != 0 {
+    return Err(io::Error::last_os_error());
+  }
+  for (name, value) in crate::xattrs::read(redundant)? {
+    xattr::set(replacement, name, &value)?;
+  }
+  // Changing the owner clears the setuid and setgid bits, so the mode is set last.
+  fs::set_permissions(replacement, metadata.permissions())
+}
+
+/// Gives `replacement` the owner, group, DACL and readonly attribute of `redundant`.
+#[cfg(windows)]
+pub fn copy_metadata(redundant: &Path, replacement: &Path) -> io::Result<()> {
+  use std::{ffi::c_void, fs, os::windows::ffi::OsStrExt, ptr};
+  const OWNER_SECURITY_INFORMATION: u32 = 0x1;
+  const GROUP_SECURITY_INFORMATION: u32 = 0x2;
+  const DACL_SECURITY_INFORMATION: u32 = 0x4;
+  const INFORMATION: u32 =
+    OWNER_SECURITY_INFORMATION | GROUP_SECURITY_INFORMATION | DACL_SECURITY_INFORMATION;
+  #[link(name = "advapi32")]
+  extern "system" {
+    fn GetFileSecurityW(
+      file_name: *const u16,
+      requested_information: u32,
+      security_descriptor: *mut c_void,
+      length: u32,
+      length_needed: *mut u32,
+    ) -> i32;
+    fn SetFileSecurityW(
+      file_name: *const u16,
+      security_information: u32,
+      security_descriptor: *const c_void,
+    ) -> i32;
+  }
+
+  let wide = |path: &Path| {
+    path
+      .as_os_str()
+      .encode_wide()
+      .chain([0])
+      .collect::<Vec<_>>()
+  };
+  let (source, target) = (wide(redundant), wide(replacement));
+  let mut length = 0;
+  // SAFETY: Only the needed length is written when no buffer is given.
+  unsafe {
+    GetFileSecurityW(
+      source.as_ptr(),
+      INFORMATION,
+      ptr::null_mut(),
+      0,
+      &mut length,
+    )
+  };
+  let mut descriptor = vec![0u8; length as usize];
+  // SAFETY: The buffer is as long as the call asked for.
+  if unsafe {
+    GetFileSecurityW(
+      source.as_ptr(),
+      INFORMATION,
+      descriptor.as_mut_ptr().cast(),
+      length,
+      &mut length,
+    )
+  } == 0
+  {
+    return Err(io::Error::last_os_error());
+  }
+  // SAFETY: The descriptor was written by `GetFileSecurityW`, and outlives the call.
+  if unsafe { SetFileSecurityW(target.as_ptr(), INFORMATION, descriptor.as_ptr().cast()) } == 0 {
+    return Err(io::Error::last_os_error());
+  }
+  fs::set_permissions(replacement, fs::metadata(redundant)?.permissions())
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn copy_metadata(redundant: &Path, replacement: &Path) -> io::Result<()> {
+  std::fs::set_permissions(replacement, std::fs::metadata(redundant)?.permissions())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn relative(target: &str, base: &str) -> PathBuf {
+    relative_to(Path::new(target), Path::new(base))
+  }
+
+  #[test]
+  fn relative_paths_walk_up_to_the_common_dir() {
+    assert_eq!(relative("/a/b/file", "/a/b"), Path::new("file"));
+    assert_eq!(relative("/a/b/file", "/a/c"), Path::new("../b/file"));
+    assert_eq!(
+      relative("/a/b/c/file", "/a/d/e"),
+      Path::new("../../b/c/file")
+    );
+    assert_eq!(relative("/a/file", "/a/b/c"), Path::new("../../file"));
+    assert_eq!(relative("/file", "/a"), Path::new("../file"));
+  }
+
+  #[test]
+  fn relative_path_of_the_base_is_empty() {
+    assert_eq!(relative("/a/b", "/a/b"), Path::new(""));
+    assert_eq!(relative("/a", "/a/b"), Path::new(".."));
+  }
+}
@@ -0,0 +1,68 @@
+use std::{
+  sync::OnceLock,
+  time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use tokio::{sync::Mutex, time::sleep};
+
+use crate::DedupArgs;
+
+/// Parses the rate of `--max-read-mbps`, which has to let something be read.
+pub fn parse_rate(mbps: &str) -> Result<f64> {
+  let rate: f64 = mbps
+    .parse()
+    .with_context(|| format!("Invalid rate {mbps:?}"))?;
+  if !rate.is_finite() || rate <= 0.0 {
+    bail!("The rate must be a positive number of MiB per second");
+  }
+  Ok(rate)
+}
+
+/// A token bucket holding up to one second worth of reads.
+struct Bucket {
+  /// Bytes per second.
+  rate: f64,
+  /// Bytes that can be read right away. Negative if reads are waiting.
+  available: f64,
+  updated: Instant,
+}
+
+static BUCKET: OnceLock<Option<Mutex<Bucket>>> = OnceLock::new();
+
+fn get_bucket() -> Option<&'static Mutex<Bucket>> {
+  BUCKET
+    .get_or_init(|| {
+      DedupArgs::get().max_read_mbps.map(|mbps| {
+        let rate = mbps * 1024.0 * 1024.0;
+        Mutex::new(Bucket {
+          rate,
+          available: rate,
+          updated: Instant::now(),
+        })
+      })
+    })
+    .as_ref()
+}
+
+/// Accounts for `bytes` read by a hashing worker, and waits until they fit in `--max-read-mbps`.
+/// The bucket stays locked while waiting, so that the workers are throttled in turn.
+pub async fn consume(bytes: impl TryInto<u64>) {
+  let Some(bucket) = get_bucket() else {
+    return;
+  };
+  let bytes = bytes.try_into().unwrap_or(u64::MAX) as f64;
+  let mut bucket = bucket.lock().await;
+  let now = Instant::now();
+  bucket.available = (bucket.available
+    + now.duration_since(bucket.updated).as_secs_f64() * bucket.rate)
+    .min(bucket.rate);
+  bucket.updated = now;
+  bucket.available -= bytes;
+  if bucket.available < 0.0 {
+    // Tiny rates may wait longer than a `Duration` can hold.
+    let wait =
+      Duration::try_from_secs_f64(-bucket.available / bucket.rate).unwrap_or(Duration::MAX);
+    sleep(wait).await;
+  }
+}
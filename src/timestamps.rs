@@ -0,0 +1,57 @@
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+use filetime::FileTime;
+use tokio::fs;
+
+use crate::DedupArgs;
+
+/// Which modification time the redundant path has after a merge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TimestampPolicy {
+  /// The modification time of the original.
+  Original,
+  /// The newest modification time of the two files.
+  Newest,
+  /// The oldest modification time of the two files.
+  Oldest,
+  /// The modification time the redundant file had before the merge.
+  Redundant,
+}
+
+impl TimestampPolicy {
+  pub fn get() -> Self {
+    DedupArgs::get().preserve_timestamps
+  }
+}
+
+/// The modification times of a pair of files about to be merged.
+pub struct Timestamps {
+  original: FileTime,
+  redundant: FileTime,
+}
+
+impl Timestamps {
+  pub async fn read(original: &Path, redundant: &Path) -> std::io::Result<Self> {
+    Ok(Timestamps {
+      original: FileTime::from_last_modification_time(&fs::metadata(original).await?),
+      redundant: FileTime::from_last_modification_time(&fs::metadata(redundant).await?),
+    })
+  }
+
+  /// Sets the modification time chosen by `--preserve-timestamps` on the file at `redundant`, which
+  /// for hard links is the original as well.
+  pub async fn apply(self, redundant: &Path) -> std::io::Result<()> {
+    let mtime = match TimestampPolicy::get() {
+      TimestampPolicy::Original => self.original,
+      TimestampPolicy::Newest => self.original.max(self.redundant),
+      TimestampPolicy::Oldest => self.original.min(self.redundant),
+      TimestampPolicy::Redundant => self.redundant,
+    };
+    if FileTime::from_last_modification_time(&fs::metadata(redundant).await?) == mtime {
+      return Ok(());
+    }
+    let redundant = PathBuf::from(redundant);
+    tokio::task::spawn_blocking(move || filetime::set_file_mtime(redundant, mtime)).await?
+  }
+}
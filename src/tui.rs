@@ -0,0 +1,247 @@
+use std::{
+  io::{self, IsTerminal, Stderr},
+  path::Path,
+};
+
+use anyhow::{bail, Result};
+use crossterm::{
+  event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+  execute,
+  terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use ratatui::{
+  prelude::*,
+  widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+};
+
+use crate::{keep::Group, output, progress, DedupArgs};
+
+const HELP: &str =
+  "↑/↓ move  Tab switch pane  Enter keep file  Space merge/skip  a merge all  n skip \
+                    all  x execute  q quit";
+
+/// Fails if `--tui` has no terminal to draw on.
+pub fn init() -> Result<()> {
+  if DedupArgs::get().tui && !(io::stdin().is_terminal() && io::stderr().is_terminal()) {
+    bail!("--tui requires a terminal");
+  }
+  Ok(())
+}
+
+/// Restores the terminal when the browser is closed, even by an error.
+struct Screen;
+
+impl Screen {
+  fn enter() -> io::Result<Self> {
+    terminal::enable_raw_mode()?;
+    let screen = Self;
+    execute!(io::stderr(), EnterAlternateScreen)?;
+    Ok(screen)
+  }
+}
+
+impl Drop for Screen {
+  fn drop(&mut self) {
+    let _ = execute!(io::stderr(), LeaveAlternateScreen);
+    let _ = terminal::disable_raw_mode();
+  }
+}
+
+#[derive(PartialEq, Eq)]
+enum Focus {
+  Groups,
+  Files,
+}
+
+struct Browser {
+  groups: Vec<Group>,
+  group: ListState,
+  file: ListState,
+  focus: Focus,
+}
+
+/// Moves the selection of `state` by `step`, within `len` items.
+fn step(state: &mut ListState, len: usize, step: isize) {
+  let selected = state.selected().unwrap_or(0) as isize + step;
+  state.select(Some(
+    selected.clamp(0, len.saturating_sub(1) as isize) as usize
+  ));
+}
+
+fn show_paths(paths: &[impl AsRef<Path>]) -> String {
+  paths
+    .iter()
+    .map(|path| path.as_ref().display().to_string())
+    .collect::<Vec<_>>()
+    .join(", ")
+}
+
+impl Browser {
+  fn current(&self) -> &Group {
+    &self.groups[self.group.selected().unwrap_or(0)]
+  }
+
+  fn current_mut(&mut self) -> &mut Group {
+    &mut self.groups[self.group.selected().unwrap_or(0)]
+  }
+
+  fn select_group(&mut self, offset: isize) {
+    step(&mut self.group, self.groups.len(), offset);
+    self.file.select(Some(self.current().kept));
+  }
+
+  fn draw(&mut self, frame: &mut Frame) {
+    let [groups_area, files_area, help_area] = *Layout::default()
+      .direction(Direction::Vertical)
+      .constraints([
+        Constraint::Percentage(50),
+        Constraint::Min(3),
+        Constraint::Length(1),
+      ])
+      .split(frame.size())
+    else {
+      unreachable!("The layout has three parts")
+    };
+    let block = |title: String, focused: bool| {
+      let block = Block::default().borders(Borders::ALL).title(title);
+      if focused {
+        block.border_style(Style::default().fg(Color::Yellow))
+      } else {
+        block
+      }
+    };
+    let highlight = Style::default().add_modifier(Modifier::REVERSED);
+
+    let total = self
+      .groups
+      .iter()
+      .filter(|group| group.apply)
+      .map(Group::wasted)
+      .sum::<u64>();
+    let title = format!(
+      " Duplicate groups ({} to save) ",
+      output::format_size(total)
+    );
+    let groups = self
+      .groups
+      .iter()
+      .map(|group| {
+        let kept = &group.candidates[group.kept];
+        ListItem::new(format!(
+          "[{}] {:>10}  {} copies of {}  {}",
+          if group.apply { 'x' } else { ' ' },
+          output::format_size(group.wasted()),
+          group.candidates.len(),
+          output::format_size(group.size),
+          kept.path().display()
+        ))
+      })
+      .collect::<Vec<_>>();
+    let groups = List::new(groups)
+      .block(block(title, self.focus == Focus::Groups))
+      .highlight_style(highlight);
+    frame.render_stateful_widget(groups, groups_area, &mut self.group);
+
+    let group = self.current();
+    let files = group
+      .candidates
+      .iter()
+      .enumerate()
+      .map(|(index, candidate)| {
+        let mut line = format!(
+          "{} {}",
+          if index == group.kept { "keep" } else { "    " },
+          show_paths(&candidate.paths)
+        );
+        if candidate.is_protected() {
+          line.push_str(" (protected)");
+        }
+        ListItem::new(line)
+      })
+      .collect::<Vec<_>>();
+    let files = List::new(files)
+      .block(block(" Files ".into(), self.focus == Focus::Files))
+      .highlight_style(highlight);
+    frame.render_stateful_widget(files, files_area, &mut self.file);
+    frame.render_widget(Paragraph::new(HELP), help_area);
+  }
+
+  /// Handles a key, and returns whether the browser is done.
+  fn key(&mut self, code: KeyCode, modifiers: KeyModifiers) -> bool {
+    let files = self.current().candidates.len();
+    match code {
+      KeyCode::Up | KeyCode::Char('k') if self.focus == Focus::Groups => self.select_group(-1),
+      KeyCode::Down | KeyCode::Char('j') if self.focus == Focus::Groups => self.select_group(1),
+      KeyCode::PageUp if self.focus == Focus::Groups => self.select_group(-10),
+      KeyCode::PageDown if self.focus == Focus::Groups => self.select_group(10),
+      KeyCode::Up | KeyCode::Char('k') => step(&mut self.file, files, -1),
+      KeyCode::Down | KeyCode::Char('j') => step(&mut self.file, files, 1),
+      KeyCode::Tab | KeyCode::BackTab | KeyCode::Left | KeyCode::Right => {
+        self.focus = match self.focus {
+          Focus::Groups => Focus::Files,
+          Focus::Files => Focus::Groups,
+        };
+      }
+      KeyCode::Enter if self.focus == Focus::Groups => self.focus = Focus::Files,
+      KeyCode::Enter => {
+        let kept = self.file.selected().unwrap_or(0);
+        let group = self.current_mut();
+        group.kept = kept;
+        group.apply = true;
+      }
+      KeyCode::Char(' ') => {
+        let group = self.current_mut();
+        group.apply = !group.apply;
+      }
+      KeyCode::Char(answer @ ('a' | 'n')) => {
+        for group in &mut self.groups {
+          group.apply = answer == 'a';
+        }
+      }
+      KeyCode::Char('x') => return true,
+      KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => return self.quit(),
+      KeyCode::Char('q') | KeyCode::Esc => return self.quit(),
+      _ => (),
+    }
+    false
+  }
+
+  /// Leaves every group unmerged.
+  fn quit(&mut self) -> bool {
+    for group in &mut self.groups {
+      group.apply = false;
+    }
+    true
+  }
+
+  fn run(&mut self, terminal: &mut Terminal<CrosstermBackend<Stderr>>) -> Result<()> {
+    loop {
+      terminal.draw(|frame| self.draw(frame))?;
+      if let Event::Key(key) = event::read()? {
+        // Windows reports releases as well.
+        if key.kind == KeyEventKind::Press && self.key(key.code, key.modifiers) {
+          return Ok(());
+        }
+      }
+    }
+  }
+}
+
+/// Lets the user browse `groups` by wasted space, choose the file to keep in each and the groups
+/// to merge. Quitting the browser skips every group.
+pub fn browse(mut groups: Vec<Group>) -> Result<Vec<Group>> {
+  groups.sort_by_key(|group| std::cmp::Reverse(group.wasted()));
+  let kept = groups[0].kept;
+  let mut browser = Browser {
+    groups,
+    group: ListState::default().with_selected(Some(0)),
+    file: ListState::default().with_selected(Some(kept)),
+    focus: Focus::Groups,
+  };
+  progress::suspend(|| -> Result<()> {
+    let _screen = Screen::enter()?;
+    let mut terminal = Terminal::new(CrosstermBackend::new(io::stderr()))?;
+    browser.run(&mut terminal)
+  })?;
+  Ok(browser.groups)
+}
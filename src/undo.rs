@@ -0,0 +1,335 @@
+use std::{
+  fs::{File, OpenOptions, Permissions},
+  io::{self, Read, Write},
+  path::{Path, PathBuf},
+  sync::{Mutex, OnceLock},
+};
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use tokio::fs;
+
+use crate::{
+  input::bytes_to_path,
+  os::{read_link_metadata, FileLinkBackend},
+  output::{self, path_bytes},
+  strategy, DedupArgs, Strategy,
+};
+
+static UNDO_LOG: OnceLock<Option<Mutex<File>>> = OnceLock::new();
+
+#[cfg(unix)]
+fn mode_string(permissions: &Permissions) -> String {
+  use std::os::unix::fs::PermissionsExt;
+  format!("{:o}", permissions.mode())
+}
+
+#[cfg(unix)]
+async fn restore_mode(path: &Path, mode: &str) -> Result<()> {
+  use std::os::unix::fs::PermissionsExt;
+  let mode = u32::from_str_radix(mode, 8).context("Invalid mode")?;
+  fs::set_permissions(path, Permissions::from_mode(mode)).await?;
+  Ok(())
+}
+
+#[cfg(windows)]
+fn mode_string(permissions: &Permissions) -> String {
+  if permissions.readonly() {
+    "readonly".to_owned()
+  } else {
+    "writable".to_owned()
+  }
+}
+
+#[cfg(windows)]
+async fn restore_mode(path: &Path, mode: &str) -> Result<()> {
+  let mut permissions = fs::metadata(path).await?.permissions();
+  permissions.set_readonly(match mode {
+    "readonly" => true,
+    "writable" => false,
+    _ => bail!("Invalid mode"),
+  });
+  fs::set_permissions(path, permissions).await?;
+  Ok(())
+}
+
+/// Opens the `--undo-log`, if any.
+pub fn init() -> Result<()> {
+  let args = DedupArgs::get();
+  let undo_log = match args.undo_log {
+    Some(ref path) => Some(Mutex::new(
+      OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Could not open undo log {}", path.display()))?,
+    )),
+    None => None,
+  };
+  let _ = UNDO_LOG.set(undo_log);
+  Ok(())
+}
+
+/// Records a merge that is about to happen, along with the current modes of both files.
+///
+/// Every record is five NUL terminated fields, prefixed by a newline: the `--strategy` of the
+/// merge, the original path, the redundant path, the mode of the original and the mode of the
+/// redundant file.
+pub async fn record(original: &Path, redundant: &Path) -> Result<()> {
+  let Some(Some(undo_log)) = UNDO_LOG.get() else {
+    return Ok(());
+  };
+  let original_mode = mode_string(&fs::metadata(original).await?.permissions());
+  let redundant_mode = mode_string(&fs::metadata(redundant).await?.permissions());
+  let strategy = Strategy::get()
+    .to_possible_value()
+    .expect("No strategy is skipped");
+  let mut record = b"\n".to_vec();
+  for field in [
+    strategy.get_name().as_bytes(),
+    path_bytes(original).as_ref(),
+    path_bytes(redundant).as_ref(),
+    original_mode.as_bytes(),
+    redundant_mode.as_bytes(),
+  ] {
+    record.extend_from_slice(field);
+    record.push(b'\0');
+  }
+  let mut undo_log = undo_log.lock().unwrap_or_else(|e| e.into_inner());
+  undo_log
+    .write_all(&record)
+    .and_then(|()| undo_log.sync_data())
+    .context("Could not write to undo log")
+}
+
+/// Copies `from` to `to` byte by byte, so that the copy doesn't share storage with it the way
+/// `fs::copy` may.
+fn copy_unshared(from: &Path, to: &Path) -> io::Result<()> {
+  let (mut from, mut to) = (File::open(from)?, File::create(to)?);
+  let mut buffer = vec![0; 1 << 20];
+  loop {
+    match from.read(&mut buffer)? {
+      0 => break,
+      read => to.write_all(&buffer[..read])?,
+    }
+  }
+  to.sync_all()
+}
+
+/// Whether the redundant path is still what the merge left there.
+async fn is_merged(strategy: Strategy, original: &Path, redundant: &Path) -> Result<bool> {
+  let original_metadata = read_link_metadata(original).await?;
+  if !fs::try_exists(redundant).await? {
+    return Ok(strategy == Strategy::Delete);
+  }
+  Ok(match strategy {
+    Strategy::HardLink => {
+      read_link_metadata(redundant).await?.get_file_uid() == original_metadata.get_file_uid()
+    }
+    Strategy::Symlink => {
+      fs::symlink_metadata(redundant).await?.is_symlink()
+        && fs::canonicalize(redundant).await? == fs::canonicalize(original).await?
+    }
+    // The redundant file is still a file of its own, which only shares storage with the original.
+    Strategy::Reflink | Strategy::DedupeIoctl => {
+      read_link_metadata(redundant).await?.get_file_uid() != original_metadata.get_file_uid()
+    }
+    Strategy::Delete => false,
+  })
+}
+
+async fn undo_merge(
+  strategy: Strategy,
+  original: &Path,
+  redundant: &Path,
+  (original_mode, redundant_mode): (&str, &str),
+) -> Result<bool> {
+  if !is_merged(strategy, original, redundant).await? {
+    return Ok(false);
+  }
+  let sign = if DedupArgs::get().dry_run {
+    '↬'
+  } else {
+    '⇒'
+  };
+  output::print_info(format!(
+    "{} {sign} {}",
+    original.display(),
+    redundant.display()
+  ))?;
+  if DedupArgs::get().dry_run {
+    return Ok(true);
+  }
+  let mut copy = redundant.as_os_str().to_owned();
+  copy.push(".undo");
+  if strategy.shares_file() || strategy == Strategy::Delete {
+    fs::copy(original, &copy).await?;
+  } else {
+    // Only the storage is shared, so the redundant file gets storage of its own again.
+    let (from, to) = (redundant.to_owned(), PathBuf::from(&copy));
+    let copied = tokio::task::spawn_blocking(move || {
+      copy_unshared(&from, &to).and_then(|()| strategy::copy_metadata(&from, &to))
+    })
+    .await?;
+    if let Err(e) = copied {
+      let _ = fs::remove_file(&copy).await;
+      return Err(e)?;
+    }
+  }
+  restore_mode(Path::new(&copy), redundant_mode).await?;
+  if let Err(e) = fs::rename(&copy, redundant).await {
+    fs::remove_file(&copy).await?;
+    return Err(e)?;
+  }
+  restore_mode(original, original_mode).await?;
+  Ok(true)
+}
+
+/// Copies the content of every original in `undo_log` back to its redundant paths, or gives
+/// reflinked and deduped files storage of their own, and restores the modes both files had before
+/// they were merged.
+pub async fn undo(undo_log: &Path) -> Result<()> {
+  let content = fs::read(undo_log)
+    .await
+    .with_context(|| format!("Could not read undo log {}", undo_log.display()))?;
+  let Some(content) = content.strip_suffix(b"\0") else {
+    return Ok(());
+  };
+  let fields = content.split(|byte| *byte == b'\0').collect::<Vec<_>>();
+  if fields.len() % 5 != 0 {
+    bail!("Truncated undo log {}", undo_log.display());
+  }
+  let (mut undone, mut skipped) = (0, 0);
+  for record in fields.chunks_exact(5).rev() {
+    let [strategy, original, redundant, original_mode, redundant_mode] = record else {
+      unreachable!()
+    };
+    let strategy = String::from_utf8_lossy(strategy.strip_prefix(b"\n").unwrap_or(strategy));
+    let Ok(strategy) = Strategy::from_str(&strategy, false) else {
+      bail!(
+        "Invalid strategy {strategy:?} in undo log {}",
+        undo_log.display()
+      );
+    };
+    let original = bytes_to_path(original.to_vec())?;
+    let redundant = bytes_to_path(redundant.to_vec())?;
+    let result = undo_merge(
+      strategy,
+      &original,
+      &redundant,
+      (
+        &String::from_utf8_lossy(original_mode),
+        &String::from_utf8_lossy(redundant_mode),
+      ),
+    )
+    .await
+    .with_context(|| {
+      format!(
+        "Could not undo merge of {} to {}",
+        redundant.display(),
+        original.display()
+      )
+    });
+    match result {
+      Ok(true) => undone += 1,
+      Ok(false) => skipped += 1,
+      Err(e) => output::print_error(e),
+    }
+  }
+  output::print_summary(format!(
+    "{undone} merges undone, {skipped} skipped since the files changed after they were merged"
+  ))?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// An empty directory for the test `name`.
+  fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+      "hard-link-dedup-undo-{name}-{}",
+      std::process::id()
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  fn mode(path: &Path) -> String {
+    mode_string(&std::fs::metadata(path).unwrap().permissions())
+  }
+
+  /// Undoes a merge of `redundant` into `original` with the modes they have now.
+  async fn undo(strategy: Strategy, original: &Path, redundant: &Path) -> bool {
+    let original_mode = mode(original);
+    undo_merge(
+      strategy,
+      original,
+      redundant,
+      (&original_mode, &original_mode),
+    )
+    .await
+    .unwrap()
+  }
+
+  async fn is_same_file(first: &Path, second: &Path) -> bool {
+    let first = read_link_metadata(first).await.unwrap().get_file_uid();
+    first == read_link_metadata(second).await.unwrap().get_file_uid()
+  }
+
+  #[tokio::test]
+  async fn hard_links_and_deleted_files_are_copied_back() {
+    let dir = test_dir("copy");
+    let (original, linked, deleted) = (dir.join("o"), dir.join("l"), dir.join("d"));
+    std::fs::write(&original, b"content").unwrap();
+    std::fs::hard_link(&original, &linked).unwrap();
+    assert!(undo(Strategy::HardLink, &original, &linked).await);
+    assert!(!is_same_file(&original, &linked).await);
+    assert_eq!(std::fs::read(&linked).unwrap(), b"content");
+    assert!(undo(Strategy::Delete, &original, &deleted).await);
+    assert_eq!(std::fs::read(&deleted).unwrap(), b"content");
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn symlinks_are_replaced_by_copies() {
+    let dir = test_dir("symlink");
+    let (original, link) = (dir.join("o"), dir.join("s"));
+    std::fs::write(&original, b"content").unwrap();
+    std::os::unix::fs::symlink("o", &link).unwrap();
+    assert!(undo(Strategy::Symlink, &original, &link).await);
+    assert!(!std::fs::symlink_metadata(&link).unwrap().is_symlink());
+    assert_eq!(std::fs::read(&link).unwrap(), b"content");
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[tokio::test]
+  async fn reflinks_keep_their_own_content() {
+    let dir = test_dir("reflink");
+    let (original, reflinked) = (dir.join("o"), dir.join("r"));
+    std::fs::write(&original, b"content").unwrap();
+    // Written to after the merge, which only shared the storage.
+    std::fs::write(&reflinked, b"changed").unwrap();
+    assert!(undo(Strategy::Reflink, &original, &reflinked).await);
+    assert_eq!(std::fs::read(&reflinked).unwrap(), b"changed");
+    assert!(!dir.join("r.undo").exists());
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[tokio::test]
+  async fn files_changed_after_the_merge_are_skipped() {
+    let dir = test_dir("changed");
+    let (original, other) = (dir.join("o"), dir.join("other"));
+    std::fs::write(&original, b"content").unwrap();
+    std::fs::write(&other, b"other").unwrap();
+    assert!(!undo(Strategy::HardLink, &original, &other).await);
+    assert!(!undo(Strategy::Symlink, &original, &other).await);
+    assert!(!undo(Strategy::Delete, &original, &other).await);
+    assert!(!undo(Strategy::HardLink, &original, &dir.join("missing")).await);
+    assert_eq!(std::fs::read(&other).unwrap(), b"other");
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
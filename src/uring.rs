@@ -0,0 +1,83 @@
+use std::{fs::File, io, os::fd::AsRawFd, path::Path};
+
+use io_uring::{opcode, types, IoUring};
+
+use crate::{hash::HashAlgorithm, HashDigest};
+
+/// Hashes a file with reads submitted through io_uring. Two buffers are kept in flight, so the next
+/// block is read while the current one is hashed. Returns the digest and the number of bytes
+/// hashed. This blocks.
+pub fn hash_with_uring(
+  path: &Path,
+  algorithm: HashAlgorithm,
+  buffer_size: usize,
+) -> io::Result<(HashDigest, u64)> {
+  let file = File::open(path)?;
+  let fd = types::Fd(file.as_raw_fd());
+  let mut ring = IoUring::new(2)?;
+  let mut buffers = [vec![0u8; buffer_size], vec![0u8; buffer_size]];
+  let mut results: [Option<i32>; 2] = [None, None];
+  let mut in_flight = 0;
+  let mut offset = 0u64;
+
+  let mut submit = |ring: &mut IoUring, buffer: &mut Vec<u8>, index: usize| {
+    let read = opcode::Read::new(fd, buffer.as_mut_ptr(), buffer.len() as u32)
+      .offset(offset)
+      .build()
+      .user_data(index as u64);
+    offset += buffer.len() as u64;
+    // SAFETY: The buffer is neither touched nor dropped until the read has completed.
+    unsafe { ring.submission().push(&read) }.expect("The queue fits both buffers");
+  };
+  let wait = |ring: &mut IoUring, results: &mut [Option<i32>; 2], in_flight: &mut usize| {
+    loop {
+      match ring.submit_and_wait(1) {
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+        result => {
+          result?;
+          break;
+        }
+      }
+    }
+    for completion in ring.completion() {
+      results[completion.user_data() as usize] = Some(completion.result());
+      *in_flight -= 1;
+    }
+    io::Result::Ok(())
+  };
+
+  for (index, buffer) in buffers.iter_mut().enumerate() {
+    submit(&mut ring, buffer, index);
+    in_flight += 1;
+  }
+  let mut hasher = algorithm.hasher();
+  let mut hashed = 0;
+  let mut next = 0;
+  let result = 'read: loop {
+    while results[next].is_none() {
+      if let Err(e) = wait(&mut ring, &mut results, &mut in_flight) {
+        break 'read Err(e);
+      }
+    }
+    let bytes_read = match results[next].take() {
+      Some(bytes_read) if bytes_read < 0 => break Err(io::Error::from_raw_os_error(-bytes_read)),
+      Some(bytes_read) => bytes_read as usize,
+      None => unreachable!(),
+    };
+    hasher.update(&buffers[next][..bytes_read]);
+    hashed += bytes_read as u64;
+    // A short read is the end of the file for regular files, and anything else is caught by the
+    // caller comparing the length.
+    if bytes_read < buffer_size {
+      break Ok(());
+    }
+    submit(&mut ring, &mut buffers[next], next);
+    in_flight += 1;
+    next ^= 1;
+  };
+  while in_flight > 0 {
+    wait(&mut ring, &mut results, &mut in_flight)?;
+  }
+  result?;
+  Ok((hasher.finalize(), hashed))
+}
@@ -0,0 +1,97 @@
+use std::{
+  collections::HashSet,
+  path::{Path, PathBuf},
+  time::Duration,
+};
+
+use anyhow::{Context, Result};
+use notify::{
+  event::{AccessKind, AccessMode, ModifyKind, RenameMode},
+  EventKind, RecommendedWatcher, RecursiveMode, Watcher as _,
+};
+use tokio::sync::mpsc;
+
+use crate::output;
+
+/// How long the events of a file are collected before it's scanned. Files that are still written
+/// after this are skipped as modified while hashing, and scanned again on their next event.
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+/// The file system events below the scanned roots, for `--watch`.
+pub struct Watcher {
+  _watcher: RecommendedWatcher,
+  events: mpsc::UnboundedReceiver<notify::Result<notify::Event>>,
+}
+
+/// The path that has new content after `event`, if any.
+fn changed_path(event: notify::Event) -> Option<PathBuf> {
+  let changed = matches!(
+    event.kind,
+    EventKind::Create(_)
+      | EventKind::Modify(
+        ModifyKind::Data(_)
+          | ModifyKind::Any
+          | ModifyKind::Other
+          | ModifyKind::Name(RenameMode::To | RenameMode::Both | RenameMode::Any)
+      )
+      | EventKind::Access(AccessKind::Close(AccessMode::Write))
+  );
+  // The path a file is renamed to comes last.
+  changed.then(|| event.paths.into_iter().last()).flatten()
+}
+
+impl Watcher {
+  /// Starts watching `roots`. Must be called before they are scanned, so that no change falls
+  /// between the scan and the watch.
+  pub fn new(roots: &[PathBuf]) -> Result<Self> {
+    let (sender, events) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event| {
+      let _ = sender.send(event);
+    })
+    .context("Could not start watching for changes")?;
+    for root in roots {
+      watcher
+        .watch(root, RecursiveMode::Recursive)
+        .with_context(|| format!("Could not watch {}", root.display()))?;
+    }
+    Ok(Self {
+      _watcher: watcher,
+      events,
+    })
+  }
+
+  /// Waits for files to be created or modified, and returns them once they have settled.
+  pub async fn changed_files(&mut self) -> Result<HashSet<PathBuf>> {
+    let mut changed = HashSet::new();
+    let mut receive = |event: notify::Result<notify::Event>| match event {
+      Ok(event) => changed.extend(changed_path(event)),
+      Err(e) => output::print_error(format_args!("Could not watch for changes: {e}")),
+    };
+    let event = self
+      .events
+      .recv()
+      .await
+      .context("Stopped watching for changes")?;
+    receive(event);
+    tokio::time::sleep(SETTLE_TIME).await;
+    while let Ok(event) = self.events.try_recv() {
+      receive(event);
+    }
+    // New directories are scanned with everything in them.
+    let files = changed
+      .iter()
+      .filter(|path| !path.ancestors().skip(1).any(|dir| changed.contains(dir)))
+      .cloned()
+      .collect();
+    Ok(files)
+  }
+}
+
+/// The root of `roots` that `path` is below.
+pub fn root_of<'a>(roots: &'a [PathBuf], path: &Path) -> Option<&'a Path> {
+  roots
+    .iter()
+    .filter(|root| path.starts_with(root))
+    .max_by_key(|root| root.components().count())
+    .map(PathBuf::as_path)
+}
@@ -0,0 +1,143 @@
+use std::{ffi::OsString, io, path::Path};
+
+use clap::ValueEnum;
+
+use crate::{DedupArgs, HashDigest};
+
+/// How the extended attributes of files are handled when they are merged.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum XattrPolicy {
+  /// Merge files regardless of their extended attributes.
+  Ignore,
+  /// Only merge files with the same extended attributes (such as SELinux labels and `user.*`
+  /// attributes), since the merged files share them.
+  RequireEqual,
+  /// Copy the extended attributes that the merged file lacks from the redundant file, and warn
+  /// about the ones that have different values and are lost.
+  CopyMissing,
+}
+
+impl XattrPolicy {
+  pub fn get() -> Self {
+    DedupArgs::get().xattr_policy
+  }
+}
+
+/// The names and values of extended attributes, ordered by name.
+pub type Attributes = Vec<(OsString, Vec<u8>)>;
+
+#[cfg(unix)]
+pub fn read(path: &Path) -> io::Result<Attributes> {
+  let mut attributes = vec![];
+  for name in xattr::list(path)? {
+    // Attributes can disappear while they are listed.
+    if let Some(value) = xattr::get(path, &name)? {
+      attributes.push((name, value));
+    }
+  }
+  attributes.sort();
+  Ok(attributes)
+}
+
+/// Extended attributes are not read on this platform.
+#[cfg(not(unix))]
+pub fn read(_path: &Path) -> io::Result<Attributes> {
+  Ok(vec![])
+}
+
+/// A digest of the names and values of every extended attribute of a file, so that files with
+/// different attributes are never merged.
+#[cfg(unix)]
+pub fn digest(path: &Path) -> io::Result<HashDigest> {
+  use std::os::unix::ffi::OsStrExt;
+  let mut hasher = blake3::Hasher::new();
+  for (name, value) in read(path)? {
+    for field in [name.as_bytes(), &value] {
+      hasher.update(&(field.len() as u64).to_le_bytes());
+      hasher.update(field);
+    }
+  }
+  Ok(*hasher.finalize().as_bytes())
+}
+
+#[cfg(not(unix))]
+pub fn digest(_path: &Path) -> io::Result<HashDigest> {
+  Ok([0; crate::HASH_LEN])
+}
+
+/// The `attributes` of the redundant file that `merged` lacks. Those it has with another value are
+/// reported as lost.
+#[cfg(unix)]
+fn missing<'a>(
+  attributes: &'a Attributes,
+  redundant: &Path,
+  merged: &Path,
+) -> io::Result<Vec<(&'a OsString, &'a Vec<u8>)>> {
+  let existing = read(merged)?;
+  let mut missing = vec![];
+  for (name, value) in attributes {
+    match existing.binary_search_by(|(existing, _)| existing.cmp(name)) {
+      Ok(index) if existing[index].1 != *value => crate::output::print_info(format!(
+        "Extended attribute {} of {} is lost, since the merged file has a different value",
+        name.to_string_lossy(),
+        redundant.display(),
+      ))?,
+      Ok(_) => (),
+      Err(_) => missing.push((name, value)),
+    }
+  }
+  Ok(missing)
+}
+
+/// Reports the `attributes` of the redundant file that are lost, since `merged` lacks them and is
+/// protected from being modified.
+#[cfg(unix)]
+pub fn report_missing(attributes: &Attributes, redundant: &Path, merged: &Path) -> io::Result<()> {
+  for (name, _) in missing(attributes, redundant, merged)? {
+    crate::output::print_info(format!(
+      "Extended attribute {} of {} is lost, since the original is protected",
+      name.to_string_lossy(),
+      redundant.display(),
+    ))?;
+  }
+  Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn report_missing(
+  _attributes: &Attributes,
+  _redundant: &Path,
+  _merged: &Path,
+) -> io::Result<()> {
+  Ok(())
+}
+
+/// Sets the `attributes` of the redundant file that `merged` lacks on it.
+#[cfg(unix)]
+pub fn copy_missing(attributes: &Attributes, redundant: &Path, merged: &Path) -> io::Result<()> {
+  use std::fs;
+  let missing = missing(attributes, redundant, merged)?;
+  if missing.is_empty() {
+    return Ok(());
+  }
+  // Files are made readonly when they are merged, which prevents setting attributes.
+  let permissions = fs::metadata(merged)?.permissions();
+  if permissions.readonly() {
+    let mut writable = permissions.clone();
+    #[allow(clippy::permissions_set_readonly_false)]
+    writable.set_readonly(false);
+    fs::set_permissions(merged, writable)?;
+  }
+  let result = missing
+    .into_iter()
+    .try_for_each(|(name, value)| xattr::set(merged, name, value));
+  if permissions.readonly() {
+    fs::set_permissions(merged, permissions)?;
+  }
+  result
+}
+
+#[cfg(not(unix))]
+pub fn copy_missing(_attributes: &Attributes, _redundant: &Path, _merged: &Path) -> io::Result<()> {
+  Ok(())
+}
@@ -0,0 +1,77 @@
+use std::{
+  path::PathBuf,
+  sync::{Arc, Mutex},
+};
+
+use clap::Parser;
+use hardlinkdedup::{DedupArgs, DedupEngineBuilder, Event};
+
+/// Two copies of a small file, and three of a large one.
+fn duplicates() -> PathBuf {
+  let dir = std::env::temp_dir().join(format!(
+    "hard-link-dedup-min-savings-{}",
+    std::process::id()
+  ));
+  let _ = std::fs::remove_dir_all(&dir);
+  std::fs::create_dir_all(&dir).unwrap();
+  for (name, content) in [
+    ("small-1", vec![1; 1 << 20]),
+    ("small-2", vec![1; 1 << 20]),
+    ("large-1", vec![2; 2 << 20]),
+    ("large-2", vec![2; 2 << 20]),
+    ("large-3", vec![2; 2 << 20]),
+  ] {
+    std::fs::write(dir.join(name), content).unwrap();
+  }
+  dir
+}
+
+#[tokio::test]
+async fn groups_below_min_savings_are_reported_but_not_merged() {
+  let dir = duplicates();
+  let args = DedupArgs::parse_from([
+    "hard-link-dedup".as_ref(),
+    "--quiet".as_ref(),
+    "--min-file-size=0".as_ref(),
+    "--min-savings=3MiB".as_ref(),
+    dir.as_os_str(),
+  ]);
+  let skipped = Arc::new(Mutex::new(vec![]));
+  let observer = {
+    let skipped = skipped.clone();
+    move |event: &Event<'_>| {
+      if let Event::Skipped { path, reason } = event {
+        skipped
+          .lock()
+          .unwrap()
+          .push((path.file_name().unwrap().to_owned(), reason.to_string()));
+      }
+    }
+  };
+  let engine = DedupEngineBuilder::from(args)
+    .observer(observer)
+    .build()
+    .unwrap();
+  let report = engine.run().await.unwrap();
+
+  assert_eq!(report.stats.links_created, 2);
+  assert_eq!(report.stats.saved_storage, 4 << 20);
+  let skipped = skipped.lock().unwrap();
+  let small = skipped
+    .iter()
+    .filter(|(_, reason)| reason == "group saves less than --min-savings")
+    .map(|(name, _)| name.to_str().unwrap())
+    .collect::<Vec<_>>();
+  assert_eq!(small.len(), 1);
+  assert!(small[0].starts_with("small-"));
+  // Merged files are made readonly.
+  for name in ["small-1", "small-2"] {
+    let metadata = std::fs::metadata(dir.join(name)).unwrap();
+    assert!(!metadata.permissions().readonly(), "{name}");
+  }
+  assert!(std::fs::metadata(dir.join("large-1"))
+    .unwrap()
+    .permissions()
+    .readonly());
+  std::fs::remove_dir_all(&dir).unwrap();
+}